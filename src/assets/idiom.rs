@@ -2,7 +2,7 @@ use rand::{rng, seq::IteratorRandom};
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, sync::LazyLock};
 
-use crate::utils::escape_html;
+use crate::utils::markup::bold;
 const IDIOM_STR: &str = include_str!("idiom.json");
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -20,14 +20,12 @@ pub struct Idiom {
 
 impl Idiom {
     pub fn html_description(&self) -> String {
-        format!(
-            "<b>{}</b> ({}): {}\n来源：{}\n使用例：{}",
-            escape_html(&self.word),
-            escape_html(&self.pinyin),
-            escape_html(&self.explanation),
-            escape_html(&self.derivation),
-            escape_html(&self.example)
-        )
+        (bold(&self.word)
+            + format!(
+                " ({}): {}\n来源：{}\n使用例：{}",
+                self.pinyin, self.explanation, self.derivation, self.example
+            ))
+        .html()
     }
 }
 
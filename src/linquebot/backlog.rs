@@ -0,0 +1,157 @@
+//! 离线期间积压消息的处理策略
+//!
+//! - `BACKLOG_POLICY`: `drop`（默认，丢弃超过 30 秒的消息）、`commands`（只补处理命令）或 `all`（全部补处理）
+//! - `BACKLOG_SUMMARY_THRESHOLD`: 某个聊天补处理的命令数达到该值时，发送一条汇总消息，默认 5
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use teloxide_core::{
+    prelude::*,
+    types::{ChatId, Message},
+};
+
+use super::db::{DataType, DbData};
+use super::{App, msg_context::CmdParts};
+use crate::utils::telegram::prelude::WarnOnError;
+
+/// 超过这个秒数的消息视为积压消息
+pub const STALE_SECS: i64 = 30;
+
+/// 长轮询已确认的 offset，持久化以便重启后继续
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct PollingOffset(pub i32);
+
+impl DbData for PollingOffset {
+    const KEY: &str = "backlog::PollingOffset";
+}
+
+pub static DATA_TYPES: &[DataType] = &[DataType::of::<PollingOffset>()];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BacklogPolicy {
+    Drop,
+    CommandsOnly,
+    All,
+}
+
+impl BacklogPolicy {
+    fn parse(src: &str) -> Option<Self> {
+        match src.trim().to_ascii_lowercase().as_str() {
+            "drop" => Some(Self::Drop),
+            "commands" => Some(Self::CommandsOnly),
+            "all" => Some(Self::All),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Backlog {
+    pub policy: BacklogPolicy,
+    pub summary_threshold: usize,
+    handled: Mutex<HashMap<ChatId, usize>>,
+    flush_pending: AtomicBool,
+}
+
+impl Backlog {
+    pub fn new(policy: BacklogPolicy, summary_threshold: usize) -> Self {
+        Self {
+            policy,
+            summary_threshold,
+            handled: Mutex::new(HashMap::new()),
+            flush_pending: AtomicBool::new(false),
+        }
+    }
+
+    pub fn from_env() -> anyhow::Result<Self> {
+        let policy = match std::env::var("BACKLOG_POLICY") {
+            Ok(policy) => BacklogPolicy::parse(&policy).ok_or_else(|| {
+                anyhow::anyhow!("BACKLOG_POLICY must be one of drop, commands and all")
+            })?,
+            Err(_) => BacklogPolicy::Drop,
+        };
+        let summary_threshold = match std::env::var("BACKLOG_SUMMARY_THRESHOLD") {
+            Ok(num) => num.parse()?,
+            Err(_) => 5,
+        };
+        Ok(Self::new(policy, summary_threshold))
+    }
+
+    /// 判断一条积压消息是否应该被处理。补处理的命令会被计数，并在积压处理完后汇总
+    pub fn replay(&'static self, app: &'static App, message: &Message) -> bool {
+        let is_command = CmdParts::parse_from(message)
+            .is_some_and(|cmd| cmd.username.is_none_or(|name| name == app.username));
+        let admitted = match self.policy {
+            BacklogPolicy::Drop => false,
+            BacklogPolicy::CommandsOnly => is_command,
+            BacklogPolicy::All => true,
+        };
+        if admitted && is_command {
+            self.record_command(app, message.chat.id);
+        }
+        admitted
+    }
+
+    fn record_command(&'static self, app: &'static App, chat_id: ChatId) {
+        let Ok(mut handled) = self.handled.lock() else {
+            error!("Failed to lock backlog records");
+            return;
+        };
+        *handled.entry(chat_id).or_default() += 1;
+        if !self.flush_pending.swap(true, Ordering::AcqRel) {
+            tokio::spawn(self.flush(app));
+        }
+    }
+
+    async fn flush(&'static self, app: &'static App) {
+        // 积压的更新会在短时间内连续到达，稍等一下再统计
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        self.flush_pending.store(false, Ordering::Release);
+        let handled = match self.handled.lock() {
+            Ok(mut handled) => std::mem::take(&mut *handled),
+            Err(_) => {
+                error!("Failed to lock backlog records");
+                return;
+            }
+        };
+        for (chat_id, count) in handled {
+            info!(target: "backlog", "replayed {count} commands in chat {chat_id}");
+            if count < self.summary_threshold {
+                continue;
+            }
+            app.bot
+                .send_message(
+                    chat_id,
+                    format!("琳酱刚才离线了一会儿，已经补处理了 {count} 条命令"),
+                )
+                .send()
+                .warn_on_error("backlog")
+                .await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BacklogPolicy;
+
+    #[test]
+    fn parse_policy() {
+        assert_eq!(BacklogPolicy::parse("drop"), Some(BacklogPolicy::Drop));
+        assert_eq!(
+            BacklogPolicy::parse(" Commands "),
+            Some(BacklogPolicy::CommandsOnly)
+        );
+        assert_eq!(BacklogPolicy::parse("all"), Some(BacklogPolicy::All));
+        assert_eq!(BacklogPolicy::parse("some"), None);
+    }
+}
@@ -0,0 +1,191 @@
+//! 按钮的回调数据
+//!
+//! 回调数据的格式是 `模块名|过期时间|数据`：数据是 ron 格式，过期时间是 36 进制的 unix 时间，
+//! 不会过期时为空。Telegram 限制回调数据最多 64 字节，超过的按钮不会被创建。
+//!
+//! 模块用 [MicroTask::OnCallback] 注册回调，[dispatch] 按模块名找到对应的回调；
+//! 过期的按钮、找不到回调的按钮和回调不处理的按钮都会直接回复用户，不会一直转圈。
+//!
+//! 使用方式：
+//! ```ignore
+//! let button = Callback::new("help", &page).button("返回");
+//! fn on_help_callback(app: &'static App, cq: &CallbackQuery, callback: &Callback) -> Consumption {
+//!     let page = callback.payload::<HelpPage>()?;
+//! }
+//! ```
+//!
+//! 参见 [crate::mods::captcha]
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use teloxide_core::{
+    prelude::*,
+    types::{CallbackQuery, InlineKeyboardButton},
+};
+
+use super::{App, MicroTask, types::Consumption};
+use crate::utils::telegram::prelude::WarnOnError;
+
+/// 回调数据的最大字节数
+pub const CALLBACK_DATA_LIMIT: usize = 64;
+
+/// 一个按钮的回调数据
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Callback {
+    /// 回调对应的模块名
+    pub module: String,
+    /// 过期的 unix 时间
+    pub expires: Option<u64>,
+    /// 模块自定义的数据，ron 格式
+    pub payload: String,
+}
+
+fn to_base36(mut n: u64) -> String {
+    let mut digits = vec![];
+    loop {
+        digits.push(char::from_digit((n % 36) as u32, 36).expect("digit"));
+        n /= 36;
+        if n == 0 {
+            break;
+        }
+    }
+    digits.iter().rev().collect()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl Callback {
+    pub fn new<T: Serialize>(module: &str, payload: &T) -> Self {
+        Self {
+            module: module.to_string(),
+            expires: None,
+            payload: ron::to_string(payload).expect("ser error"),
+        }
+    }
+
+    /// 按钮在 `ttl` 之后过期
+    pub fn expires_in(mut self, ttl: Duration) -> Self {
+        self.expires = Some(unix_now() + ttl.as_secs());
+        self
+    }
+
+    pub fn encode(&self) -> String {
+        let expires = self.expires.map(to_base36).unwrap_or_default();
+        format!("{}|{expires}|{}", self.module, self.payload)
+    }
+
+    pub fn parse(data: &str) -> Option<Self> {
+        let mut parts = data.splitn(3, '|');
+        let module = parts.next()?.to_string();
+        let expires = match parts.next()? {
+            "" => None,
+            expires => Some(u64::from_str_radix(expires, 36).ok()?),
+        };
+        let payload = parts.next()?.to_string();
+        Some(Self {
+            module,
+            expires,
+            payload,
+        })
+    }
+
+    pub fn payload<T: for<'a> Deserialize<'a>>(&self) -> Option<T> {
+        ron::from_str(&self.payload)
+            .inspect_err(|err| {
+                warn!(target: "callback", "bad payload {} of {}: {err}", self.payload, self.module)
+            })
+            .ok()
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires.is_some_and(|expires| expires <= unix_now())
+    }
+
+    /// 生成按钮，回调数据过长时返回 `None`
+    pub fn button(&self, text: impl Into<String>) -> Option<InlineKeyboardButton> {
+        let data = self.encode();
+        if data.len() > CALLBACK_DATA_LIMIT {
+            warn!(target: "callback", "callback data is too long: {data}");
+            return None;
+        }
+        Some(InlineKeyboardButton::callback(text, data))
+    }
+}
+
+fn answer(app: &'static App, query: &CallbackQuery, text: &'static str) -> Consumption {
+    app.bot
+        .answer_callback_query(query.id.clone())
+        .text(text)
+        .send()
+        .warn_on_error("callback")
+        .into()
+}
+
+/// 把回调交给注册了对应模块名的 [MicroTask::OnCallback]
+pub fn dispatch(app: &'static App, query: &CallbackQuery) -> Consumption {
+    let Some(callback) = query.data.as_deref().and_then(Callback::parse) else {
+        return answer(app, query, "这个按钮已经失效了");
+    };
+    let handler = app.micro_tasks.iter().find_map(|task| match task {
+        MicroTask::OnCallback(module, handler) if *module == callback.module => Some(handler),
+        _ => None,
+    });
+    let Some(handler) = handler else {
+        warn!(target: "callback", "no callback registered for {}", callback.module);
+        return answer(app, query, "这个按钮已经失效了");
+    };
+    if callback.is_expired() {
+        return answer(app, query, "这个按钮已经过期了");
+    }
+    let result = handler(app, query, &callback);
+    if result.task.is_none() {
+        return answer(app, query, "这个按钮已经失效了");
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn callback_roundtrip() {
+        let callback = Callback::new("captcha", &(1234567890u64, 3usize));
+        assert_eq!(callback.encode(), "captcha||(1234567890,3)");
+        assert_eq!(Callback::parse(&callback.encode()), Some(callback.clone()));
+        assert_eq!(callback.payload::<(u64, usize)>(), Some((1234567890, 3)));
+        assert!(!callback.is_expired());
+
+        let callback = callback.expires_in(Duration::from_secs(60));
+        let parsed = Callback::parse(&callback.encode()).unwrap();
+        assert_eq!(parsed.expires, callback.expires);
+        assert!(!parsed.is_expired());
+
+        let expired = Callback {
+            expires: Some(unix_now() - 1),
+            ..parsed
+        };
+        assert!(Callback::parse(&expired.encode()).unwrap().is_expired());
+
+        // 数据里可以有分隔符
+        let callback = Callback::new("help", &"a|b");
+        assert_eq!(Callback::parse(&callback.encode()), Some(callback));
+        // 旧格式的数据
+        assert_eq!(Callback::parse("help waife"), None);
+    }
+
+    #[test]
+    fn callback_limit() {
+        assert!(Callback::new("help", &"short").button("a").is_some());
+        assert!(Callback::new("help", &"x".repeat(64)).button("a").is_none());
+        assert_eq!(to_base36(0), "0");
+        assert_eq!(to_base36(36 * 36 + 35), "10z");
+    }
+}
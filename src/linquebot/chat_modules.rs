@@ -0,0 +1,110 @@
+//! 每个聊天的模块开关，以 [Module::name] 为键，有持久化
+//!
+//! 由 [crate::resolvers::message::resolve] 在调用模块之前检查，
+//! 使用 `/modules` 命令管理，参见 [crate::mods::modules]
+
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+use teloxide_core::{
+    RequestError,
+    prelude::*,
+    types::{BotCommand, BotCommandScope, ChatId, Recipient},
+};
+
+use super::db::{DataType, DbData, DbError};
+use super::{App, Module, ModuleKind};
+
+/// 不能被关闭的模块
+const PROTECTED_MODULES: &[&str] = &["help", "modules", "bot_on"];
+
+/// 一个聊天里被关闭的模块
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChatModules {
+    disabled: HashSet<String>,
+}
+
+impl DbData for ChatModules {
+    const KEY: &str = "chat_modules::ChatModules";
+}
+
+pub static DATA_TYPES: &[DataType] = &[DataType::of::<ChatModules>()];
+
+impl ChatModules {
+    /// 没有记录时所有模块都是打开的，不写入数据库
+    pub async fn load(app: &'static App, chat_id: ChatId) -> Result<Self, DbError> {
+        let modules = app.db.of::<ChatModules>().chat(chat_id).get().await?;
+        Ok(modules.map(|modules| modules.clone()).unwrap_or_default())
+    }
+
+    /// 没有名字的模块总是打开的
+    pub fn is_enabled(&self, module: &Module) -> bool {
+        module
+            .name()
+            .is_none_or(|name| !self.disabled.contains(name))
+    }
+
+    pub fn is_enabled_name(&self, name: &str) -> bool {
+        !self.disabled.contains(name)
+    }
+
+    pub fn enable(&mut self, name: &str) -> bool {
+        self.disabled.remove(name)
+    }
+
+    pub fn disable(&mut self, name: &str) -> bool {
+        self.disabled.insert(name.to_string())
+    }
+
+    pub fn all_enabled(&self) -> bool {
+        self.disabled.is_empty()
+    }
+
+    pub fn enabled_modules(&self, app: &App) -> impl Iterator<Item = &'static Module> {
+        app.modules
+            .iter()
+            .copied()
+            .filter(|module| self.is_enabled(module))
+    }
+}
+
+/// 可以被 `/modules` 开关的模块
+pub fn is_toggleable(module: &Module) -> bool {
+    module
+        .name()
+        .is_some_and(|name| !PROTECTED_MODULES.contains(&name))
+}
+
+/// 模块对应的 bot 命令列表
+pub fn bot_commands<'a>(modules: impl Iterator<Item = &'a Module>) -> Vec<BotCommand> {
+    modules
+        .filter_map(|module| {
+            if let ModuleKind::Command(cmd) = &module.kind {
+                Some(BotCommand::new(cmd.name, cmd.description))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// 让聊天里的命令菜单只显示打开的模块
+pub async fn sync_chat_commands(
+    app: &'static App,
+    chat_id: ChatId,
+    modules: &ChatModules,
+) -> Result<(), RequestError> {
+    let scope = BotCommandScope::Chat {
+        chat_id: Recipient::Id(chat_id),
+    };
+    if modules.all_enabled() {
+        app.bot.delete_my_commands().scope(scope).send().await?;
+    } else {
+        app.bot
+            .set_my_commands(bot_commands(modules.enabled_modules(app)))
+            .scope(scope)
+            .send()
+            .await?;
+    }
+    Ok(())
+}
@@ -1,34 +1,156 @@
 use std::{
-    any::{type_name, Any, TypeId},
+    any::{Any, TypeId, type_name},
+    collections::HashMap,
+    convert::Infallible,
+    fmt::{self, Display, Formatter},
     marker::PhantomData,
     ops::{Deref, DerefMut},
     sync::Arc,
+    time::Duration,
 };
 
+use futures::{Stream, StreamExt, stream};
+use log::{error, info, warn};
 use quick_cache::sync::Cache;
 use serde::{Deserialize, Serialize};
-use sqlx::{
-    query::Query,
-    sqlite::{SqliteArguments, SqliteConnectOptions},
-    Connection, Row, Sqlite, SqliteConnection,
-};
-use teloxide_core::types::{ChatId, UserId};
-use tokio::sync::{Mutex, MutexGuard, OwnedMappedMutexGuard, OwnedMutexGuard};
+use teloxide_core::types::{ChatId, MessageId, ThreadId, UserId};
+
+use super::db_backend::{self, Backend, DataRow, Filter, Key, SCAN_PAGE, TypeStat};
+use super::msg_context::Topic;
+use tokio::sync::{Mutex, OwnedMappedMutexGuard, OwnedMutexGuard};
 
 pub trait DbDataDyn: Any + Send + Sync {
     fn ser_data(&self) -> String;
 }
-pub trait DbData: DbDataDyn {
-    fn deser_data(src: &str) -> Self;
-}
 impl<T: Any + Send + Sync + Serialize> DbDataDyn for T {
     fn ser_data(&self) -> String {
         ron::to_string(self).expect("ser error")
     }
 }
-impl<T: DbDataDyn + for<'a> Deserialize<'a>> DbData for T {
-    fn deser_data(src: &str) -> Self {
-        ron::from_str(src).expect("deser error")
+
+/// 把上一个版本的数据（ron 格式）升级为下一个版本
+pub type Migration = fn(&str) -> anyhow::Result<String>;
+
+/// 保存在数据库中的类型
+///
+/// ```ignore
+/// impl DbData for WaifeStatus {
+///     const KEY: &str = "waife::WaifeStatus";
+///     // 修改数据格式时加上一个升级函数，旧数据会在读取时升级
+///     const MIGRATIONS: &[Migration] = &[|src| { .. }];
+/// }
+/// ```
+///
+/// 新的类型还需要加入 [crate::mods::DATA_TYPES]
+pub trait DbData: DbDataDyn + for<'a> Deserialize<'a> {
+    /// 数据库中的类型名，移动或重命名类型时不能改变
+    const KEY: &'static str;
+    /// `MIGRATIONS[i]` 把第 i 版的数据升级为第 i + 1 版
+    const MIGRATIONS: &'static [Migration] = &[];
+
+    /// 当前的数据版本
+    fn version() -> u32 {
+        Self::MIGRATIONS.len() as u32
+    }
+
+    /// 读取 `version` 版本的数据，需要时先升级到当前版本
+    fn deser_data(src: &str, version: u32) -> anyhow::Result<Self> {
+        let migrations = Self::MIGRATIONS
+            .get(version as usize..)
+            .ok_or_else(|| anyhow::anyhow!("unknown version {version}"))?;
+        let mut src = src.to_string();
+        for (i, migrate) in migrations.iter().enumerate() {
+            src = migrate(&src).map_err(|err| {
+                err.context(format!("migrate from version {}", version as usize + i))
+            })?;
+        }
+        Ok(ron::from_str(&src)?)
+    }
+}
+
+/// 数据库错误
+#[derive(Debug)]
+pub enum DbError {
+    /// 读写数据库失败
+    Sql(sqlx::Error),
+    /// 数据库已经关闭
+    Closed,
+    /// 数据无法读取，已经被移到隔离表中
+    Corrupted { key: &'static str, error: String },
+}
+
+impl DbError {
+    /// 回复给用户的说明
+    pub fn friendly(&self) -> &'static str {
+        match self {
+            DbError::Sql(_) | DbError::Closed => "琳酱的数据库暂时出了点问题，请稍后再试",
+            DbError::Corrupted { .. } => {
+                "这里保存的数据损坏了，琳酱已经把它交给主人检查，再试一次会使用新的数据"
+            }
+        }
+    }
+}
+
+impl Display for DbError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::Sql(err) => write!(f, "database error: {err}"),
+            DbError::Closed => write!(f, "database is closed"),
+            DbError::Corrupted { key, error } => write!(f, "corrupted {key}: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for DbError {}
+
+impl From<sqlx::Error> for DbError {
+    fn from(err: sqlx::Error) -> Self {
+        match err {
+            sqlx::Error::PoolClosed => DbError::Closed,
+            err => DbError::Sql(err),
+        }
+    }
+}
+
+/// 后台任务读写失败时只记录日志
+pub trait WarnDbError<T> {
+    fn ok_or_warn(self, target: &str) -> Option<T>;
+}
+
+impl<T> WarnDbError<T> for Result<T, DbError> {
+    fn ok_or_warn(self, target: &str) -> Option<T> {
+        self.inspect_err(|err| warn!(target: target, "{err}")).ok()
+    }
+}
+
+/// 被隔离的数据
+#[derive(Debug)]
+pub struct Quarantined {
+    pub ty: String,
+    pub user: String,
+    pub chat: String,
+    pub error: String,
+    /// 被隔离的时间，UTC
+    pub at: String,
+}
+
+/// 注册的数据类型，用于启动时检查数据库和 [DataStorage::remove_all]
+pub struct DataType {
+    pub key: &'static str,
+    pub version: u32,
+    /// 以前的版本按 `type_name` 保存数据
+    type_name: fn() -> &'static str,
+    type_id: fn() -> TypeId,
+}
+
+impl DataType {
+    pub const fn of<T: DbData>() -> Self {
+        Self {
+            key: T::KEY,
+            version: T::MIGRATIONS.len() as u32,
+            type_name: type_name::<T>,
+            type_id: TypeId::of::<T>,
+        }
     }
 }
 
@@ -40,40 +162,64 @@ impl<T: DbDataDyn + for<'a> Deserialize<'a>> DbData for T {
 /// ctx.db.of::<类型>().chat(chat_id).get_or_insert()
 /// ctx.db.of::<类型>().user(user_id).get_or_insert()
 /// ctx.db.of::<类型>().chat(chat_id).user(user_id).get_or_insert()
+/// ctx.db.of::<类型>().topic(ctx.topic()).get_or_insert()
 /// ```
 ///
+/// 数据库中没有的数据也会被缓存，频繁地 `get()` 不存在的数据不会每次都查询数据库。
+///
+/// 通过 [DataGuard] 做的修改在释放时进入写入队列，稍后批量写入；[DataStorage::close] 会写完队列再关闭。
+///
+/// 数据保存在 [db_backend] 选择的后端中。
+///
 /// 参见 [crate::mods::markov]
 #[derive(Debug)]
 pub struct DataStorage {
-    cache: Cache<DataId, Arc<Mutex<dyn DbDataDyn>>>,
-    db: Mutex<Option<SqliteConnection>>,
+    cache: Cache<DataId, Slot>,
+    backend: Box<dyn Backend>,
+    /// 写入数据库时持有，保证写入队列按顺序写入
+    write_lock: Mutex<()>,
+    pending: std::sync::Mutex<PendingWrites>,
+}
+
+/// 缓存中的一份数据，`None` 表示数据库中没有这份数据，不用每次都去查询
+type Slot = Option<Arc<Mutex<dyn DbDataDyn>>>;
+
+/// [DataGuard] 释放后等待写入的数据
+#[derive(Debug)]
+struct PendingWrite {
+    key: &'static str,
+    version: u32,
+    val: String,
+    /// 放入队列的顺序，写入期间又被修改过的数据要留在队列中
+    seq: u64,
+}
+
+/// 写入队列。同一份数据多次修改时只保留最后一次，[FLUSH_DELAY] 后在一个事务中写入
+#[derive(Debug, Default)]
+struct PendingWrites {
+    writes: HashMap<DataId, PendingWrite>,
+    /// 已经安排了写入任务
+    scheduled: bool,
+    next_seq: u64,
 }
 
+/// 修改后等待多久写入数据库
+const FLUSH_DELAY: Duration = Duration::from_millis(100);
+
 impl DataStorage {
     pub async fn new() -> anyhow::Result<Self> {
-        let mut db = SqliteConnection::connect_with(
-            &SqliteConnectOptions::new()
-                .filename("data.db")
-                .create_if_missing(true),
-        )
-        .await?;
-        sqlx::query(concat!(
-            "create table if not exists data",
-            "(ty text, user text, chat text, val blob, ",
-            "primary key (ty, user, chat), unique (ty, user, chat))",
+        Ok(Self::with_backend(
+            db_backend::connect(&db_backend::url_from_env()).await?,
         ))
-        .execute(&mut db)
-        .await?;
-        Ok(Self {
-            cache: Cache::new(1000),
-            db: Mutex::new(Some(db)),
-        })
     }
 
-    async fn get_db(&self) -> tokio::sync::MappedMutexGuard<'_, SqliteConnection> {
-        MutexGuard::map(self.db.lock().await, |db| {
-            db.as_mut().expect("Database connection is not initialized")
-        })
+    pub fn with_backend(backend: Box<dyn Backend>) -> Self {
+        Self {
+            cache: Cache::new(1000),
+            backend,
+            write_lock: Mutex::new(()),
+            pending: Default::default(),
+        }
     }
 
     pub fn of<T: DbData>(&'static self) -> DataBuilder<T> {
@@ -81,40 +227,58 @@ impl DataStorage {
             db: self,
             phantom_t: PhantomData,
             chat: None,
+            thread: None,
             user: None,
         }
     }
 
-    pub async fn get<T: DbData>(&'static self, id: DataId) -> Option<DataGuard<T>> {
-        let cache = if let Some(c) = self.cache.get(&id) {
-            c
-        } else {
-            let res = self.get_from_db::<T>(id).await?;
-            self.cache.insert(id, res.clone());
-            res
+    pub async fn get<T: DbData>(
+        &'static self,
+        id: DataId,
+    ) -> Result<Option<DataGuard<T>>, DbError> {
+        let cache = match self.cache.get(&id) {
+            Some(Some(c)) => c,
+            Some(None) => return Ok(None),
+            None => {
+                let Some(res) = self.get_from_db::<T>(id).await? else {
+                    // 查询期间可能有别的任务插入了这份数据，这时不能覆盖
+                    let _ = self
+                        .cache
+                        .get_or_insert_with(&id, || Ok::<_, Infallible>(None));
+                    return Ok(None);
+                };
+                self.cache.insert(id, Some(res.clone()));
+                res
+            }
         };
-        Some(self.mk_insert_res::<T>(id, cache).await)
+        Ok(Some(self.mk_insert_res::<T>(id, cache).await))
     }
 
     pub async fn get_or_insert<T: DbData>(
         &'static self,
         id: DataId,
         mk: impl FnOnce() -> T,
-    ) -> DataGuard<T> {
-        let cache = if let Some(c) = self.cache.get(&id) {
-            c
-        } else {
-            let res = if let Some(r) = self.get_from_db::<T>(id).await {
-                r
-            } else {
-                let r = mk();
-                self.insert_raw(type_name::<T>(), id, &r.ser_data()).await;
-                Arc::new(Mutex::new(r))
-            };
-            self.cache.insert(id, res.clone());
-            res
+    ) -> Result<DataGuard<T>, DbError> {
+        let cache = match self.cache.get(&id) {
+            Some(Some(c)) => c,
+            slot => {
+                let stored = match slot {
+                    Some(None) => None,
+                    _ => self.get_from_db::<T>(id).await?,
+                };
+                let res = if let Some(r) = stored {
+                    r
+                } else {
+                    let r = mk();
+                    self.insert_raw(T::KEY, T::version(), id, &r.ser_data())
+                        .await?;
+                    Arc::new(Mutex::new(r))
+                };
+                self.cache.insert(id, Some(res.clone()));
+                res
+            }
         };
-        self.mk_insert_res::<T>(id, cache).await
+        Ok(self.mk_insert_res::<T>(id, cache).await)
     }
 
     async fn mk_insert_res<T: DbData>(
@@ -124,10 +288,7 @@ impl DataStorage {
     ) -> DataGuard<T> {
         let cache = cache.lock_owned().await;
         let Ok(sub) = OwnedMutexGuard::try_map(cache, |val| <dyn Any>::downcast_mut(val)) else {
-            panic!(
-                "Cached type mismatch: expected {:?}",
-                std::any::type_name::<T>()
-            );
+            panic!("Cached type mismatch: expected {:?}", type_name::<T>());
         };
         DataGuard {
             db: self,
@@ -137,69 +298,349 @@ impl DataStorage {
         }
     }
 
-    async fn get_from_db<T: DbData>(&'static self, id: DataId) -> Option<Arc<Mutex<T>>> {
-        let res = sqlx::query("select val from data where ty = $1 and user = $2 and chat = $3")
-            .bind_id(type_name::<T>(), id)
-            .fetch_optional(&mut *self.get_db().await)
-            .await
-            .expect("db read error")?;
-        let res = T::deser_data(res.get::<&str, usize>(0));
-        Some(Arc::new(Mutex::new(res)))
+    async fn get_from_db<T: DbData>(
+        &'static self,
+        id: DataId,
+    ) -> Result<Option<Arc<Mutex<T>>>, DbError> {
+        let pending = self.pending.lock().unwrap().writes.get(&id).map(|write| {
+            // 还没有写入数据库，从队列中读取
+            (write.val.clone(), write.version)
+        });
+        let (val, version) = match pending {
+            Some(pending) => pending,
+            None => {
+                let Some(row) = self.backend.get(&key(T::KEY, id)).await? else {
+                    return Ok(None);
+                };
+                row
+            }
+        };
+        let res = self.decode::<T>(id, &val, version).await?;
+        Ok(Some(Arc::new(Mutex::new(res))))
     }
 
-    #[allow(dead_code)]
-    pub async fn insert<T: DbData>(&'static self, id: DataId, val: T) {
-        self.insert_raw(type_name::<T>(), id, &val.ser_data()).await;
-        self.cache.insert(id, Arc::new(Mutex::new(val)));
+    /// 读取一行数据，无法读取时移到隔离表中，旧版本的数据会被更新
+    async fn decode<T: DbData>(
+        &'static self,
+        id: DataId,
+        val: &str,
+        version: u32,
+    ) -> Result<T, DbError> {
+        let res = match T::deser_data(val, version) {
+            Ok(res) => res,
+            Err(err) => {
+                let error = format!("{err:#}");
+                error!(target: "db", "quarantined a row of {}: {error}", T::KEY);
+                self.quarantine(T::KEY, id, &error).await?;
+                return Err(DbError::Corrupted { key: T::KEY, error });
+            }
+        };
+        if version != T::version() {
+            info!(target: "db", "migrated {} from version {version} to {}", T::KEY, T::version());
+            self.insert_raw(T::KEY, T::version(), id, &res.ser_data())
+                .await?;
+        }
+        Ok(res)
     }
-    async fn insert_raw(&'static self, ty: &str, id: DataId, val: &str) {
-        sqlx::query(concat!(
-            "insert into data(ty, user, chat, val) values ($1, $2, $3, $4) ",
-            "on conflict(ty, user, chat) do update set val = $4"
-        ))
-        .bind_id(ty, id)
-        .bind(val)
-        .execute(&mut *self.get_db().await)
-        .await
-        .expect("db write error");
-    }
-
-    #[allow(dead_code)]
-    pub async fn remove<T: DbData>(&'static self, id: DataId) {
-        self.cache.remove(&id);
-        sqlx::query("delete from data where ty = $1 and user = $2 and chat = $3")
-            .bind_id(type_name::<T>(), id)
-            .execute(&mut *self.get_db().await)
-            .await
-            .expect("db write error");
+
+    /// 按范围遍历一种类型的所有数据
+    ///
+    /// 缓存和写入队列中的数据比数据库中的新，会优先使用；正在被修改的数据返回修改前的值。
+    /// 每次按主键顺序读出一页，遍历时可以继续读写数据库。
+    pub fn scan<T: DbData>(
+        &'static self,
+        range: Scan,
+    ) -> impl Stream<Item = Result<(DataId, T), DbError>> + Send {
+        // 外层为 None 时已经读完，内层是上一页最后一行的主键
+        stream::unfold(Some(None), move |after: Option<Option<Key>>| async move {
+            let after = after?;
+            let page = self
+                .scan_page(T::KEY, TypeId::of::<T>(), range, after.as_ref())
+                .await;
+            let (rows, next) = match page {
+                Ok((rows, next)) => (rows.into_iter().map(Ok).collect(), next.map(Some)),
+                Err(err) => (vec![Err(err)], None),
+            };
+            Some((stream::iter(rows), next))
+        })
+        .flatten()
+        .then(move |row| async move {
+            let (id, val, version) = row?;
+            let (val, version) = self.latest::<T>(id).unwrap_or((val, version));
+            Ok((id, self.decode::<T>(id, &val, version).await?))
+        })
+    }
+
+    /// 读取 `after` 之后的一页，还有下一页时同时返回这一页最后一行的主键
+    async fn scan_page(
+        &self,
+        key: &str,
+        ty: TypeId,
+        range: Scan,
+        after: Option<&Key>,
+    ) -> Result<(Vec<(DataId, String, u32)>, Option<Key>), DbError> {
+        let value = match range {
+            Scan::All => String::new(),
+            Scan::Chat(chat) => ron::to_string(&Some(chat.0)).expect("ser i64"),
+            Scan::User(user) => ron::to_string(&Some(user.0)).expect("ser u64"),
+        };
+        let filter = match range {
+            Scan::All => Filter::All,
+            // 也包括这个群里的话题
+            Scan::Chat(_) => Filter::Chat(&value),
+            Scan::User(_) => Filter::User(&value),
+        };
+        let rows = self
+            .backend
+            .scan(Some(key), filter, after, SCAN_PAGE)
+            .await?;
+        let next = match rows.len() < SCAN_PAGE as usize {
+            true => None,
+            false => rows.last().map(|row| row.key.clone()),
+        };
+        let rows = rows
+            .into_iter()
+            .filter_map(|row| {
+                let Key { user, chat, .. } = &row.key;
+                let Some(id) = decode_id(ty, user, chat) else {
+                    warn!(target: "db", "bad id of {key}: user = {user}, chat = {chat}");
+                    return None;
+                };
+                Some((id, row.val, row.version))
+            })
+            .collect();
+        Ok((rows, next))
+    }
+
+    /// 缓存或写入队列中还没有写入数据库的值
+    fn latest<T: DbData>(&self, id: DataId) -> Option<(String, u32)> {
+        if let Some(Some(cached)) = self.cache.get(&id)
+            && let Ok(val) = cached.try_lock()
+        {
+            return Some((val.ser_data(), T::version()));
+        }
+        let pending = self.pending.lock().unwrap();
+        let write = pending.writes.get(&id)?;
+        Some((write.val.clone(), write.version))
+    }
+
+    /// 把无法读取的数据移到隔离表中
+    async fn quarantine(&self, ty: &str, id: DataId, error: &str) -> Result<(), DbError> {
+        self.backend.quarantine(&key(ty, id), error).await
+    }
+
+    /// 最近被隔离的数据，以及被隔离的总数
+    pub async fn quarantined(&self, limit: u32) -> Result<(Vec<Quarantined>, u32), DbError> {
+        self.backend.quarantined(limit).await
     }
 
+    pub async fn insert<T: DbData>(&'static self, id: DataId, val: T) -> Result<(), DbError> {
+        self.insert_raw(T::KEY, T::version(), id, &val.ser_data())
+            .await?;
+        self.cache.insert(id, Some(Arc::new(Mutex::new(val))));
+        Ok(())
+    }
+    async fn insert_raw(
+        &'static self,
+        ty: &str,
+        version: u32,
+        id: DataId,
+        val: &str,
+    ) -> Result<(), DbError> {
+        let _lock = self.write_lock.lock().await;
+        // 直接写入的数据比队列中的新
+        self.pending.lock().unwrap().writes.remove(&id);
+        let row = DataRow {
+            key: key(ty, id),
+            val: val.to_string(),
+            version,
+        };
+        self.backend.upsert(&[row]).await
+    }
+
+    pub async fn remove<T: DbData>(&'static self, id: DataId) -> Result<(), DbError> {
+        self.remove_raw(T::KEY, id).await
+    }
+    async fn remove_raw(&'static self, ty: &str, id: DataId) -> Result<(), DbError> {
+        self.cache.insert(id, None);
+        let _lock = self.write_lock.lock().await;
+        self.pending.lock().unwrap().writes.remove(&id);
+        self.backend.remove(&key(ty, id)).await
+    }
+
+    /// 删除范围内所有注册类型的数据，返回删除的行数，例如删除一个用户的所有数据
+    pub async fn remove_all(
+        &'static self,
+        types: &[&[DataType]],
+        range: Scan,
+    ) -> Result<usize, DbError> {
+        // 队列中的数据可能还没有写入数据库，先写入才能遍历到
+        self.flush().await?;
+        let mut removed = 0;
+        for ty in types.iter().flat_map(|types| types.iter()) {
+            let mut after = None;
+            loop {
+                let (rows, next) = self
+                    .scan_page(ty.key, (ty.type_id)(), range, after.as_ref())
+                    .await?;
+                for (id, _, _) in rows {
+                    self.remove_raw(ty.key, id).await?;
+                    removed += 1;
+                }
+                match next {
+                    Some(next) => after = Some(next),
+                    None => break,
+                }
+            }
+        }
+        Ok(removed)
+    }
+
+    /// 把修改放入写入队列，需要时安排写入任务
+    fn enqueue(&'static self, id: DataId, mut write: PendingWrite) {
+        let mut pending = self.pending.lock().unwrap();
+        write.seq = pending.next_seq;
+        pending.next_seq += 1;
+        pending.writes.insert(id, write);
+        if !pending.scheduled {
+            pending.scheduled = true;
+            tokio::spawn(async move {
+                tokio::time::sleep(FLUSH_DELAY).await;
+                if let Err(err) = self.flush().await {
+                    error!(target: "db", "failed to flush pending writes: {err}");
+                }
+            });
+        }
+    }
+
+    /// 在一个事务中写入队列中的所有修改
+    async fn flush(&self) -> Result<(), DbError> {
+        let _lock = self.write_lock.lock().await;
+        // 写入成功之前数据留在队列中，这期间缓存未命中时仍然从队列中读取；
+        // 写入失败时下次写入或关闭时重试
+        let (seqs, rows): (Vec<_>, Vec<_>) = {
+            let mut pending = self.pending.lock().unwrap();
+            pending.scheduled = false;
+            pending
+                .writes
+                .iter()
+                .map(|(id, write)| {
+                    let row = DataRow {
+                        key: key(write.key, *id),
+                        val: write.val.clone(),
+                        version: write.version,
+                    };
+                    ((*id, write.seq), row)
+                })
+                .unzip()
+        };
+        if rows.is_empty() {
+            return Ok(());
+        }
+        self.backend.upsert(&rows).await?;
+        let mut pending = self.pending.lock().unwrap();
+        for (id, seq) in seqs {
+            if pending
+                .writes
+                .get(&id)
+                .is_some_and(|write| write.seq == seq)
+            {
+                pending.writes.remove(&id);
+            }
+        }
+        Ok(())
+    }
+
+    /// 启动时把以前按 `type_name` 保存的数据改为 [DbData::KEY]，
+    /// 并报告不属于任何注册类型的数据和无法读取的新版本数据
+    pub async fn check_types(&self, types: &[&[DataType]]) -> anyhow::Result<()> {
+        let types = types
+            .iter()
+            .flat_map(|types| types.iter())
+            .collect::<Vec<_>>();
+        let mut keys = HashMap::new();
+        for ty in &types {
+            if let Some(other) = keys.insert(ty.key, (ty.type_name)()) {
+                warn!(target: "db", "{} and {other} have the same key {}", (ty.type_name)(), ty.key);
+            }
+            let renamed = self.backend.rename_type((ty.type_name)(), ty.key).await?;
+            if renamed > 0 {
+                info!(target: "db", "renamed {renamed} rows of {} to {}", (ty.type_name)(), ty.key);
+            }
+        }
+        let mut total = 0;
+        for TypeStat {
+            ty: key,
+            version,
+            count,
+        } in self.backend.type_stats().await?
+        {
+            total += count;
+            match types.iter().find(|ty| ty.key == key) {
+                None => {
+                    warn!(target: "db", "{count} rows of {key} do not belong to any registered type")
+                }
+                Some(ty) if version > ty.version => warn!(
+                    target: "db",
+                    "{count} rows of {key} have version {version}, newer than {}",
+                    ty.version
+                ),
+                Some(_) => {}
+            }
+        }
+        info!(target: "db", "{total} rows of {} registered types", types.len());
+        Ok(())
+    }
+
+    /// 写入队列中剩余的修改并关闭数据库
     pub async fn close(&self) {
-        let mut db = self.db.lock().await;
-        if let Some(db) = db.take() {
-            db.close()
-                .await
-                .expect("Failed to close database connection");
+        if let Err(err) = self.flush().await {
+            error!(target: "db", "failed to flush pending writes: {err}");
         }
+        self.backend.close().await;
     }
 }
 
-trait QueryExt<'q> {
-    fn bind_id<'a>(self, ty: &'a str, id: DataId) -> Self
-    where
-        'a: 'q;
-}
-impl<'q> QueryExt<'q> for Query<'q, Sqlite, SqliteArguments> {
-    fn bind_id<'a>(self, ty: &'a str, id: DataId) -> Self
-    where
-        'a: 'q,
-    {
-        self.bind(ty)
-            .bind(ron::to_string(&id.user.map(|u| u.0)).expect("ser u64"))
-            .bind(ron::to_string(&id.chat.map(|c| c.0)).expect("ser i64"))
+fn key(ty: &str, id: DataId) -> Key {
+    let (user, chat) = encode_id(id);
+    Key {
+        ty: ty.to_string(),
+        user,
+        chat,
     }
 }
 
+/// 数据库中 `user` 和 `chat` 两列的值
+fn encode_id(id: DataId) -> (String, String) {
+    let chat = id.chat.map(|c| c.0);
+    // 不在话题中时和原来的格式相同
+    let chat = match id.thread {
+        Some(thread) => ron::to_string(&(chat, thread.0.0)),
+        None => ron::to_string(&chat),
+    };
+    (
+        ron::to_string(&id.user.map(|u| u.0)).expect("ser u64"),
+        chat.expect("ser i64"),
+    )
+}
+
+fn decode_id(ty: TypeId, user: &str, chat: &str) -> Option<DataId> {
+    let user = ron::from_str::<Option<u64>>(user).ok()?.map(UserId);
+    let (chat, thread) = match ron::from_str::<Option<i64>>(chat) {
+        Ok(chat) => (chat, None),
+        Err(_) => {
+            let (chat, thread) = ron::from_str::<(Option<i64>, i32)>(chat).ok()?;
+            (chat, Some(ThreadId(MessageId(thread))))
+        }
+    };
+    Some(DataId {
+        ty,
+        chat: chat.map(ChatId),
+        thread,
+        user,
+    })
+}
+
 pub struct DataGuard<T: DbData> {
     db: &'static DataStorage,
     id: DataId,
@@ -221,15 +662,17 @@ impl<T: DbData> DerefMut for DataGuard<T> {
 }
 impl<T: DbData> Drop for DataGuard<T> {
     fn drop(&mut self) {
-        let Self {
-            db, id, changed, ..
-        } = *self;
-        let sub = self.sub.ser_data();
-        tokio::spawn(async move {
-            if changed {
-                db.insert_raw(type_name::<T>(), id, &sub).await;
-            }
-        });
+        // 使用期间数据被删除的话不再写回
+        if self.changed && !matches!(self.db.cache.get(&self.id), Some(None)) {
+            let write = PendingWrite {
+                key: T::KEY,
+                version: T::version(),
+                val: self.sub.ser_data(),
+                // 由 [DataStorage::enqueue] 分配
+                seq: 0,
+            };
+            self.db.enqueue(self.id, write);
+        }
     }
 }
 
@@ -237,6 +680,7 @@ pub struct DataBuilder<T: DbData> {
     db: &'static DataStorage,
     phantom_t: PhantomData<T>,
     chat: Option<ChatId>,
+    thread: Option<ThreadId>,
     user: Option<UserId>,
 }
 
@@ -245,6 +689,12 @@ impl<T: DbData> DataBuilder<T> {
         self.chat = Some(chat);
         self
     }
+    /// 按论坛话题区分的数据，不在话题中时和 [DataBuilder::chat] 相同
+    pub fn topic(mut self, (chat, thread): Topic) -> Self {
+        self.chat = Some(chat);
+        self.thread = thread;
+        self
+    }
     pub fn user(mut self, user: UserId) -> Self {
         self.user = Some(user);
         self
@@ -254,32 +704,311 @@ impl<T: DbData> DataBuilder<T> {
         DataId {
             ty: TypeId::of::<T>(),
             chat: self.chat,
+            thread: self.thread,
             user: self.user,
         }
     }
 
-    pub async fn get(self) -> Option<DataGuard<T>> {
+    pub async fn get(self) -> Result<Option<DataGuard<T>>, DbError> {
         self.db.get(self.data_id()).await
     }
 
-    #[allow(dead_code)]
-    pub async fn insert(self, val: T) {
+    pub async fn insert(self, val: T) -> Result<(), DbError> {
         self.db.insert(self.data_id(), val).await
     }
 
-    pub async fn get_or_insert(self, mk: impl FnOnce() -> T) -> DataGuard<T> {
+    pub async fn get_or_insert(self, mk: impl FnOnce() -> T) -> Result<DataGuard<T>, DbError> {
         self.db.get_or_insert(self.data_id(), mk).await
     }
 
-    #[allow(dead_code)]
-    pub async fn remove(self) {
+    pub async fn remove(self) -> Result<(), DbError> {
         self.db.remove::<T>(self.data_id()).await
     }
 }
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+/// [DataStorage::scan] 的范围
+#[derive(Debug, Clone, Copy)]
+pub enum Scan {
+    /// 所有数据
+    All,
+    /// 一个聊天的数据，包括其中的话题和聊天中每个用户的数据
+    Chat(ChatId),
+    /// 一个用户的数据，包括在各个聊天中的数据
+    User(UserId),
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub struct DataId {
     pub ty: TypeId,
     pub chat: Option<ChatId>,
+    pub thread: Option<ThreadId>,
     pub user: Option<UserId>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Setting {
+        limit: u32,
+        enabled: bool,
+    }
+
+    #[derive(Deserialize)]
+    struct SettingV1 {
+        limit: u32,
+    }
+
+    impl DbData for Setting {
+        const KEY: &str = "test::Setting";
+        const MIGRATIONS: &[Migration] = &[
+            // 第 0 版只保存了 limit
+            |src| Ok(format!("(limit: {src})")),
+            |src| {
+                let old = ron::from_str::<SettingV1>(src)?;
+                Ok(ron::to_string(&Setting {
+                    limit: old.limit,
+                    enabled: true,
+                })?)
+            },
+        ];
+    }
+
+    #[test]
+    fn migrate_on_read() {
+        let expected = Setting {
+            limit: 3,
+            enabled: true,
+        };
+        assert_eq!(Setting::version(), 2);
+        assert_eq!(Setting::deser_data("3", 0).unwrap(), expected);
+        assert_eq!(Setting::deser_data("(limit: 3)", 1).unwrap(), expected);
+        let current = expected.ser_data();
+        assert_eq!(Setting::deser_data(&current, 2).unwrap(), expected);
+        assert!(Setting::deser_data(&current, 3).is_err());
+        assert!(Setting::deser_data("oops", 0).is_err());
+        let ty = DataType::of::<Setting>();
+        assert_eq!((ty.key, ty.version), ("test::Setting", 2));
+    }
+
+    async fn open(url: &str) -> &'static DataStorage {
+        let backend = db_backend::connect(url).await.unwrap();
+        Box::leak(Box::new(DataStorage::with_backend(backend)))
+    }
+
+    #[tokio::test]
+    async fn quarantine_corrupted() {
+        let db = open("sqlite::memory:").await;
+        let id = DataId {
+            ty: TypeId::of::<Setting>(),
+            chat: Some(ChatId(1)),
+            thread: None,
+            user: None,
+        };
+        db.insert_raw(Setting::KEY, Setting::version(), id, "oops")
+            .await
+            .unwrap();
+        assert!(matches!(
+            db.get::<Setting>(id).await,
+            Err(DbError::Corrupted {
+                key: "test::Setting",
+                ..
+            })
+        ));
+        let (rows, count) = db.quarantined(10).await.unwrap();
+        assert_eq!(count, 1);
+        assert_eq!(rows[0].ty, "test::Setting");
+        // 损坏的数据已经移走，可以重新写入
+        assert!(db.get::<Setting>(id).await.unwrap().is_none());
+        let setting = db
+            .get_or_insert(id, || Setting {
+                limit: 1,
+                enabled: false,
+            })
+            .await
+            .unwrap();
+        assert_eq!(setting.limit, 1);
+    }
+
+    fn test_id(chat: i64) -> DataId {
+        DataId {
+            ty: TypeId::of::<Setting>(),
+            chat: Some(ChatId(chat)),
+            thread: None,
+            user: None,
+        }
+    }
+
+    async fn bump(db: &'static DataStorage, id: DataId) {
+        let mut setting = db
+            .get_or_insert(id, || Setting {
+                limit: 0,
+                enabled: true,
+            })
+            .await
+            .unwrap();
+        setting.limit += 1;
+    }
+
+    #[tokio::test]
+    async fn coalesce_writes() {
+        let db = open("sqlite::memory:").await;
+        for _ in 0..10 {
+            bump(db, test_id(1)).await;
+        }
+        bump(db, test_id(2)).await;
+        assert_eq!(db.pending.lock().unwrap().writes.len(), 2);
+        db.flush().await.unwrap();
+        assert!(db.pending.lock().unwrap().writes.is_empty());
+        let (val, _) = db
+            .backend
+            .get(&key(Setting::KEY, test_id(1)))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(ron::from_str::<Setting>(&val).unwrap().limit, 10);
+    }
+
+    #[tokio::test]
+    async fn scan_ranges() {
+        let db = open("sqlite::memory:").await;
+        let ids = [
+            test_id(1),
+            DataId {
+                thread: Some(ThreadId(MessageId(5))),
+                ..test_id(1)
+            },
+            DataId {
+                user: Some(UserId(7)),
+                ..test_id(1)
+            },
+            DataId {
+                user: Some(UserId(7)),
+                ..test_id(2)
+            },
+            test_id(-100),
+        ];
+        for id in ids {
+            let (user, chat) = encode_id(id);
+            assert_eq!(decode_id(id.ty, &user, &chat), Some(id));
+            bump(db, id).await;
+        }
+        // 还在写入队列中的修改
+        bump(db, test_id(1)).await;
+        let scan = |range| async move {
+            let mut res = db
+                .scan::<Setting>(range)
+                .map(|row| {
+                    let (id, setting) = row.unwrap();
+                    (
+                        id.chat.map(|c| c.0),
+                        id.thread.is_some(),
+                        id.user.is_some(),
+                        setting.limit,
+                    )
+                })
+                .collect::<Vec<_>>()
+                .await;
+            res.sort();
+            res
+        };
+        assert_eq!(
+            scan(Scan::Chat(ChatId(1))).await,
+            vec![
+                (Some(1), false, false, 2),
+                (Some(1), false, true, 1),
+                (Some(1), true, false, 1)
+            ]
+        );
+        assert_eq!(
+            scan(Scan::User(UserId(7))).await,
+            vec![(Some(1), false, true, 1), (Some(2), false, true, 1)]
+        );
+        assert_eq!(scan(Scan::All).await.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn cache_absence() {
+        let db = open("sqlite::memory:").await;
+        assert!(db.get::<Setting>(test_id(1)).await.unwrap().is_none());
+        // 绕过缓存写入的数据读不到，说明没有再次查询数据库
+        let row = DataRow {
+            key: key(Setting::KEY, test_id(1)),
+            val: Setting {
+                limit: 5,
+                enabled: true,
+            }
+            .ser_data(),
+            version: Setting::version(),
+        };
+        db.backend.upsert(&[row]).await.unwrap();
+        assert!(db.get::<Setting>(test_id(1)).await.unwrap().is_none());
+        bump(db, test_id(1)).await;
+        let setting = db.get::<Setting>(test_id(1)).await.unwrap().unwrap();
+        assert_eq!(setting.limit, 1);
+        drop(setting);
+        db.remove::<Setting>(test_id(1)).await.unwrap();
+        assert!(db.get::<Setting>(test_id(1)).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn remove_while_held() {
+        let db = open("sqlite::memory:").await;
+        bump(db, test_id(1)).await;
+        db.flush().await.unwrap();
+        let mut setting = db.get::<Setting>(test_id(1)).await.unwrap().unwrap();
+        setting.limit += 1;
+        db.remove::<Setting>(test_id(1)).await.unwrap();
+        drop(setting);
+        assert!(db.pending.lock().unwrap().writes.is_empty());
+        assert!(db.get::<Setting>(test_id(1)).await.unwrap().is_none());
+        let row = db
+            .backend
+            .get(&key(Setting::KEY, test_id(1)))
+            .await
+            .unwrap();
+        assert!(row.is_none());
+    }
+
+    #[tokio::test]
+    async fn remove_user_data() {
+        let db = open("sqlite::memory:").await;
+        let user = |chat, user| DataId {
+            user: Some(UserId(user)),
+            ..test_id(chat)
+        };
+        bump(db, user(1, 7)).await;
+        db.flush().await.unwrap();
+        // 还在写入队列中的修改也会被删除
+        bump(db, user(2, 7)).await;
+        bump(db, user(1, 8)).await;
+        bump(db, test_id(1)).await;
+        let types: &[&[DataType]] = &[&[DataType::of::<Setting>()]];
+        let removed = db.remove_all(types, Scan::User(UserId(7))).await.unwrap();
+        assert_eq!(removed, 2);
+        assert!(db.get::<Setting>(user(1, 7)).await.unwrap().is_none());
+        assert!(db.get::<Setting>(user(2, 7)).await.unwrap().is_none());
+        assert!(db.get::<Setting>(user(1, 8)).await.unwrap().is_some());
+        assert_eq!(db.scan::<Setting>(Scan::All).count().await, 2);
+    }
+
+    #[tokio::test]
+    async fn close_drains_writes() {
+        let path = std::env::temp_dir().join(format!("linquebot-db-{}.db", std::process::id()));
+        let url = format!("sqlite:{}", path.display());
+        let db = open(&url).await;
+        for _ in 0..50 {
+            bump(db, test_id(1)).await;
+        }
+        db.close().await;
+        assert!(db.pending.lock().unwrap().writes.is_empty());
+
+        let db = open(&url).await;
+        let setting = db.get::<Setting>(test_id(1)).await.unwrap().unwrap();
+        assert_eq!(setting.limit, 50);
+        drop(setting);
+        db.close().await;
+        std::fs::remove_file(&path).unwrap();
+    }
+}
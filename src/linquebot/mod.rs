@@ -1,17 +1,34 @@
+pub mod backlog;
+pub mod callback;
+pub mod chat_modules;
 pub mod db;
+pub mod db_backend;
 pub mod msg_context;
+pub mod outbox;
+pub mod permission;
+pub mod reply;
+pub mod scheduler;
 pub mod vector_db;
+pub mod webhook;
 
 use std::{future::Future, pin::Pin};
 
 use msg_context::{CmdParts, Context};
 use teloxide_core::{
     prelude::*,
-    types::{CallbackQuery, ChatMemberUpdated, Message},
+    types::{CallbackQuery, ChatMemberUpdated, InlineQuery, Message},
 };
 
 use crate::DataStorage;
 use crate::VectorDB;
+use crate::utils::telegram::prelude::MessageExtension;
+use backlog::Backlog;
+use callback::Callback;
+use outbox::Outbox;
+pub use permission::Permission;
+use permission::Permissions;
+use reply::ReplyRecords;
+use scheduler::{Job, Scheduler};
 
 pub type TaskResult = Pin<Box<dyn Future<Output = ()> + Send>>;
 
@@ -99,6 +116,8 @@ pub struct ModuleDescription {
     pub description: &'static str,
     /// 单独显示的详细提示。当为 None 时，help 页不会显示详细信息按钮。
     pub description_detailed: Option<&'static str>,
+    /// 执行模块需要的权限，由 [crate::resolvers::message::resolve] 统一检查
+    pub permission: Permission,
 }
 
 /// 模块类型
@@ -111,9 +130,22 @@ pub enum ModuleKind {
 }
 
 /// 其他 Telegram Updates 的响应器
+#[allow(clippy::enum_variant_names)]
 pub enum MicroTask {
-    OnCallbackQuery(fn(app: &'static App, query: &CallbackQuery) -> types::Consumption),
+    /// 按钮的回调，第一个参数是 [Callback::module]
+    OnCallback(
+        &'static str,
+        fn(app: &'static App, query: &CallbackQuery, callback: &Callback) -> types::Consumption,
+    ),
     OnMyChatMember(fn(app: &'static App, data: &ChatMemberUpdated) -> types::Consumption),
+    /// 群成员的状态变化，琳酱需要是管理员
+    OnChatMember(fn(app: &'static App, data: &ChatMemberUpdated) -> types::Consumption),
+    OnInlineQuery(fn(app: &'static App, query: &InlineQuery) -> types::Consumption),
+    /// 定时任务的回调，第一个参数是 [Job::module]
+    OnScheduledJob(
+        &'static str,
+        fn(app: &'static App, job: &Job) -> types::Consumption,
+    ),
 }
 
 /// 消息处理模块
@@ -145,18 +177,39 @@ pub struct App {
     pub db: DataStorage,
     /// vector database for searching
     pub vector_db: anyhow::Result<VectorDB>,
+    /// policy for messages received after being offline
+    pub backlog: Backlog,
+    /// bot owners and cached admin rights
+    pub permissions: Permissions,
+    /// persistent scheduled jobs
+    pub scheduler: Scheduler,
+    /// replies sent for each message, used to edit them when the message is edited
+    pub replies: ReplyRecords,
+    /// rate limited queue for outgoing messages
+    pub outbox: Outbox,
     /// modules loaded
     pub modules: &'static [&'static Module],
+    /// modules that also handle edited messages, in the same order as `modules`
+    pub edit_modules: &'static [&'static Module],
     /// micor_tasks loaded
     pub micro_tasks: &'static [&'static MicroTask],
 }
 
 impl App {
+    /// 模块是否处理被编辑的消息
+    pub fn handles_edit(&self, module: &Module) -> bool {
+        self.edit_modules
+            .iter()
+            .any(|edit_module| std::ptr::eq(*edit_module, module))
+    }
+
     pub fn create_message_context<'a>(&'static self, message: &'a Message) -> Context<'a> {
         Context {
             cmd: CmdParts::parse_from(message),
             message_id: message.id,
             chat_id: message.chat.id,
+            thread_id: message.topic_id(),
+            edited: message.edit_date().is_some(),
             app: self,
         }
     }
@@ -1,11 +1,27 @@
+use std::sync::LazyLock;
+
 use teloxide_core::{
     payloads::SendMessage,
     prelude::*,
     requests::JsonRequest,
-    types::{ChatId, Message, MessageId, ParseMode, ReplyParameters},
+    types::{ChatId, Message, MessageId, ParseMode, ReplyParameters, ThreadId},
 };
 
-use super::{App, ModuleDescription};
+use super::{App, ModuleDescription, db::DbError, reply::Reply};
+use crate::utils::telegram::prelude::{InTopic, WarnOnError};
+
+/// 聊天和其中的论坛话题，不在话题中时为 `None`。用于按话题区分的状态
+pub type Topic = (ChatId, Option<ThreadId>);
+
+/// 设置 `PER_TOPIC_STATE=true` 时，复读、接龙和语料学习的开关在论坛的每个话题中分开，否则整个群共用
+static PER_TOPIC_STATE: LazyLock<bool> = LazyLock::new(|| {
+    std::env::var("PER_TOPIC_STATE").is_ok_and(|val| matches!(val.trim(), "true" | "1"))
+});
+
+/// 保存状态用的范围，见 [PER_TOPIC_STATE]
+fn scope_of((chat_id, thread_id): Topic) -> Topic {
+    (chat_id, thread_id.filter(|_| *PER_TOPIC_STATE))
+}
 
 /// Command parts of /xxx@yyy zzz
 #[derive(PartialEq, Eq, Debug, Clone, Copy)]
@@ -44,7 +60,11 @@ impl<'a> CmdParts<'a> {
 pub struct Context<'a> {
     pub cmd: Option<CmdParts<'a>>,
     pub chat_id: ChatId,
+    /// 消息所在的论坛话题
+    pub thread_id: Option<ThreadId>,
     pub message_id: MessageId,
+    /// 消息是否是被编辑过的
+    pub edited: bool,
     pub app: &'static App,
 }
 
@@ -53,10 +73,21 @@ impl Context<'_> {
         TaskContext {
             message_id: self.message_id,
             chat_id: self.chat_id,
+            thread_id: self.thread_id,
+            edited: self.edited,
             app: self.app,
         }
     }
 
+    pub fn topic(&self) -> Topic {
+        (self.chat_id, self.thread_id)
+    }
+
+    /// 保存状态用的范围，只有打开 `PER_TOPIC_STATE` 时才区分话题
+    pub fn scope(&self) -> Topic {
+        scope_of(self.topic())
+    }
+
     pub fn matches_command(&self, desc: &ModuleDescription) -> bool {
         let Some(cmd) = &self.cmd else {
             return false;
@@ -73,25 +104,68 @@ impl Context<'_> {
 #[derive(Clone)]
 pub struct TaskContext {
     pub chat_id: ChatId,
+    /// 消息所在的论坛话题，发送的消息都会在这个话题中
+    pub thread_id: Option<ThreadId>,
     pub message_id: MessageId,
+    /// 消息是否是被编辑过的，此时 [TaskContext::reply] 会编辑之前的回复
+    pub edited: bool,
     pub app: &'static App,
 }
 
 impl TaskContext {
-    pub fn reply(&self, text: impl Into<String>) -> JsonRequest<SendMessage> {
+    pub fn reply(&self, text: impl Into<String>) -> Reply {
+        Reply::new(
+            self.app,
+            self.chat_id,
+            self.message_id,
+            self.edited,
+            SendMessage::new(self.chat_id, text)
+                .reply_parameters(ReplyParameters::new(self.message_id))
+                .in_topic(self.thread_id),
+        )
+    }
+
+    /// 不回复地发送到当前聊天和话题
+    pub fn send_message(&self, text: impl Into<String>) -> JsonRequest<SendMessage> {
         self.app
             .bot
             .send_message(self.chat_id, text)
-            .reply_parameters(ReplyParameters::new(self.message_id))
+            .in_topic(self.thread_id)
+    }
+
+    pub fn topic(&self) -> Topic {
+        (self.chat_id, self.thread_id)
+    }
+
+    /// 保存状态用的范围，只有打开 `PER_TOPIC_STATE` 时才区分话题
+    pub fn scope(&self) -> Topic {
+        scope_of(self.topic())
     }
 
-    pub fn reply_markdown(&self, text: impl Into<String>) -> JsonRequest<SendMessage> {
+    pub fn reply_markdown(&self, text: impl Into<String>) -> Reply {
         self.reply(text).parse_mode(ParseMode::MarkdownV2)
     }
 
-    pub fn reply_html(&self, text: impl Into<String>) -> JsonRequest<SendMessage> {
+    pub fn reply_html(&self, text: impl Into<String>) -> Reply {
         self.reply(text).parse_mode(ParseMode::Html)
     }
+
+    /// 数据库出错时记录日志并回复用户
+    pub async fn reply_db_error(&self, name: &str, err: DbError) {
+        log::warn!(target: name, "{err}");
+        self.reply(err.friendly()).send().warn_on_error(name).await;
+    }
+
+    /// 执行会读写数据库的任务，出错时回复用户
+    pub async fn with_db(
+        &self,
+        name: &'static str,
+        task: impl Future<Output = Result<(), DbError>>,
+    ) {
+        if let Err(err) = task.await {
+            self.reply_db_error(name, err).await;
+        }
+    }
 }
 
 #[cfg(test)]
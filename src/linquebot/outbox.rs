@@ -0,0 +1,257 @@
+//! 发送消息的排队和限速
+//!
+//! Telegram 限制每个群每分钟约 20 条消息、每个私聊每秒约 1 条、全局每秒约 30 条，超过后返回 429。
+//! [Outbox] 为每个聊天和全局各维护一个令牌桶，发送前按顺序预约发送时间并等待；
+//! 遇到 `RetryAfter` 时暂停对应的聊天再重试，连接失败按指数退避重试。
+//! 其他网络错误（比如超时）时请求可能已经到达 Telegram，重试会发出重复的消息，所以直接返回错误。
+//!
+//! [TaskContext::reply] 返回的 [Reply] 会自动经过这里，其他请求可以使用 [Outbox::send]：
+//! ```ignore
+//! app.outbox.send(chat_id, &app.bot.send_message(chat_id, "喵")).await;
+//! ```
+//!
+//! [TaskContext::reply]: super::msg_context::TaskContext::reply
+//! [Reply]: super::reply::Reply
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use log::{info, warn};
+use teloxide_core::{
+    RequestError,
+    requests::{Output, Request},
+    types::ChatId,
+};
+use tokio::time::{Instant, sleep, sleep_until};
+
+/// 遇到 `RetryAfter` 时最多重试的次数
+const MAX_RETRY_AFTER: u32 = 5;
+/// 连接失败时最多重试的次数，第 n 次等待 2^n 秒
+const MAX_NETWORK_RETRIES: u32 = 3;
+/// 等待超过这个时间时记录日志
+const LOG_WAIT: Duration = Duration::from_secs(1);
+
+/// 令牌桶。令牌可以是负数，表示已经预约到未来的发送
+#[derive(Debug)]
+struct Bucket {
+    /// 每秒补充的令牌数
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+    /// 收到 `RetryAfter` 后暂停到这个时间
+    paused_until: Option<Instant>,
+}
+
+impl Bucket {
+    fn new(rate: f64, capacity: f64, now: Instant) -> Self {
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            updated: now,
+            paused_until: None,
+        }
+    }
+
+    /// 群组和频道每分钟 20 条，私聊每秒 1 条
+    fn for_chat(chat_id: ChatId, now: Instant) -> Self {
+        if chat_id.is_user() {
+            Self::new(1.0, 3.0, now)
+        } else {
+            Self::new(20.0 / 60.0, 5.0, now)
+        }
+    }
+
+    fn global(now: Instant) -> Self {
+        Self::new(30.0, 30.0, now)
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if now > self.updated {
+            let elapsed = (now - self.updated).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+            self.updated = now;
+        }
+    }
+
+    /// 预约一次发送，返回可以发送的时间
+    fn reserve(&mut self, now: Instant) -> Instant {
+        self.refill(now);
+        self.tokens -= 1.0;
+        let at = if self.tokens >= 0.0 {
+            now
+        } else {
+            now + Duration::from_secs_f64(-self.tokens / self.rate)
+        };
+        match self.paused_until {
+            Some(until) if until > at => until,
+            _ => at,
+        }
+    }
+
+    fn pause(&mut self, until: Instant) {
+        self.paused_until = Some(self.paused_until.map_or(until, |old| old.max(until)));
+    }
+
+    /// 桶已经满了，并且没有暂停，可以丢弃
+    fn idle(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.capacity && self.paused_until.is_none_or(|until| until <= now)
+    }
+}
+
+/// 请求一定没有发出去的错误，重试不会重复发送
+fn retryable(err: &RequestError) -> bool {
+    match err {
+        RequestError::Network(err) => err.is_connect(),
+        RequestError::Io(_) => true,
+        _ => false,
+    }
+}
+
+#[derive(Debug)]
+pub struct Outbox {
+    global: Mutex<Bucket>,
+    chats: Mutex<HashMap<ChatId, Bucket>>,
+    /// 正在等待发送的请求数
+    queued: AtomicUsize,
+}
+
+impl Outbox {
+    pub fn new() -> Self {
+        Self {
+            global: Mutex::new(Bucket::global(Instant::now())),
+            chats: Mutex::new(HashMap::new()),
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    /// 正在等待发送的请求数
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    fn reserve(&self, chat_id: ChatId) -> Instant {
+        let now = Instant::now();
+        let chat_at = {
+            let mut chats = self.chats.lock().unwrap();
+            if chats.len() > 10000 {
+                chats.retain(|_, bucket| !bucket.idle(now));
+            }
+            chats
+                .entry(chat_id)
+                .or_insert_with(|| Bucket::for_chat(chat_id, now))
+                .reserve(now)
+        };
+        let global_at = self.global.lock().unwrap().reserve(now);
+        chat_at.max(global_at)
+    }
+
+    fn pause(&self, chat_id: ChatId, duration: Duration) {
+        let now = Instant::now();
+        self.chats
+            .lock()
+            .unwrap()
+            .entry(chat_id)
+            .or_insert_with(|| Bucket::for_chat(chat_id, now))
+            .pause(now + duration);
+    }
+
+    /// 等待轮到这个聊天发送。只需要限速、不需要重试的请求可以直接使用
+    pub async fn wait(&self, chat_id: ChatId) {
+        let at = self.reserve(chat_id);
+        let wait = at.saturating_duration_since(Instant::now());
+        if wait.is_zero() {
+            return;
+        }
+        let queued = self.queued.fetch_add(1, Ordering::Relaxed) + 1;
+        if wait >= LOG_WAIT {
+            info!(target: "outbox", "{queued} requests queued, {chat_id} waits {wait:?}");
+        }
+        sleep_until(at).await;
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// 限速并重试，`make` 每次调用都会重新发送请求
+    pub async fn run<T, F, Fut>(&self, chat_id: ChatId, mut make: F) -> Result<T, RequestError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, RequestError>>,
+    {
+        let mut retry_afters = 0;
+        let mut network_retries = 0;
+        loop {
+            self.wait(chat_id).await;
+            match make().await {
+                Err(RequestError::RetryAfter(secs)) if retry_afters < MAX_RETRY_AFTER => {
+                    retry_afters += 1;
+                    warn!(
+                        target: "outbox",
+                        "{chat_id} is rate limited, retry after {}s ({} requests queued)",
+                        secs.seconds(),
+                        self.queued()
+                    );
+                    self.pause(chat_id, secs.duration());
+                }
+                Err(err) if retryable(&err) && network_retries < MAX_NETWORK_RETRIES => {
+                    let backoff = Duration::from_secs(1 << network_retries);
+                    network_retries += 1;
+                    warn!(target: "outbox", "{err}, retry in {backoff:?}");
+                    sleep(backoff).await;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// 限速并重试地发送一个请求
+    pub async fn send<R>(&self, chat_id: ChatId, request: &R) -> Result<Output<R>, RequestError>
+    where
+        R: Request<Err = RequestError>,
+    {
+        self.run(chat_id, || request.send_ref()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_paces_bursts() {
+        let now = Instant::now();
+        let mut bucket = Bucket::new(1.0, 3.0, now);
+        for _ in 0..3 {
+            assert_eq!(bucket.reserve(now), now);
+        }
+        assert_eq!(bucket.reserve(now), now + Duration::from_secs(1));
+        assert_eq!(bucket.reserve(now), now + Duration::from_secs(2));
+        // 两秒后补充的令牌已经被预约了
+        let later = now + Duration::from_secs(2);
+        assert_eq!(bucket.reserve(later), now + Duration::from_secs(3));
+        assert!(!bucket.idle(later));
+        assert!(bucket.idle(now + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn bucket_pauses() {
+        let now = Instant::now();
+        let mut bucket = Bucket::new(1.0, 3.0, now);
+        let until = now + Duration::from_secs(5);
+        bucket.pause(until);
+        assert_eq!(bucket.reserve(now), until);
+        assert!(!bucket.idle(now + Duration::from_secs(4)));
+        assert_eq!(
+            bucket.reserve(now + Duration::from_secs(6)),
+            now + Duration::from_secs(6)
+        );
+    }
+}
@@ -0,0 +1,122 @@
+//! 命令的权限检查
+//!
+//! - `BOT_OWNERS`: 用逗号分隔的 bot 主人的 user id，主人拥有所有权限
+
+use std::{
+    collections::HashSet,
+    time::{Duration, Instant},
+};
+
+use log::warn;
+use quick_cache::sync::Cache;
+use teloxide_core::{
+    prelude::*,
+    types::{ChatId, Message, UserId},
+};
+
+/// 执行一个模块需要的权限
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Permission {
+    /// 任何人
+    Anyone,
+    /// 群管理员，私聊中总是满足
+    ChatAdmin,
+    /// bot 的主人
+    BotOwner,
+}
+
+impl Permission {
+    /// 权限不足时的统一回复，HTML 格式
+    pub fn refusal(&self) -> &'static str {
+        match self {
+            Permission::Anyone => "",
+            Permission::ChatAdmin => concat!(
+                "<s>You are not in the sudoers file. This incident will be reported.</s> ",
+                "只有管理员才能执行该命令哦。"
+            ),
+            Permission::BotOwner => "只有琳酱的主人才能执行该命令哦。",
+        }
+    }
+}
+
+/// 管理员身份的缓存时间
+const ADMIN_CACHE_TTL: Duration = Duration::from_secs(300);
+
+#[derive(Debug)]
+pub struct Permissions {
+    owners: HashSet<UserId>,
+    admin_cache: Cache<(ChatId, UserId), (Instant, bool)>,
+}
+
+impl Permissions {
+    pub fn new(owners: HashSet<UserId>) -> Self {
+        Self {
+            owners,
+            admin_cache: Cache::new(1000),
+        }
+    }
+
+    pub fn from_env() -> anyhow::Result<Self> {
+        let owners = match std::env::var("BOT_OWNERS") {
+            Ok(owners) => owners
+                .split(',')
+                .map(str::trim)
+                .filter(|id| !id.is_empty())
+                .map(|id| id.parse().map(UserId))
+                .collect::<Result<_, _>>()?,
+            Err(_) => HashSet::new(),
+        };
+        Ok(Self::new(owners))
+    }
+
+    pub fn is_owner(&self, user_id: UserId) -> bool {
+        self.owners.contains(&user_id)
+    }
+
+    /// 通过 `get_chat_member` 检查是否是管理员，结果会缓存一段时间
+    pub async fn is_chat_admin(&self, bot: &Bot, chat_id: ChatId, user_id: UserId) -> bool {
+        if let Some((at, is_admin)) = self.admin_cache.get(&(chat_id, user_id))
+            && at.elapsed() < ADMIN_CACHE_TTL
+        {
+            return is_admin;
+        }
+        let is_admin = match bot.get_chat_member(chat_id, user_id).send().await {
+            Ok(member) => member.is_privileged(),
+            Err(err) => {
+                warn!(
+                    "Failed to check admin right of {user_id} in {chat_id}, fallback to false. {err}"
+                );
+                return false;
+            }
+        };
+        self.admin_cache
+            .insert((chat_id, user_id), (Instant::now(), is_admin));
+        is_admin
+    }
+
+    /// 检查消息的发送者是否有 `permission`
+    pub async fn check(&self, bot: &Bot, message: &Message, permission: Permission) -> bool {
+        if permission == Permission::Anyone {
+            return true;
+        }
+        // 匿名管理员以群组自身的身份发言
+        if let Some(sender_chat) = &message.sender_chat
+            && sender_chat.id == message.chat.id
+        {
+            return permission == Permission::ChatAdmin;
+        }
+        let Some(user) = &message.from else {
+            return false;
+        };
+        if self.is_owner(user.id) {
+            return true;
+        }
+        match permission {
+            Permission::Anyone => true,
+            Permission::ChatAdmin => {
+                message.chat.is_private() || self.is_chat_admin(bot, message.chat.id, user.id).await
+            }
+            Permission::BotOwner => false,
+        }
+    }
+}
@@ -0,0 +1,201 @@
+//! 命令的回复
+//!
+//! [TaskContext::reply] 返回 [Reply]，经过 [crate::linquebot::outbox] 限速发送，发送成功后会记录触发消息到回复消息的对应关系。
+//! 处理被编辑的消息时（参见 [crate::mods::EDIT_MODULES]），[Reply] 会编辑之前的回复，而不是发送新的消息。
+//!
+//! 超过长度限制的回复会用 [split_message] 切成几段，依次回复上一段发送，按钮放在最后一段。
+//! 编辑时依次编辑之前的每一段，段数变多时发送新的段，变少时删除多余的段。
+//!
+//! [TaskContext::reply]: super::msg_context::TaskContext::reply
+
+use std::future::IntoFuture;
+
+use futures::future::BoxFuture;
+use log::warn;
+use quick_cache::sync::Cache;
+use teloxide_core::{
+    ApiError, RequestError,
+    payloads::SendMessage,
+    prelude::*,
+    requests::{HasPayload, JsonRequest},
+    types::{ChatId, Message, MessageId, ReplyMarkup, ReplyParameters},
+};
+
+use super::App;
+use crate::utils::split::{MESSAGE_LIMIT, split_message};
+
+/// 触发消息到回复消息（切分后的每一段）的对应关系，只保存最近的一部分
+#[derive(Debug)]
+pub struct ReplyRecords {
+    cache: Cache<(ChatId, MessageId), Vec<MessageId>>,
+}
+
+impl ReplyRecords {
+    pub fn new() -> Self {
+        Self {
+            cache: Cache::new(10000),
+        }
+    }
+
+    pub fn get(&self, chat_id: ChatId, trigger: MessageId) -> Option<Vec<MessageId>> {
+        self.cache.get(&(chat_id, trigger))
+    }
+
+    pub fn record(&self, chat_id: ChatId, trigger: MessageId, replies: Vec<MessageId>) {
+        self.cache.insert((chat_id, trigger), replies);
+    }
+}
+
+/// 对一条消息的回复，用法和 `JsonRequest<SendMessage>` 相同
+#[derive(Clone)]
+pub struct Reply {
+    app: &'static App,
+    chat_id: ChatId,
+    trigger: MessageId,
+    edited: bool,
+    payload: SendMessage,
+}
+
+impl Reply {
+    pub fn new(
+        app: &'static App,
+        chat_id: ChatId,
+        trigger: MessageId,
+        edited: bool,
+        payload: SendMessage,
+    ) -> Self {
+        Self {
+            app,
+            chat_id,
+            trigger,
+            edited,
+            payload,
+        }
+    }
+
+    /// 把 `payload` 编辑到之前的回复 `reply` 上
+    async fn edit(
+        app: &'static App,
+        chat_id: ChatId,
+        reply: MessageId,
+        payload: SendMessage,
+    ) -> Result<Message, RequestError> {
+        let mut edit = app
+            .bot
+            .edit_message_text(payload.chat_id, reply, payload.text);
+        let edit_payload = edit.payload_mut();
+        edit_payload.parse_mode = payload.parse_mode;
+        edit_payload.entities = payload.entities;
+        edit_payload.link_preview_options = payload.link_preview_options;
+        if let Some(ReplyMarkup::InlineKeyboard(markup)) = payload.reply_markup {
+            edit_payload.reply_markup = Some(markup);
+        }
+        app.outbox.send(chat_id, &edit).await
+    }
+
+    /// 发送或编辑每一段，返回第一段的结果
+    async fn send_reply(self) -> Result<Message, RequestError> {
+        let Self {
+            app,
+            chat_id,
+            trigger,
+            edited,
+            payload,
+        } = self;
+        // 自定义 entities 的偏移无法切分，交给 Telegram 处理
+        let chunks = match payload.entities {
+            Some(_) => vec![payload.text.clone()],
+            None => split_message(&payload.text, payload.parse_mode, MESSAGE_LIMIT),
+        };
+        let previous = match edited {
+            true => app.replies.get(chat_id, trigger).unwrap_or_default(),
+            false => vec![],
+        };
+        let count = chunks.len();
+        let mut sent = vec![];
+        let mut first = None;
+        for (i, text) in chunks.into_iter().enumerate() {
+            let mut chunk = payload.clone();
+            chunk.text = text;
+            if i + 1 < count {
+                chunk.reply_markup = None;
+            }
+            if let Some(last) = sent.last() {
+                chunk.reply_parameters = Some(ReplyParameters::new(*last));
+            }
+            let res = match previous.get(i) {
+                Some(&reply) => match Self::edit(app, chat_id, reply, chunk).await {
+                    // 这一段没有变化，继续编辑后面的段
+                    Err(RequestError::Api(ApiError::MessageNotModified)) => {
+                        sent.push(reply);
+                        first.get_or_insert(Err(RequestError::Api(ApiError::MessageNotModified)));
+                        continue;
+                    }
+                    res => res,
+                },
+                None => {
+                    let request = JsonRequest::new(app.bot.clone(), chunk);
+                    app.outbox.send(chat_id, &request).await
+                }
+            };
+            match res {
+                Ok(message) => {
+                    sent.push(message.id);
+                    first.get_or_insert(Ok(message));
+                }
+                Err(err) => {
+                    // 已经发送的段仍然记录下来，下次编辑时使用
+                    sent.extend(previous.iter().skip(sent.len()));
+                    app.replies.record(chat_id, trigger, sent);
+                    return Err(err);
+                }
+            }
+        }
+        for &extra in previous.iter().skip(count) {
+            if let Err(err) = app
+                .outbox
+                .send(chat_id, &app.bot.delete_message(chat_id, extra))
+                .await
+            {
+                warn!(target: "reply", "failed to delete extra reply {extra}: {err}");
+            }
+        }
+        app.replies.record(chat_id, trigger, sent);
+        first.expect("split_message returns at least one chunk")
+    }
+}
+
+impl HasPayload for Reply {
+    type Payload = SendMessage;
+
+    fn payload_mut(&mut self) -> &mut Self::Payload {
+        &mut self.payload
+    }
+
+    fn payload_ref(&self) -> &Self::Payload {
+        &self.payload
+    }
+}
+
+impl Request for Reply {
+    type Err = RequestError;
+    type Send = BoxFuture<'static, Result<Message, RequestError>>;
+    type SendRef = BoxFuture<'static, Result<Message, RequestError>>;
+
+    fn send(self) -> Self::Send {
+        Box::pin(self.send_reply())
+    }
+
+    fn send_ref(&self) -> Self::SendRef {
+        Box::pin(self.clone().send_reply())
+    }
+}
+
+impl IntoFuture for Reply {
+    type Output = Result<Message, RequestError>;
+    type IntoFuture = BoxFuture<'static, Result<Message, RequestError>>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        self.send()
+    }
+}
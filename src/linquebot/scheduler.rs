@@ -0,0 +1,239 @@
+//! 持久化的定时任务
+//!
+//! 任务按聊天保存在数据库中，启动时由 [Scheduler::restore] 重新加载。
+//! 到时间后，按任务的 `module` 找到 [MicroTask::OnScheduledJob] 注册的回调并执行，
+//! 回调完成后才删除任务；错过的任务和执行中途退出的任务会在启动后立即执行。
+//!
+//! 使用方式：
+//! ```ignore
+//! let id = app.scheduler.schedule(app, NewJob { module: "todo", .. }).await;
+//! app.scheduler.cancel(app, chat_id, id).await;
+//! ```
+//!
+//! 参见 [crate::mods::todo]
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    pin::pin,
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use futures::StreamExt;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use teloxide_core::types::{ChatId, MessageId, ThreadId};
+use tokio_util::sync::CancellationToken;
+
+use super::db::{DataType, DbData, DbError, Scan};
+use super::{App, MicroTask};
+
+/// 一个定时任务
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    /// 聊天中的任务 id
+    pub id: u64,
+    /// 回调对应的模块名
+    pub module: String,
+    pub chat_id: ChatId,
+    /// 任务所在的论坛话题
+    #[serde(default)]
+    pub thread_id: Option<ThreadId>,
+    /// 触发任务的消息
+    pub message_id: Option<MessageId>,
+    /// 执行时间
+    pub at: SystemTime,
+    /// 模块自定义的数据，ron 格式
+    pub payload: String,
+}
+
+impl Job {
+    pub fn payload<T: for<'a> Deserialize<'a>>(&self) -> Option<T> {
+        ron::from_str(&self.payload)
+            .inspect_err(|err| warn!(target: "scheduler", "bad payload of job {}: {err}", self.id))
+            .ok()
+    }
+}
+
+/// 新建任务的参数
+pub struct NewJob<'a, T: Serialize> {
+    pub module: &'static str,
+    pub chat_id: ChatId,
+    pub thread_id: Option<ThreadId>,
+    pub message_id: Option<MessageId>,
+    pub at: SystemTime,
+    pub payload: &'a T,
+}
+
+/// 一个聊天中的所有任务，每个聊天一行，一行损坏时不影响其他聊天
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ChatJobs {
+    next_id: u64,
+    jobs: BTreeMap<u64, Job>,
+}
+
+impl DbData for ChatJobs {
+    const KEY: &str = "scheduler::ChatJobs";
+}
+
+pub static DATA_TYPES: &[DataType] = &[DataType::of::<ChatJobs>()];
+
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    timers: Mutex<HashMap<(ChatId, u64), CancellationToken>>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 从数据库加载所有任务并开始计时，无法读取的聊天会被跳过
+    pub async fn restore(&'static self, app: &'static App) -> Result<(), DbError> {
+        let mut jobs = vec![];
+        let mut rows = pin!(app.db.scan::<ChatJobs>(Scan::All));
+        while let Some(row) = rows.next().await {
+            match row {
+                Ok((_, chat)) => {
+                    jobs.extend(chat.jobs.values().map(|job| (job.chat_id, job.id, job.at)))
+                }
+                Err(err) => error!(target: "scheduler", "failed to restore jobs: {err}"),
+            }
+        }
+        info!(target: "scheduler", "restored {} jobs", jobs.len());
+        for (chat_id, id, at) in jobs {
+            self.arm(app, chat_id, id, at);
+        }
+        Ok(())
+    }
+
+    /// 添加一个任务，返回任务在聊天中的 id
+    pub async fn schedule<T: Serialize>(
+        &'static self,
+        app: &'static App,
+        job: NewJob<'_, T>,
+    ) -> Result<u64, DbError> {
+        let job = {
+            let mut jobs = app
+                .db
+                .of::<ChatJobs>()
+                .chat(job.chat_id)
+                .get_or_insert(Default::default)
+                .await?;
+            jobs.next_id += 1;
+            let job = Job {
+                id: jobs.next_id,
+                module: job.module.to_string(),
+                chat_id: job.chat_id,
+                thread_id: job.thread_id,
+                message_id: job.message_id,
+                at: job.at,
+                payload: ron::to_string(job.payload).expect("ser error"),
+            };
+            jobs.jobs.insert(job.id, job.clone());
+            job
+        };
+        self.arm(app, job.chat_id, job.id, job.at);
+        Ok(job.id)
+    }
+
+    /// 取消一个任务，返回被取消的任务
+    pub async fn cancel(
+        &'static self,
+        app: &'static App,
+        chat_id: ChatId,
+        id: u64,
+    ) -> Result<Option<Job>, DbError> {
+        if let Ok(mut timers) = self.timers.lock()
+            && let Some(token) = timers.remove(&(chat_id, id))
+        {
+            token.cancel();
+        }
+        remove_job(app, chat_id, id).await
+    }
+
+    /// 某个模块在某个聊天中的所有任务，按执行时间排序
+    pub async fn jobs_of(
+        &'static self,
+        app: &'static App,
+        module: &str,
+        chat_id: ChatId,
+    ) -> Result<Vec<Job>, DbError> {
+        let Some(jobs) = app.db.of::<ChatJobs>().chat(chat_id).get().await? else {
+            return Ok(vec![]);
+        };
+        let mut jobs = jobs
+            .jobs
+            .values()
+            .filter(|job| job.module == module)
+            .cloned()
+            .collect::<Vec<_>>();
+        jobs.sort_by_key(|job| job.at);
+        Ok(jobs)
+    }
+
+    fn arm(&'static self, app: &'static App, chat_id: ChatId, id: u64, at: SystemTime) {
+        let token = CancellationToken::new();
+        match self.timers.lock() {
+            Ok(mut timers) => {
+                timers.insert((chat_id, id), token.clone());
+            }
+            Err(_) => error!(target: "scheduler", "Failed to lock timers"),
+        }
+        tokio::spawn(async move {
+            let delay = at.duration_since(SystemTime::now()).unwrap_or_default();
+            tokio::select! {
+                _ = token.cancelled() => {}
+                _ = tokio::time::sleep(delay) => self.fire(app, chat_id, id).await,
+            }
+        });
+    }
+
+    /// 执行任务，回调完成后才从数据库中删除，执行中途退出的任务会在重启后再次执行
+    async fn fire(&'static self, app: &'static App, chat_id: ChatId, id: u64) {
+        if let Ok(mut timers) = self.timers.lock() {
+            timers.remove(&(chat_id, id));
+        }
+        let job = match app.db.of::<ChatJobs>().chat(chat_id).get().await {
+            Ok(jobs) => jobs.and_then(|jobs| jobs.jobs.get(&id).cloned()),
+            Err(err) => {
+                error!(target: "scheduler", "failed to load job {id} in {chat_id}: {err}");
+                return;
+            }
+        };
+        let Some(job) = job else {
+            return;
+        };
+        let callback = app.micro_tasks.iter().find_map(|task| match task {
+            MicroTask::OnScheduledJob(module, callback) if *module == job.module => Some(callback),
+            _ => None,
+        });
+        match callback {
+            Some(callback) => {
+                if let Some(task) = callback(app, &job).task
+                    && let Err(err) = tokio::spawn(task).await
+                {
+                    error!(
+                        target: "scheduler",
+                        "job {id} of {} in {chat_id} failed and will run again after restart: {err}",
+                        job.module
+                    );
+                    return;
+                }
+            }
+            None => {
+                warn!(target: "scheduler", "no callback registered for job {id} of {}", job.module)
+            }
+        }
+        if let Err(err) = remove_job(app, chat_id, id).await {
+            error!(target: "scheduler", "failed to remove job {id} in {chat_id}: {err}");
+        }
+    }
+}
+
+async fn remove_job(app: &'static App, chat_id: ChatId, id: u64) -> Result<Option<Job>, DbError> {
+    let Some(mut jobs) = app.db.of::<ChatJobs>().chat(chat_id).get().await? else {
+        return Ok(None);
+    };
+    Ok(jobs.jobs.remove(&id))
+}
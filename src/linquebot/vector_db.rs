@@ -84,6 +84,15 @@ SET
     vector = $4::vector;
 "#;
 
+// NULLs never conflict in the unique constraint, so rows with the same key are removed first.
+const DELETE_VECTOR_QUERY: &str = r#"
+DELETE FROM vector_db
+WHERE index = $1
+    AND "user" IS NOT DISTINCT
+    FROM $2
+    AND chat = $3;
+"#;
+
 // When the length of the vector is 1, inner product is equivalent to the cosine similarity.
 const SELECT_VECTOR_QUERY: &str = r#"
 SELECT index,
@@ -120,13 +129,21 @@ impl VectorDB {
     }
 
     pub async fn upsert(&self, data: VectorData) -> anyhow::Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(DELETE_VECTOR_QUERY)
+            .bind(&data.index)
+            .bind(&data.user)
+            .bind(&data.chat)
+            .execute(&mut *tx)
+            .await?;
         sqlx::query(UPSERT_VECTOR_QUERY)
             .bind(&data.index)
             .bind(&data.user)
             .bind(&data.chat)
             .bind(format!("{:?}", data.vector))
-            .execute(&self.pool)
+            .execute(&mut *tx)
             .await?;
+        tx.commit().await?;
         Ok(())
     }
 
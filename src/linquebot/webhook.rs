@@ -0,0 +1,232 @@
+//! Webhook 模式
+//!
+//! 设置了 `WEBHOOK_URL` 时，琳酱不再轮询 `get_updates`，而是启动一个内置的 HTTP 服务，
+//! 接收 Telegram 推送的 `Update` JSON。
+//!
+//! - `WEBHOOK_URL`: 告诉 Telegram 的公开地址，例如 `https://example.com/linquebot`。
+//!   本地监听的路径与它的 path 部分一致，方便多个 bot 共用一个反向代理。
+//! - `WEBHOOK_LISTEN`: 本地监听地址，默认 `0.0.0.0:8080`
+//! - `WEBHOOK_SECRET`: 校验 `X-Telegram-Bot-Api-Secret-Token` 用的密钥，
+//!   只能包含 `A-Z a-z 0-9 _ -`。不填时每次启动随机生成。
+
+use std::net::SocketAddr;
+
+use axum::{
+    Router,
+    body::Bytes,
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    routing::post,
+};
+use log::{info, trace, warn};
+use rand::{Rng, distr::Alphanumeric};
+use reqwest::Url;
+use teloxide_core::{prelude::*, types::Update};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+use super::App;
+use crate::resolvers;
+use crate::resolvers::update::ALLOWED_UPDATES;
+
+pub const SECRET_HEADER: &str = "X-Telegram-Bot-Api-Secret-Token";
+
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    pub url: Url,
+    pub listen: SocketAddr,
+    pub secret: String,
+}
+
+impl WebhookConfig {
+    /// 从环境变量读取配置，没有设置 `WEBHOOK_URL` 时返回 `None`，即使用长轮询
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let Ok(url) = std::env::var("WEBHOOK_URL") else {
+            return Ok(None);
+        };
+        let url = Url::parse(&url)?;
+        let listen = match std::env::var("WEBHOOK_LISTEN") {
+            Ok(addr) => addr.parse()?,
+            Err(_) => SocketAddr::from(([0, 0, 0, 0], 8080)),
+        };
+        let secret = match std::env::var("WEBHOOK_SECRET") {
+            Ok(secret) => {
+                if secret.is_empty()
+                    || secret.len() > 256
+                    || !secret
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+                {
+                    anyhow::bail!(
+                        "WEBHOOK_SECRET must be 1-256 characters of A-Z, a-z, 0-9, _ and -"
+                    );
+                }
+                secret
+            }
+            Err(_) => rand::rng()
+                .sample_iter(Alphanumeric)
+                .take(64)
+                .map(char::from)
+                .collect(),
+        };
+        Ok(Some(Self {
+            url,
+            listen,
+            secret,
+        }))
+    }
+}
+
+#[derive(Clone)]
+struct WebhookState {
+    secret: String,
+    sender: mpsc::UnboundedSender<Update>,
+}
+
+async fn receive(State(state): State<WebhookState>, headers: HeaderMap, body: Bytes) -> StatusCode {
+    let secret = headers
+        .get(SECRET_HEADER)
+        .and_then(|value| value.to_str().ok());
+    if secret != Some(state.secret.as_str()) {
+        warn!(target: "webhook", "rejected request with wrong secret token");
+        return StatusCode::UNAUTHORIZED;
+    }
+    let update = match serde_json::from_slice::<Update>(&body) {
+        Ok(update) => update,
+        Err(err) => {
+            warn!(target: "webhook", "failed to parse update: {err}");
+            return StatusCode::BAD_REQUEST;
+        }
+    };
+    trace!(target: "webhook", "get update: {}", update.id.0);
+    if state.sender.send(update).is_err() {
+        return StatusCode::SERVICE_UNAVAILABLE;
+    }
+    StatusCode::OK
+}
+
+/// 构造接收 update 的路由，收到的 update 会按顺序发送到 `sender`
+pub fn router(path: &str, secret: String, sender: mpsc::UnboundedSender<Update>) -> Router {
+    Router::new()
+        .route(path, post(receive))
+        .with_state(WebhookState { secret, sender })
+}
+
+/// 以 webhook 模式运行，直到 `cancel_token` 被取消
+pub async fn run(
+    app: &'static App,
+    config: WebhookConfig,
+    cancel_token: CancellationToken,
+) -> anyhow::Result<()> {
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let listener = tokio::net::TcpListener::bind(config.listen).await?;
+    info!(target: "webhook", "Listening on {} at {}", config.listen, config.url.path());
+
+    let server_token = cancel_token.clone();
+    let server = tokio::spawn(
+        axum::serve(
+            listener,
+            router(config.url.path(), config.secret.clone(), sender),
+        )
+        .with_graceful_shutdown(async move { server_token.cancelled().await })
+        .into_future(),
+    );
+
+    info!(target: "webhook", "Setting webhook...");
+    if let Err(err) = app
+        .bot
+        .set_webhook(config.url.clone())
+        .secret_token(config.secret.clone())
+        .allowed_updates(ALLOWED_UPDATES.to_vec())
+        .send()
+        .await
+    {
+        // 没有设置好 webhook 时不会收到 update，停掉已经启动的服务
+        server.abort();
+        return Err(err.into());
+    }
+
+    loop {
+        let update = tokio::select! {
+            _ = cancel_token.cancelled() => break,
+            update = receiver.recv() => update,
+        };
+        let Some(update) = update else {
+            break;
+        };
+        resolvers::update::resolve(app, update).await;
+    }
+
+    info!(target: "webhook", "Deleting webhook...");
+    if let Err(err) = app.bot.delete_webhook().send().await {
+        warn!(target: "webhook", "Failed to delete webhook: {err}");
+    }
+    server.await??;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UPDATE: &str = r#"{
+        "update_id": 10000,
+        "message": {
+            "message_id": 1365,
+            "date": 1441645532,
+            "chat": { "id": 1111111, "type": "private", "first_name": "Test" },
+            "from": { "id": 1111111, "is_bot": false, "first_name": "Test" },
+            "text": "/help"
+        }
+    }"#;
+
+    async fn serve_router() -> (String, mpsc::UnboundedReceiver<Update>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::serve(listener, router("/hook", "secret".to_string(), sender)).into_future(),
+        );
+        (format!("http://{addr}/hook"), receiver)
+    }
+
+    #[tokio::test]
+    async fn webhook_accepts_update_with_secret() {
+        let (url, mut receiver) = serve_router().await;
+        let res = reqwest::Client::new()
+            .post(&url)
+            .header(SECRET_HEADER, "secret")
+            .body(UPDATE)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let update = receiver.recv().await.unwrap();
+        assert_eq!(update.id.0, 10000);
+    }
+
+    #[tokio::test]
+    async fn webhook_rejects_wrong_secret() {
+        let (url, mut receiver) = serve_router().await;
+        let client = reqwest::Client::new();
+        let res = client.post(&url).body(UPDATE).send().await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+        let res = client
+            .post(&url)
+            .header(SECRET_HEADER, "wrong")
+            .body(UPDATE)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+        let res = client
+            .post(&url)
+            .header(SECRET_HEADER, "secret")
+            .body("not json")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::BAD_REQUEST);
+        assert!(receiver.try_recv().is_err());
+    }
+}
@@ -23,17 +23,23 @@ mod resolvers;
 mod test_utils;
 mod utils;
 
+use crate::backlog::{Backlog, PollingOffset};
+use crate::chat_modules;
 use crate::db::DataStorage;
 use crate::linquebot::types::*;
 use crate::linquebot::*;
+use crate::outbox::Outbox;
+use crate::permission::Permissions;
+use crate::reply::ReplyRecords;
+use crate::scheduler::Scheduler;
 use crate::vector_db::VectorDB;
+use crate::webhook::WebhookConfig;
 use colored::Colorize;
 use env_logger::Env;
 use log::{error, info, warn};
 use resolvers::update::ALLOWED_UPDATES;
 use std::sync::OnceLock;
 use teloxide_core::prelude::*;
-use teloxide_core::types::BotCommand;
 use teloxide_core::types::True;
 use teloxide_core::RequestError;
 use tokio::signal;
@@ -42,25 +48,22 @@ use tokio_util::sync::CancellationToken;
 static APP: OnceLock<App> = OnceLock::new();
 
 async fn set_my_commands(app: &'static App) -> Result<True, RequestError> {
-    let commands = app.modules.iter().filter_map(|module| {
-        if let ModuleKind::Command(cmd) = &module.kind {
-            Some(BotCommand::new(cmd.name, cmd.description))
-        } else {
-            None
-        }
-    });
-
+    let commands = chat_modules::bot_commands(app.modules.iter().copied());
     app.bot.set_my_commands(commands).send().await
 }
 
 async fn init_app() -> anyhow::Result<&'static linquebot::App> {
     info!(target: "init", "Loading Database...");
     let db = DataStorage::new().await?;
+    db.check_types(mods::DATA_TYPES).await?;
     info!(target: "init", "Loading Vector Database...");
     let vector_db = VectorDB::new().await;
     if let Err(e) = &vector_db {
         warn!(target: "init", "Failed to initialize VectorDB:\n{}", e);
     }
+    let backlog = Backlog::from_env()?;
+    info!(target: "init", "Backlog policy: {:?}", backlog.policy);
+    let permissions = Permissions::from_env()?;
     info!(target: "init", "Initializing Bot...");
     let bot = Bot::from_env();
     info!(target: "init", "Checking Network...");
@@ -72,22 +75,38 @@ async fn init_app() -> anyhow::Result<&'static linquebot::App> {
         bot,
         db,
         vector_db,
+        backlog,
+        permissions,
+        scheduler: Scheduler::new(),
+        replies: ReplyRecords::new(),
+        outbox: Outbox::new(),
         modules: mods::MODULES,
+        edit_modules: mods::EDIT_MODULES,
         micro_tasks: mods::MICRO_TASKS,
     });
     let app = APP.get().expect("should initialized app");
     info!(target: "init", "user name: {}", app.username);
     info!(target: "init", "Setting commands...");
     set_my_commands(app).await?;
+    info!(target: "init", "Restoring scheduled jobs...");
+    app.scheduler.restore(app).await?;
+    info!(target: "init", "Restoring bot on/off states...");
+    mods::bot_on_off::restore(app).await?;
+    mods::waife::migrate_user_cache(app).await?;
     info!(target: "init", "{}", "Successfully initialized bot".green());
     Ok(app)
 }
 
-async fn main_loop(cancel_token: CancellationToken) -> anyhow::Result<()> {
-    let app = init_app().await?;
+async fn polling_loop(app: &'static App, cancel_token: CancellationToken) -> anyhow::Result<()> {
     let bot = &app.bot;
 
-    let mut offset: i32 = 0;
+    let mut offset = app
+        .db
+        .of::<PollingOffset>()
+        .get_or_insert(Default::default)
+        .await?
+        .0;
+    info!(target: "main-loop", "Start polling from offset {offset}");
 
     loop {
         let pms = bot
@@ -99,18 +118,30 @@ async fn main_loop(cancel_token: CancellationToken) -> anyhow::Result<()> {
 
         let res = tokio::select! {
             _ = cancel_token.cancelled() => {
-                app.db.close().await;
                 break Ok(());
             }
             res = pms => res
         };
         match res {
             Ok(updates) => {
-                offset = updates.last().map(|u| u.id.0 as i32 + 1).unwrap_or(offset);
+                let Some(last) = updates.last() else {
+                    continue;
+                };
+                offset = last.id.0 as i32 + 1;
 
                 for update in updates {
                     resolvers::update::resolve(app, update).await;
                 }
+
+                match app
+                    .db
+                    .of::<PollingOffset>()
+                    .get_or_insert(Default::default)
+                    .await
+                {
+                    Ok(mut saved) => saved.0 = offset,
+                    Err(err) => error!(target: "main-loop", "Failed to save offset: {err}"),
+                }
             }
             Err(err) => {
                 warn!(target: "main-loop", "GetUpdate Error: {}", err);
@@ -119,6 +150,18 @@ async fn main_loop(cancel_token: CancellationToken) -> anyhow::Result<()> {
     }
 }
 
+async fn main_loop(cancel_token: CancellationToken) -> anyhow::Result<()> {
+    let webhook = WebhookConfig::from_env()?;
+    let app = init_app().await?;
+
+    let res = match webhook {
+        Some(config) => webhook::run(app, config, cancel_token).await,
+        None => polling_loop(app, cancel_token).await,
+    };
+    app.db.close().await;
+    res
+}
+
 async fn wait_for_ctrlc(cancel_token: CancellationToken) {
     signal::ctrl_c().await.expect("Failed to listen for Ctrl-C");
     println!(); // Print a newline to separate the Ctrl-C message from the previous output
@@ -130,6 +173,16 @@ async fn wait_for_ctrlc(cancel_token: CancellationToken) {
 async fn main() {
     dotenvy::dotenv().ok();
     env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    let args = std::env::args().collect::<Vec<_>>();
+    if let [_, cmd, from, to] = args.as_slice()
+        && cmd == "migrate-db"
+    {
+        if let Err(err) = db_backend::migrate_urls(from, to).await {
+            error!("failed to migrate database: {err:#}");
+            std::process::exit(1);
+        }
+        return;
+    }
     let cancel_token = CancellationToken::new();
     tokio::spawn(wait_for_ctrlc(cancel_token.clone()));
     if let Err(err) = main_loop(cancel_token.clone()).await {
@@ -11,13 +11,17 @@ use crate::assets::answer_book;
 use crate::assets::bad_answer_book;
 use crate::linquebot::*;
 
+/// 从《答案之书》中随机选一个答案
+pub fn random_answer() -> &'static str {
+    answer_book::ANSWERS
+        .choose(&mut rng())
+        .expect("answers will never be empty")
+}
+
 fn on_message(ctx: &mut Context, _message: &Message) -> Consumption {
     let ctx = ctx.task();
     async move {
-        let chosen = answer_book::ANSWERS
-            .choose(&mut rng())
-            .expect("answers will never be empty");
-        let res = ctx.reply(*chosen).send().await;
+        let res = ctx.reply(random_answer()).send().await;
         if let Err(err) = res {
             warn!("Failed to send reply: {}", err);
         }
@@ -47,6 +51,7 @@ pub static MODULE: Module = Module {
             "该命令不需要参数。\n",
             "调用《答案之书》给出<del>显然一点用也没有的</del>回答。"
         )),
+        permission: Permission::Anyone,
     }),
     task: on_message,
 };
@@ -56,6 +61,7 @@ pub static MODULE_BAD: Module = Module {
         name: "bad_answer",
         description: "抽象之书",
         description_detailed: Some(concat!("该命令不需要参数。\n", "怪东西。")),
+        permission: Permission::Anyone,
     }),
     task: on_bad_answer_message,
 };
@@ -0,0 +1,449 @@
+//! 防刷屏
+//! ```text
+//! /antiflood [on|off]
+//! /antiflood limit <条数> <秒数>
+//! /antiflood repeat <条数>
+//! /antiflood sticker <条数>
+//! /antiflood action <warn|delete|mute 时长>
+//! ```
+//! 在内存中记录每个人最近发送的消息，短时间内消息过多、重复相同内容或者连续发贴纸时处理。
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use teloxide_core::prelude::*;
+use teloxide_core::types::*;
+
+use crate::Consumption;
+use crate::linquebot::db::{DataType, DbData, WarnDbError};
+use crate::linquebot::msg_context::{Context, TaskContext};
+use crate::linquebot::*;
+use crate::mods::moderation::{self, Actor, LogEntry, MUTE_RANGE, MUTE_RANGE_HINT, ModAction};
+use crate::utils::split_args;
+use crate::utils::telegram::prelude::*;
+use crate::utils::time::{describe_duration, parse_duration};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum FloodAction {
+    Warn,
+    Delete,
+    Mute(Duration),
+}
+
+impl FloodAction {
+    fn describe(&self) -> String {
+        match self {
+            FloodAction::Warn => "警告".to_string(),
+            FloodAction::Delete => "删除刷屏的消息".to_string(),
+            FloodAction::Mute(duration) => {
+                format!("删除刷屏的消息并禁言 {}", describe_duration(*duration))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct FloodSetting {
+    enabled: bool,
+    /// `window` 秒内最多发送的消息数
+    max_messages: usize,
+    window: u64,
+    /// `window` 秒内最多重复相同内容的次数
+    max_repeats: usize,
+    /// `window` 秒内最多发送的贴纸数
+    max_stickers: usize,
+    action: FloodAction,
+}
+
+impl DbData for FloodSetting {
+    const KEY: &str = "antiflood::FloodSetting";
+}
+
+pub static DATA_TYPES: &[DataType] = &[DataType::of::<FloodSetting>()];
+
+impl Default for FloodSetting {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_messages: 10,
+            window: 10,
+            max_repeats: 4,
+            max_stickers: 5,
+            action: FloodAction::Delete,
+        }
+    }
+}
+
+impl FloodSetting {
+    fn describe(&self) -> String {
+        format!(
+            concat!(
+                "本群的防刷屏已{}\n",
+                "{} 秒内最多 {} 条消息，相同内容最多 {} 次，贴纸最多 {} 个\n",
+                "刷屏时{}"
+            ),
+            if self.enabled { "打开" } else { "关闭" },
+            self.window,
+            self.max_messages,
+            self.max_repeats,
+            self.max_stickers,
+            self.action.describe()
+        )
+    }
+
+    /// 修改设置，参数不对时返回要回复的提示，不修改设置
+    fn update(&mut self, cmd: &str, args: &str) -> Result<(), &'static str> {
+        let [first, second] = split_args(args);
+        let count = || first.parse().ok().filter(|count| *count >= 2).ok_or(USAGE);
+        match cmd {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "limit" => {
+                let max_messages = count()?;
+                self.window = second
+                    .parse()
+                    .ok()
+                    .filter(|secs| (1..=600).contains(secs))
+                    .ok_or(USAGE)?;
+                self.max_messages = max_messages;
+            }
+            "repeat" => self.max_repeats = count()?,
+            "sticker" => self.max_stickers = count()?,
+            "action" => {
+                self.action = match first {
+                    "warn" => FloodAction::Warn,
+                    "delete" => FloodAction::Delete,
+                    "mute" => {
+                        let duration = parse_duration(second).ok_or(USAGE)?;
+                        if !MUTE_RANGE.contains(&duration) {
+                            return Err(MUTE_RANGE_HINT);
+                        }
+                        FloodAction::Mute(duration)
+                    }
+                    _ => return Err(USAGE),
+                }
+            }
+            _ => return Err(USAGE),
+        }
+        Ok(())
+    }
+}
+
+const USAGE: &str = concat!(
+    "用法：\n",
+    "/antiflood [on|off]\n",
+    "/antiflood limit <条数> <秒数>\n",
+    "/antiflood repeat <条数>\n",
+    "/antiflood sticker <条数>\n",
+    "/antiflood action <warn|delete|mute 时长>"
+);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FloodKind {
+    Messages,
+    Repeats,
+    Stickers,
+}
+
+impl FloodKind {
+    fn describe(self) -> &'static str {
+        match self {
+            FloodKind::Messages => "短时间内发送了太多消息",
+            FloodKind::Repeats => "重复发送相同的内容",
+            FloodKind::Stickers => "连续发送贴纸",
+        }
+    }
+}
+
+/// 最近的一条消息
+#[derive(Debug, Clone, Copy)]
+struct Seen {
+    at: Instant,
+    message_id: MessageId,
+    /// 文字或贴纸的哈希
+    fingerprint: Option<u64>,
+    sticker: bool,
+}
+
+impl Seen {
+    fn of(msg: &Message) -> Self {
+        let content = msg.text().or(msg.caption()).or(msg
+            .sticker()
+            .map(|sticker| sticker.file.unique_id.0.as_str()));
+        let fingerprint = content.map(|content| {
+            let mut hasher = DefaultHasher::new();
+            content.hash(&mut hasher);
+            hasher.finish()
+        });
+        Self {
+            at: Instant::now(),
+            message_id: msg.id,
+            fingerprint,
+            sticker: msg.sticker().is_some(),
+        }
+    }
+}
+
+/// 一个人在一个群里最近的消息
+#[derive(Debug, Default)]
+struct Window {
+    seen: VecDeque<Seen>,
+}
+
+impl Window {
+    /// 记录一条消息，刷屏时返回刷屏的类型和这段时间内的消息，并清空记录
+    fn push(&mut self, seen: Seen, setting: &FloodSetting) -> Option<(FloodKind, Vec<MessageId>)> {
+        let window = Duration::from_secs(setting.window);
+        while self
+            .seen
+            .front()
+            .is_some_and(|first| seen.at.duration_since(first.at) > window)
+        {
+            self.seen.pop_front();
+        }
+        self.seen.push_back(seen);
+        let repeats = match seen.fingerprint {
+            Some(fingerprint) => self
+                .seen
+                .iter()
+                .filter(|other| other.fingerprint == Some(fingerprint))
+                .count(),
+            None => 0,
+        };
+        let stickers = self.seen.iter().filter(|other| other.sticker).count();
+        let kind = if repeats > setting.max_repeats {
+            FloodKind::Repeats
+        } else if seen.sticker && stickers > setting.max_stickers {
+            FloodKind::Stickers
+        } else if self.seen.len() > setting.max_messages {
+            FloodKind::Messages
+        } else {
+            return None;
+        };
+        let burst = self.seen.drain(..).map(|seen| seen.message_id).collect();
+        Some((kind, burst))
+    }
+}
+
+static WINDOWS: LazyLock<Mutex<HashMap<(ChatId, UserId), Window>>> =
+    LazyLock::new(Default::default);
+
+fn track(
+    chat_id: ChatId,
+    user_id: UserId,
+    seen: Seen,
+    setting: &FloodSetting,
+) -> Option<(FloodKind, Vec<MessageId>)> {
+    let mut windows = WINDOWS.lock().ok()?;
+    if windows.len() > 10000 {
+        let window = Duration::from_secs(setting.window);
+        windows.retain(|_, other| {
+            other
+                .seen
+                .back()
+                .is_some_and(|last| seen.at.duration_since(last.at) <= window)
+        });
+    }
+    windows
+        .entry((chat_id, user_id))
+        .or_default()
+        .push(seen, setting)
+}
+
+async fn on_flood(
+    ctx: &TaskContext,
+    user: User,
+    setting: FloodSetting,
+    kind: FloodKind,
+    burst: Vec<MessageId>,
+) {
+    let app = ctx.app;
+    if app
+        .permissions
+        .is_chat_admin(&app.bot, ctx.chat_id, user.id)
+        .await
+    {
+        return;
+    }
+    if setting.action != FloodAction::Warn {
+        app.bot
+            .delete_messages(ctx.chat_id, burst)
+            .send()
+            .warn_on_error("antiflood")
+            .await;
+        moderation::record(
+            app,
+            ctx.chat_id,
+            LogEntry::new(Actor::Bot, &user, ModAction::Delete, kind.describe()),
+        )
+        .await;
+    }
+    if let FloodAction::Mute(duration) = setting.action {
+        let action = ModAction::Mute {
+            duration: Some(duration),
+        };
+        moderation::execute(ctx, Actor::Bot, &user, action, kind.describe()).await;
+    }
+    let request = ctx
+        .send_message(format!(
+            "{} {}，请不要刷屏",
+            user.html_link(),
+            kind.describe()
+        ))
+        .parse_mode(ParseMode::Html);
+    ctx.app
+        .outbox
+        .send(ctx.chat_id, &request)
+        .warn_on_error("antiflood")
+        .await;
+}
+
+fn on_message(ctx: &mut Context, msg: &Message) -> Consumption {
+    if msg.chat.is_private() || msg.sender_chat.is_some() {
+        return Consumption::just_next();
+    }
+    let user = msg.from.as_ref()?.clone();
+    let seen = Seen::of(msg);
+    let ctx = ctx.task();
+    Consumption::next_with(async move {
+        let Some(setting) = ctx
+            .app
+            .db
+            .of::<FloodSetting>()
+            .chat(ctx.chat_id)
+            .get()
+            .await
+            .ok_or_warn("antiflood")
+            .flatten()
+            .map(|setting| setting.clone())
+        else {
+            return;
+        };
+        if !setting.enabled {
+            return;
+        }
+        if let Some((kind, burst)) = track(ctx.chat_id, user.id, seen, &setting) {
+            on_flood(&ctx, user, setting, kind, burst).await;
+        }
+    })
+}
+
+fn on_antiflood(ctx: &mut Context, _: &Message) -> Consumption {
+    let [cmd, args] = split_args(ctx.cmd?.content);
+    let (cmd, args) = (cmd.to_string(), args.to_string());
+    let ctx = ctx.task();
+    async move {
+        let mut setting = match ctx
+            .app
+            .db
+            .of::<FloodSetting>()
+            .chat(ctx.chat_id)
+            .get_or_insert(Default::default)
+            .await
+        {
+            Ok(setting) => setting,
+            Err(err) => return ctx.reply_db_error("antiflood", err).await,
+        };
+        let text = if cmd.is_empty() {
+            setting.describe()
+        } else {
+            match setting.update(&cmd, &args) {
+                Ok(()) => setting.describe(),
+                Err(hint) => hint.to_string(),
+            }
+        };
+        drop(setting);
+        ctx.reply(text).send().warn_on_error("antiflood").await;
+    }
+    .into()
+}
+
+pub static MODULE: Module = Module {
+    kind: ModuleKind::General(None),
+    task: on_message,
+};
+
+pub static COMMAND: Module = Module {
+    kind: ModuleKind::Command(ModuleDescription {
+        name: "antiflood",
+        description: "设置防刷屏",
+        description_detailed: Some(concat!(
+            "/antiflood on 或 /antiflood off 打开或关闭防刷屏\n",
+            "/antiflood limit 10 10 设置 10 秒内最多 10 条消息\n",
+            "/antiflood repeat 4 设置最多重复相同内容 4 次\n",
+            "/antiflood sticker 5 设置最多连续发送 5 个贴纸\n",
+            "/antiflood action warn|delete|mute 10m 设置刷屏时警告、删除刷屏的消息或禁言\n",
+            "管理员不受限制"
+        )),
+        permission: Permission::ChatAdmin,
+    }),
+    task: on_antiflood,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seen(at: Instant, id: i32, fingerprint: u64, sticker: bool) -> Seen {
+        Seen {
+            at,
+            message_id: MessageId(id),
+            fingerprint: Some(fingerprint),
+            sticker,
+        }
+    }
+
+    #[test]
+    fn detect_floods() {
+        let setting = FloodSetting::default();
+        let start = Instant::now();
+        let mut window = Window::default();
+        for i in 0..10 {
+            assert_eq!(window.push(seen(start, i, i as u64, false), &setting), None);
+        }
+        let (kind, burst) = window.push(seen(start, 10, 10, false), &setting).unwrap();
+        assert_eq!(kind, FloodKind::Messages);
+        assert_eq!(burst.len(), 11);
+
+        for i in 0..4 {
+            assert_eq!(window.push(seen(start, i, 0, false), &setting), None);
+        }
+        let (kind, _) = window.push(seen(start, 4, 0, false), &setting).unwrap();
+        assert_eq!(kind, FloodKind::Repeats);
+
+        for i in 0..5 {
+            assert_eq!(window.push(seen(start, i, i as u64, true), &setting), None);
+        }
+        let (kind, _) = window.push(seen(start, 5, 5, true), &setting).unwrap();
+        assert_eq!(kind, FloodKind::Stickers);
+    }
+
+    #[test]
+    fn update_settings() {
+        let mut setting = FloodSetting::default();
+        assert_eq!(setting.update("action", "mute 10m"), Ok(()));
+        assert_eq!(setting.action, FloodAction::Mute(Duration::from_secs(600)));
+        assert_eq!(setting.update("action", "mute 10s"), Err(MUTE_RANGE_HINT));
+        assert_eq!(setting.update("action", "mute 400d"), Err(MUTE_RANGE_HINT));
+        assert_eq!(setting.update("action", "mute"), Err(USAGE));
+        assert_eq!(setting.action, FloodAction::Mute(Duration::from_secs(600)));
+        let max_messages = setting.max_messages;
+        assert_eq!(setting.update("limit", "5 0"), Err(USAGE));
+        assert_eq!(setting.max_messages, max_messages);
+    }
+
+    #[test]
+    fn old_messages_expire() {
+        let setting = FloodSetting::default();
+        let start = Instant::now();
+        let mut window = Window::default();
+        for i in 0..20 {
+            let at = start + Duration::from_secs(2 * i as u64);
+            assert_eq!(window.push(seen(at, i, i as u64, false), &setting), None);
+        }
+    }
+}
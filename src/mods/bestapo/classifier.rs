@@ -0,0 +1,310 @@
+//! 从管理员的 /spam 和 /ham 中学习的朴素贝叶斯分类器
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use teloxide_core::types::MessageId;
+use unicode_segmentation::UnicodeSegmentation;
+
+use super::rules::Action;
+use crate::linquebot::db::DbData;
+
+/// 两类样本都至少有这么多时才开始判断
+pub const MIN_SAMPLES: u32 = 5;
+/// 向量相似度检查认为是斯帕姆的最大夹角（度）
+pub const MAX_SPAM_ANGLE: f32 = 25.0;
+const MAX_FLAGS: usize = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Label {
+    Spam,
+    Ham,
+}
+
+/// 分词：小写的单词，以及相邻单词组成的词对。
+/// 中文每个字是一个单词，所以词对相当于二元组。
+pub fn tokenize(text: &str) -> HashSet<String> {
+    let words = text
+        .unicode_words()
+        .map(|word| word.to_lowercase())
+        .collect::<Vec<_>>();
+    let pairs = words.windows(2).map(|pair| pair.concat());
+    pairs.chain(words.iter().cloned()).collect()
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Counts {
+    docs: u32,
+    tokens: HashMap<String, u32>,
+}
+
+impl Counts {
+    fn add(&mut self, tokens: &HashSet<String>) {
+        self.docs += 1;
+        for token in tokens {
+            *self.tokens.entry(token.clone()).or_default() += 1;
+        }
+    }
+
+    fn sub(&mut self, tokens: &HashSet<String>) {
+        self.docs = self.docs.saturating_sub(1);
+        for token in tokens {
+            if let Some(count) = self.tokens.get_mut(token) {
+                *count -= 1;
+                if *count == 0 {
+                    self.tokens.remove(token);
+                }
+            }
+        }
+    }
+
+    /// 包含这个词的文档比例，加一平滑
+    fn ratio(&self, token: &str) -> f64 {
+        (*self.tokens.get(token).unwrap_or(&0) as f64 + 1.0) / (self.docs as f64 + 2.0)
+    }
+}
+
+/// 一个群的标注语料训练出的模型
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SpamCorpus {
+    spam: Counts,
+    ham: Counts,
+    /// 已经标注过的消息，重新标注时先撤销原来的
+    labels: HashMap<MessageId, Label>,
+}
+
+impl DbData for SpamCorpus {
+    const KEY: &str = "bestapo::SpamCorpus";
+}
+
+impl SpamCorpus {
+    fn counts(&mut self, label: Label) -> &mut Counts {
+        match label {
+            Label::Spam => &mut self.spam,
+            Label::Ham => &mut self.ham,
+        }
+    }
+
+    /// 加入一条标注，返回原来的标注
+    pub fn learn(&mut self, message_id: MessageId, text: &str, label: Label) -> Option<Label> {
+        let tokens = tokenize(text);
+        let old = self.labels.insert(message_id, label);
+        match old {
+            Some(old) if old == label => return Some(old),
+            Some(old) => self.counts(old).sub(&tokens),
+            None => {}
+        }
+        self.counts(label).add(&tokens);
+        old
+    }
+
+    pub fn samples(&self) -> (u32, u32) {
+        (self.spam.docs, self.ham.docs)
+    }
+
+    /// 是斯帕姆的概率，样本不足时返回 None
+    pub fn score(&self, text: &str) -> Option<f64> {
+        if self.spam.docs < MIN_SAMPLES || self.ham.docs < MIN_SAMPLES {
+            return None;
+        }
+        let tokens = tokenize(text);
+        if tokens.is_empty() {
+            return None;
+        }
+        let prior = (self.spam.docs as f64 / self.ham.docs as f64).ln();
+        let log_odds = tokens
+            .iter()
+            .filter(|token| {
+                self.spam.tokens.contains_key(*token) || self.ham.tokens.contains_key(*token)
+            })
+            .map(|token| (self.spam.ratio(token) / self.ham.ratio(token)).ln())
+            .sum::<f64>()
+            + prior;
+        Some(1.0 / (1.0 + (-log_odds).exp()))
+    }
+}
+
+/// 分类器的设置
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SpamFilter {
+    /// 为 None 时不使用分类器
+    pub action: Option<Action>,
+    pub threshold: f64,
+    /// 分数不够但超过一半时，再和已知斯帕姆的向量比较
+    pub embedding: bool,
+}
+
+impl DbData for SpamFilter {
+    const KEY: &str = "bestapo::SpamFilter";
+}
+
+impl Default for SpamFilter {
+    fn default() -> Self {
+        Self {
+            action: Some(Action::Warn),
+            threshold: 0.95,
+            embedding: false,
+        }
+    }
+}
+
+/// 分类器的一次处理
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Flag {
+    pub message_id: MessageId,
+    /// 琳酱发出的警告
+    pub warning_id: Option<MessageId>,
+    pub text: String,
+    pub score: f64,
+    pub at: SystemTime,
+    /// 被管理员用 /ham 纠正过
+    pub false_positive: bool,
+}
+
+/// 分类器的效果统计
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SpamStats {
+    pub flagged: u32,
+    pub false_positives: u32,
+    /// 管理员用 /spam 报告、但分类器没有发现的消息
+    pub missed: u32,
+    pub flags: VecDeque<Flag>,
+}
+
+impl DbData for SpamStats {
+    const KEY: &str = "bestapo::SpamStats";
+}
+
+impl SpamStats {
+    /// 记录被处理的消息，被编辑后再次处理时只更新之前的记录
+    pub fn flag(&mut self, mut flag: Flag) {
+        if let Some(old) = self
+            .flags
+            .iter_mut()
+            .find(|old| old.message_id == flag.message_id)
+        {
+            flag.false_positive = old.false_positive;
+            flag.warning_id = flag.warning_id.or(old.warning_id);
+            *old = flag;
+            return;
+        }
+        self.flagged += 1;
+        self.flags.push_back(flag);
+        while self.flags.len() > MAX_FLAGS {
+            self.flags.pop_front();
+        }
+    }
+
+    /// 找到被处理的消息，或琳酱对它的警告
+    pub fn find(&mut self, message_id: MessageId) -> Option<&mut Flag> {
+        self.flags
+            .iter_mut()
+            .find(|flag| flag.message_id == message_id || flag.warning_id == Some(message_id))
+    }
+
+    pub fn report(&self, samples: (u32, u32)) -> String {
+        let true_positives = self.flagged - self.false_positives;
+        let percent = |num: u32, den: u32| {
+            if den == 0 {
+                "-".to_string()
+            } else {
+                format!("{:.1}%", num as f64 * 100.0 / den as f64)
+            }
+        };
+        format!(
+            concat!(
+                "语料：{} 条斯帕姆，{} 条正常消息\n",
+                "分类器处理了 {} 条消息，其中 {} 条被纠正为正常消息\n",
+                "管理员另外报告了 {} 条漏掉的斯帕姆\n",
+                "精确率：{}\n",
+                "召回率：{}"
+            ),
+            samples.0,
+            samples.1,
+            self.flagged,
+            self.false_positives,
+            self.missed,
+            percent(true_positives, self.flagged),
+            percent(true_positives, true_positives + self.missed),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn corpus() -> SpamCorpus {
+        let mut corpus = SpamCorpus::default();
+        let spam = [
+            "免费领取 NFT 空投，点击链接",
+            "free airdrop claim now",
+            "USDT 空投 点击领取",
+            "claim your free NFT now",
+            "空投福利 免费领取",
+        ];
+        let ham = [
+            "今天吃什么",
+            "晚上一起打游戏吗",
+            "这个 bug 我修好了",
+            "free time tomorrow?",
+            "早安喵",
+        ];
+        for (i, text) in spam.iter().enumerate() {
+            corpus.learn(MessageId(i as i32), text, Label::Spam);
+        }
+        for (i, text) in ham.iter().enumerate() {
+            corpus.learn(MessageId(100 + i as i32), text, Label::Ham);
+        }
+        corpus
+    }
+
+    #[test]
+    fn score_messages() {
+        let corpus = corpus();
+        assert!(corpus.score("免费领取空投").unwrap() > 0.9);
+        assert!(corpus.score("claim free NFT").unwrap() > 0.9);
+        assert!(corpus.score("今天一起吃饭吗").unwrap() < 0.5);
+        assert!(SpamCorpus::default().score("空投").is_none());
+    }
+
+    fn flag_of(message_id: i32, warning_id: Option<i32>) -> Flag {
+        Flag {
+            message_id: MessageId(message_id),
+            warning_id: warning_id.map(MessageId),
+            text: "空投".to_string(),
+            score: 0.9,
+            at: SystemTime::now(),
+            false_positive: false,
+        }
+    }
+
+    #[test]
+    fn flag_edited_once() {
+        let mut stats = SpamStats::default();
+        stats.flag(flag_of(1, Some(2)));
+        stats.flag(flag_of(3, None));
+        // 消息被编辑后再次被处理
+        stats.flag(flag_of(1, None));
+        assert_eq!(stats.flagged, 2);
+        assert_eq!(stats.flags.len(), 2);
+        assert_eq!(stats.find(MessageId(2)).unwrap().message_id, MessageId(1));
+    }
+
+    #[test]
+    fn relabel() {
+        let mut corpus = corpus();
+        let text = "免费领取 NFT 空投，点击链接";
+        assert_eq!(
+            corpus.learn(MessageId(0), text, Label::Spam),
+            Some(Label::Spam)
+        );
+        assert_eq!(corpus.samples(), (5, 5));
+        assert_eq!(
+            corpus.learn(MessageId(0), text, Label::Ham),
+            Some(Label::Spam)
+        );
+        assert_eq!(corpus.samples(), (4, 6));
+    }
+}
@@ -0,0 +1,191 @@
+use teloxide_core::{prelude::Request, types::Message};
+
+use super::classifier::{SpamCorpus, SpamFilter, SpamStats};
+use super::rules::{Action, BestapoRules, Matcher, Scope, Target};
+use crate::{
+    linquebot::{
+        Module, ModuleDescription, ModuleKind, Permission, msg_context::Context, types::Consumption,
+    },
+    utils::{split_args, telegram::prelude::WarnOnError},
+};
+
+const RULE_USAGE: &str = concat!(
+    "用法：\n",
+    "/bestapo rule add [all|channel] [warn|delete|ban] <keyword|regex|deny_domain|allow_domain> <内容>\n",
+    "/bestapo rule remove <编号>\n",
+    "/bestapo rule list\n",
+    "/bestapo rule test <文本>\n",
+    "/bestapo classifier [warn|delete|ban|off]\n",
+    "/bestapo threshold <0.5~1>\n",
+    "/bestapo embedding [on|off]\n",
+    "/bestapo stats"
+);
+
+/// 解析 `[all|channel] [warn|delete|ban] <类型> <内容>`
+fn parse_rule(mut args: &str) -> Result<(Matcher, Scope, Action), String> {
+    let mut scope = Scope::ChannelReplies;
+    let mut action = Action::Delete;
+    loop {
+        let [word, rest] = split_args(args);
+        match word {
+            "all" => scope = Scope::All,
+            "channel" => scope = Scope::ChannelReplies,
+            word => match Action::parse(word) {
+                Some(parsed) => action = parsed,
+                None => return Ok((Matcher::parse(word, rest)?, scope, action)),
+            },
+        }
+        args = rest;
+    }
+}
+
+fn on_rule(rules: &mut BestapoRules, args: &str) -> String {
+    let [cmd, rest] = split_args(args);
+    match cmd {
+        "add" => match parse_rule(rest) {
+            Ok((matcher, scope, action)) => {
+                let id = rules.add(matcher, scope, action);
+                format!("已添加规则 #{id}")
+            }
+            Err(err) => format!("{err}\n{RULE_USAGE}"),
+        },
+        "remove" => match rest
+            .trim_start_matches('#')
+            .parse()
+            .ok()
+            .and_then(|id| rules.remove(id))
+        {
+            Some(rule) => format!("已删除规则 {rule}"),
+            None => "没有这条规则".to_string(),
+        },
+        "list" if rules.rules.is_empty() => "本群还没有审查规则".to_string(),
+        "list" => rules
+            .rules
+            .iter()
+            .map(|rule| rule.to_string())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        "test" if !rest.is_empty() => match rules.check(&Target::from_text(rest)) {
+            Some(rule) => format!("命中规则 {rule}"),
+            None => "没有命中任何规则".to_string(),
+        },
+        _ => RULE_USAGE.to_string(),
+    }
+}
+
+fn on_filter(filter: &mut SpamFilter, cmd: &str, args: &str) -> String {
+    match (cmd, args) {
+        ("classifier", "") => match filter.action {
+            Some(action) => format!(
+                "分类器已打开，命中后{}，阈值 {}",
+                action.describe(),
+                filter.threshold
+            ),
+            None => "分类器已关闭".to_string(),
+        },
+        ("classifier", "off") => {
+            filter.action = None;
+            "分类器已关闭".to_string()
+        }
+        ("classifier", action) => match Action::parse(action) {
+            Some(action) => {
+                filter.action = Some(action);
+                format!("分类器已打开，命中后{}", action.describe())
+            }
+            None => RULE_USAGE.to_string(),
+        },
+        ("threshold", threshold) => match threshold.parse::<f64>() {
+            Ok(threshold) if (0.5..=1.0).contains(&threshold) => {
+                filter.threshold = threshold;
+                format!("分类器的阈值已设置为 {threshold}")
+            }
+            _ => "阈值需要在 0.5 到 1 之间".to_string(),
+        },
+        ("embedding", "on") => {
+            filter.embedding = true;
+            "已打开向量相似度检查，需要连接向量数据库".to_string()
+        }
+        ("embedding", "off") => {
+            filter.embedding = false;
+            "已关闭向量相似度检查".to_string()
+        }
+        _ => RULE_USAGE.to_string(),
+    }
+}
+
+fn on_bestapo(ctx: &mut Context, _: &Message) -> Consumption {
+    let [cmd, rest] = split_args(ctx.cmd?.content);
+    let (cmd, rest) = (cmd.to_string(), rest.to_string());
+    let ctx = ctx.task();
+    async move {
+        ctx.with_db("bestapo", async {
+            let text = match cmd.as_str() {
+                "rule" => {
+                    let mut rules = ctx
+                        .app
+                        .db
+                        .of::<BestapoRules>()
+                        .chat(ctx.chat_id)
+                        .get_or_insert(Default::default)
+                        .await?;
+                    on_rule(&mut rules, &rest)
+                }
+                "classifier" | "threshold" | "embedding" => {
+                    let mut filter = ctx
+                        .app
+                        .db
+                        .of::<SpamFilter>()
+                        .chat(ctx.chat_id)
+                        .get_or_insert(Default::default)
+                        .await?;
+                    on_filter(&mut filter, &cmd, &rest)
+                }
+                "stats" => {
+                    let samples = match ctx
+                        .app
+                        .db
+                        .of::<SpamCorpus>()
+                        .chat(ctx.chat_id)
+                        .get()
+                        .await?
+                    {
+                        Some(corpus) => corpus.samples(),
+                        None => (0, 0),
+                    };
+                    match ctx.app.db.of::<SpamStats>().chat(ctx.chat_id).get().await? {
+                        Some(stats) => stats.report(samples),
+                        None => SpamStats::default().report(samples),
+                    }
+                }
+                _ => RULE_USAGE.to_string(),
+            };
+            ctx.reply(text).send().warn_on_error("bestapo").await;
+            Ok(())
+        })
+        .await
+    }
+    .into()
+}
+
+pub static COMMAND: Module = Module {
+    kind: ModuleKind::Command(ModuleDescription {
+        name: "bestapo",
+        description: "管理<b>北世太保</b>的审查规则和分类器",
+        description_detailed: Some(concat!(
+            "/bestapo rule add [all|channel] [warn|delete|ban] &lt;类型&gt; &lt;内容&gt; 添加规则\n",
+            "类型可以是 keyword（关键词）、regex（正则）、deny_domain（禁止的域名）、allow_domain（只允许的域名）\n",
+            "默认只检查带链接的频道回复，命中后删除消息\n",
+            "/bestapo rule remove &lt;编号&gt; 删除规则\n",
+            "/bestapo rule list 列出规则\n",
+            "/bestapo rule test &lt;文本&gt; 测试文本会命中哪条规则\n",
+            "没有命中规则的消息会交给分类器，它从管理员的 /spam 和 /ham 中学习\n",
+            "/bestapo classifier [warn|delete|ban|off] 设置分类器命中后的处理，默认警告\n",
+            "/bestapo threshold &lt;0.5~1&gt; 设置分类器的阈值，默认 0.95\n",
+            "/bestapo embedding [on|off] 分数接近阈值时，再和已知斯帕姆比较向量相似度\n",
+            "/bestapo stats 查看分类器的精确率\n",
+            "规则只在 /toggle_bestapo 打开审查后生效"
+        )),
+        permission: Permission::ChatAdmin,
+    }),
+    task: on_bestapo,
+};
@@ -1,60 +1,237 @@
+use super::classifier::{Flag, MAX_SPAM_ANGLE, SpamCorpus, SpamFilter, SpamStats};
+use super::rules::{Action, BestapoRules, Target};
 use super::toggle::BestapoCensor;
-use super::utils::{is_contains_url, is_zero_width_char};
-use crate::linquebot::{msg_context::Context, types::Consumption, Module};
-use crate::utils::telegram::prelude::{MessageExtension, WarnOnError};
+use crate::linquebot::db::WarnDbError;
+use crate::linquebot::msg_context::TaskContext;
+use crate::linquebot::vector_db::VectorQuery;
+use crate::linquebot::{Module, msg_context::Context, types::Consumption};
+use crate::mods::moderation::{self, Actor, LogEntry, ModAction};
+use crate::mods::search::embedding::text_embedding;
+use crate::utils::telegram::prelude::WarnOnError;
 use log::{debug, warn};
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use teloxide_core::{
     prelude::{Request, Requester},
-    types::Message,
+    types::{ChatId, Message, MessageId, UserId},
 };
 use tokio::time::sleep;
 
-const SENSITIVE_WORDS: &[&str] = &["trump", "nft", "opensea"];
+/// 消息的发送者，封禁和记录日志时使用
+#[derive(Clone)]
+struct Sender {
+    user: Option<UserId>,
+    chat: Option<ChatId>,
+    name: String,
+}
 
-fn on_message(ctx: &mut Context, msg: &Message) -> Consumption {
-    let ctx = ctx.task();
-    if !msg.is_reply_to_channel() || !is_contains_url(msg) {
-        return Consumption::just_next();
+impl Sender {
+    fn of(msg: &Message) -> Self {
+        let name = match (&msg.sender_chat, &msg.from) {
+            (Some(chat), _) => chat.title().unwrap_or_default().to_string(),
+            (None, Some(user)) => user.full_name(),
+            (None, None) => String::new(),
+        };
+        Self {
+            user: msg.from.as_ref().map(|user| user.id),
+            chat: msg.sender_chat.as_ref().map(|chat| chat.id),
+            name,
+        }
     }
-    let mut is_spam = false;
-    if let Some(text) = msg.text() {
-        let text = text.to_lowercase().replace(is_zero_width_char, "");
-        for word in SENSITIVE_WORDS.iter() {
-            if text.contains(word) {
-                warn!("Sensitive word detected: {}", word);
-                debug!("Message: {:#?}", msg);
-                is_spam = true;
-                break;
-            }
+}
+
+/// 按 action 处理消息并写入管理日志，返回琳酱发出的警告
+async fn punish(
+    ctx: &TaskContext,
+    action: Action,
+    sender: Sender,
+    warning: String,
+    reason: String,
+) -> Option<MessageId> {
+    let warning = match ctx.reply(warning).send().await {
+        Ok(msg) => Some(msg.id),
+        Err(err) => {
+            warn!(target: "bestapo", "Error: {err}");
+            None
         }
+    };
+    if action == Action::Warn {
+        return warning;
     }
-    if !is_spam {
-        return Consumption::just_next();
+    sleep(Duration::from_secs(3)).await;
+    ctx.app
+        .bot
+        .delete_message(ctx.chat_id, ctx.message_id)
+        .send()
+        .warn_on_error("bestapo")
+        .await;
+    let log = |action| LogEntry {
+        at: SystemTime::now(),
+        actor: Actor::Bot,
+        target_id: sender.user.filter(|_| sender.chat.is_none()),
+        target_name: sender.name.clone(),
+        action,
+        reason: reason.clone(),
+    };
+    moderation::record(ctx.app, ctx.chat_id, log(ModAction::Delete)).await;
+    if action != Action::DeleteAndBan {
+        return warning;
+    }
+    moderation::record(ctx.app, ctx.chat_id, log(ModAction::Ban)).await;
+    if let Some(sender_chat) = sender.chat {
+        ctx.app
+            .bot
+            .ban_chat_sender_chat(ctx.chat_id, sender_chat)
+            .send()
+            .warn_on_error("bestapo-ban")
+            .await;
+    } else if let Some(sender) = sender.user {
+        ctx.app
+            .bot
+            .ban_chat_member(ctx.chat_id, sender)
+            .send()
+            .warn_on_error("bestapo-ban")
+            .await;
+    }
+    warning
+}
+
+/// 和已知斯帕姆的向量比较
+async fn similar_to_spam(ctx: &TaskContext, text: &str) -> bool {
+    let Ok(vector_db) = &ctx.app.vector_db else {
+        return false;
+    };
+    let embedding = match text_embedding(text).await {
+        Ok(embedding) => embedding,
+        Err(err) => {
+            warn!("Text Embedding Error with:\n{err}");
+            return false;
+        }
+    };
+    match vector_db
+        .get(VectorQuery {
+            chat: ctx.chat_id.to_string(),
+            user: Some("spam".to_string()),
+            vector: embedding,
+        })
+        .await
+    {
+        Ok(results) => results
+            .first()
+            .is_some_and(|res| res.distance < MAX_SPAM_ANGLE),
+        Err(err) => {
+            warn!("Query Failed with:\n{err}");
+            false
+        }
+    }
+}
+
+/// 用分类器判断，返回分数
+async fn classify(ctx: &TaskContext, text: &str) -> Option<(f64, Action)> {
+    let filter = ctx
+        .app
+        .db
+        .of::<SpamFilter>()
+        .chat(ctx.chat_id)
+        .get()
+        .await
+        .ok_or_warn("bestapo")?;
+    let (action, threshold, embedding) = match filter {
+        Some(filter) => (filter.action?, filter.threshold, filter.embedding),
+        None => {
+            let filter = SpamFilter::default();
+            (filter.action?, filter.threshold, filter.embedding)
+        }
+    };
+    let score = ctx
+        .app
+        .db
+        .of::<SpamCorpus>()
+        .chat(ctx.chat_id)
+        .get()
+        .await
+        .ok_or_warn("bestapo")??
+        .score(text)?;
+    if score >= threshold || (embedding && score >= 0.5 && similar_to_spam(ctx, text).await) {
+        Some((score, action))
+    } else {
+        None
     }
+}
+
+fn on_message(ctx: &mut Context, msg: &Message) -> Consumption {
+    let target = Target::from_message(msg)?;
+    let sender = Sender::of(msg);
+    let ctx = ctx.task();
     Consumption::next_with(async move {
         let enabled = ctx
             .app
             .db
             .of::<BestapoCensor>()
             .chat(ctx.chat_id)
-            .get_or_insert(BestapoCensor::default)
+            .get()
             .await
-            .censor_enabled;
+            .ok_or_warn("bestapo")
+            .flatten()
+            .is_some_and(|censor| censor.censor_enabled);
         if !enabled {
             return;
         }
-        ctx.reply_markdown("检测到斯帕姆。把它上市！")
-            .send()
-            .warn_on_error("bestapo")
-            .await;
-        sleep(Duration::from_secs(3)).await;
-        ctx.app
-            .bot
-            .delete_message(ctx.chat_id, ctx.message_id)
-            .send()
-            .warn_on_error("bestapo")
+        let Some(rules) = ctx
+            .app
+            .db
+            .of::<BestapoRules>()
+            .chat(ctx.chat_id)
+            .get_or_insert(Default::default)
+            .await
+            .ok_or_warn("bestapo")
+        else {
+            return;
+        };
+        let rule = rules.check(&target).cloned();
+        drop(rules);
+        if let Some(rule) = rule {
+            warn!("Spam rule matched: {rule}");
+            debug!("Message: {:#?}", target.text);
+            punish(
+                &ctx,
+                rule.action,
+                sender,
+                "检测到斯帕姆。把它上市！".to_string(),
+                format!("北世太保规则 #{}", rule.id),
+            )
             .await;
+            return;
+        }
+        let Some((score, action)) = classify(&ctx, &target.text).await else {
+            return;
+        };
+        warn!("Spam classified with score {score:.3}");
+        debug!("Message: {:#?}", target.text);
+        let warning = format!(
+            "检测到疑似斯帕姆（{:.0}%）。管理员可以回复 /ham 纠正",
+            score * 100.0
+        );
+        let reason = format!("北世太保分类器 {:.0}%", score * 100.0);
+        let warning_id = punish(&ctx, action, sender, warning, reason).await;
+        let Some(mut stats) = ctx
+            .app
+            .db
+            .of::<SpamStats>()
+            .chat(ctx.chat_id)
+            .get_or_insert(Default::default)
+            .await
+            .ok_or_warn("bestapo")
+        else {
+            return;
+        };
+        stats.flag(Flag {
+            message_id: ctx.message_id,
+            warning_id,
+            text: target.text,
+            score,
+            at: SystemTime::now(),
+            false_positive: false,
+        });
     })
 }
 
@@ -1,8 +1,24 @@
 //! 北世太保，有持久化
 
+mod classifier;
+mod command;
 mod message_handler;
+mod report;
+mod rules;
 mod toggle;
 mod utils;
 
+pub use command::COMMAND;
 pub use message_handler::MESSAGE_HANDLER;
+pub use report::{HAM, SPAM};
 pub use toggle::TOGGLE;
+
+use crate::linquebot::db::DataType;
+
+pub static DATA_TYPES: &[DataType] = &[
+    DataType::of::<toggle::BestapoCensor>(),
+    DataType::of::<rules::BestapoRules>(),
+    DataType::of::<classifier::SpamCorpus>(),
+    DataType::of::<classifier::SpamFilter>(),
+    DataType::of::<classifier::SpamStats>(),
+];
@@ -0,0 +1,173 @@
+use log::warn;
+use teloxide_core::{
+    prelude::{Request, Requester},
+    types::Message,
+};
+
+use super::classifier::{Label, SpamCorpus, SpamStats};
+use super::rules::Target;
+use crate::{
+    linquebot::{
+        Module, ModuleDescription, ModuleKind, Permission, msg_context::Context,
+        types::Consumption, vector_db::VectorData,
+    },
+    mods::search::embedding::text_embedding,
+    utils::telegram::prelude::WarnOnError,
+};
+
+fn on_report(ctx: &mut Context, msg: &Message, label: Label) -> Consumption {
+    let Some(reported) = msg.reply_to_message() else {
+        return ctx
+            .task()
+            .reply("请回复一条消息")
+            .send()
+            .warn_on_error("bestapo-report")
+            .into();
+    };
+    let reported_id = reported.id;
+    let reported_text = Target::from_message(reported).map(|target| target.text);
+    let ctx = ctx.task();
+    async move {
+        let mut stats = match ctx
+            .app
+            .db
+            .of::<SpamStats>()
+            .chat(ctx.chat_id)
+            .get_or_insert(Default::default)
+            .await
+        {
+            Ok(stats) => stats,
+            Err(err) => return ctx.reply_db_error("bestapo-report", err).await,
+        };
+        // 回复的是被分类器处理过的消息，或者琳酱的警告
+        let flag = stats.find(reported_id).map(|flag| {
+            let was_false_positive = flag.false_positive;
+            flag.false_positive = label == Label::Ham;
+            (flag.message_id, flag.text.clone(), was_false_positive)
+        });
+        let flagged = flag.is_some();
+        let (message_id, text) = match flag {
+            Some((message_id, text, was_false_positive)) => {
+                match (was_false_positive, label) {
+                    (false, Label::Ham) => stats.false_positives += 1,
+                    (true, Label::Spam) => stats.false_positives -= 1,
+                    _ => {}
+                }
+                (message_id, text)
+            }
+            None => {
+                let Some(text) = reported_text else {
+                    drop(stats);
+                    ctx.reply("这条消息没有文字")
+                        .send()
+                        .warn_on_error("bestapo-report")
+                        .await;
+                    return;
+                };
+                (reported_id, text)
+            }
+        };
+
+        let (old, samples) = {
+            let mut corpus = match ctx
+                .app
+                .db
+                .of::<SpamCorpus>()
+                .chat(ctx.chat_id)
+                .get_or_insert(Default::default)
+                .await
+            {
+                Ok(corpus) => corpus,
+                Err(err) => return ctx.reply_db_error("bestapo-report", err).await,
+            };
+            (corpus.learn(message_id, &text, label), corpus.samples())
+        };
+        // 分类器没有发现的斯帕姆，重复标记不重复计数，改标为正常消息时撤销
+        if !flagged && old != Some(label) {
+            match label {
+                Label::Spam => stats.missed += 1,
+                Label::Ham if old == Some(Label::Spam) => {
+                    stats.missed = stats.missed.saturating_sub(1)
+                }
+                Label::Ham => {}
+            }
+        }
+        drop(stats);
+
+        if label == Label::Spam {
+            ctx.app
+                .bot
+                .delete_message(ctx.chat_id, message_id)
+                .send()
+                .warn_on_error("bestapo-report")
+                .await;
+        }
+        let name = match label {
+            Label::Spam => "斯帕姆",
+            Label::Ham => "正常消息",
+        };
+        ctx.reply(format!(
+            "已标记为{name}，语料中共有 {} 条斯帕姆，{} 条正常消息",
+            samples.0, samples.1
+        ))
+        .send()
+        .warn_on_error("bestapo-report")
+        .await;
+
+        if label == Label::Spam
+            && let Ok(vector_db) = &ctx.app.vector_db
+        {
+            match text_embedding(text).await {
+                Ok(vector) => {
+                    let res = vector_db
+                        .upsert(VectorData {
+                            index: message_id.to_string(),
+                            user: Some("spam".to_string()),
+                            chat: ctx.chat_id.to_string(),
+                            vector,
+                        })
+                        .await;
+                    if let Err(err) = res {
+                        warn!("Failed to upsert spam vector: {err}");
+                    }
+                }
+                Err(err) => warn!("Text Embedding Error with:\n{err}"),
+            }
+        }
+    }
+    .into()
+}
+
+fn on_spam(ctx: &mut Context, msg: &Message) -> Consumption {
+    on_report(ctx, msg, Label::Spam)
+}
+
+fn on_ham(ctx: &mut Context, msg: &Message) -> Consumption {
+    on_report(ctx, msg, Label::Ham)
+}
+
+pub static SPAM: Module = Module {
+    kind: ModuleKind::Command(ModuleDescription {
+        name: "spam",
+        description: "把回复的消息标记为斯帕姆",
+        description_detailed: Some(concat!(
+            "回复一条消息使用，把它加入本群的斯帕姆语料并删除。\n",
+            "<b>北世太保</b>会根据语料判断之后的消息，参见 /bestapo"
+        )),
+        permission: Permission::ChatAdmin,
+    }),
+    task: on_spam,
+};
+
+pub static HAM: Module = Module {
+    kind: ModuleKind::Command(ModuleDescription {
+        name: "ham",
+        description: "把回复的消息标记为正常消息",
+        description_detailed: Some(concat!(
+            "回复一条消息（或者琳酱对它的警告）使用，把它加入本群的正常消息语料。\n",
+            "用来纠正<b>北世太保</b>的误判"
+        )),
+        permission: Permission::ChatAdmin,
+    }),
+    task: on_ham,
+};
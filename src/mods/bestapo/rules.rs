@@ -0,0 +1,335 @@
+//! 每个群的审查规则
+
+use std::fmt::Display;
+use std::sync::LazyLock;
+
+use quick_cache::sync::Cache;
+use regex::Regex;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use teloxide_core::types::{Message, MessageEntityKind};
+
+use super::utils::{is_contains_url, is_zero_width_char};
+use crate::linquebot::db::DbData;
+use crate::utils::telegram::prelude::MessageExtension;
+
+/// 规则怎样匹配消息
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Matcher {
+    /// 包含任意一个关键词（不区分大小写）
+    Keywords(Vec<String>),
+    /// 匹配正则表达式
+    Regex(String),
+    /// 包含这些域名（及其子域名）的链接
+    DenyDomains(Vec<String>),
+    /// 包含不在这些域名（及其子域名）中的链接
+    AllowDomains(Vec<String>),
+}
+
+/// 规则检查哪些消息
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Scope {
+    /// 只检查带链接的频道回复
+    ChannelReplies,
+    /// 检查所有消息
+    All,
+}
+
+/// 命中规则后怎么处理
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Action {
+    Warn,
+    Delete,
+    DeleteAndBan,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rule {
+    pub id: u32,
+    pub matcher: Matcher,
+    pub scope: Scope,
+    pub action: Action,
+}
+
+/// 一个群的所有规则
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BestapoRules {
+    pub next_id: u32,
+    pub rules: Vec<Rule>,
+}
+
+impl DbData for BestapoRules {
+    const KEY: &str = "bestapo::BestapoRules";
+}
+
+impl Default for BestapoRules {
+    /// 原来写死的规则
+    fn default() -> Self {
+        Self {
+            next_id: 2,
+            rules: vec![Rule {
+                id: 1,
+                matcher: Matcher::Keywords(vec!["trump".into(), "nft".into(), "opensea".into()]),
+                scope: Scope::ChannelReplies,
+                action: Action::Delete,
+            }],
+        }
+    }
+}
+
+impl BestapoRules {
+    pub fn add(&mut self, matcher: Matcher, scope: Scope, action: Action) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.rules.push(Rule {
+            id,
+            matcher,
+            scope,
+            action,
+        });
+        id
+    }
+
+    pub fn remove(&mut self, id: u32) -> Option<Rule> {
+        let idx = self.rules.iter().position(|rule| rule.id == id)?;
+        Some(self.rules.remove(idx))
+    }
+
+    /// 第一条命中的规则
+    pub fn check(&self, target: &Target) -> Option<&Rule> {
+        self.rules.iter().find(|rule| rule.matches(target))
+    }
+}
+
+/// 被检查的内容
+#[derive(Debug, Default)]
+pub struct Target {
+    /// 去掉零宽字符后的文本
+    pub text: String,
+    /// 链接的域名，小写
+    pub domains: Vec<String>,
+    /// 是否是带链接的频道回复
+    pub channel_reply: bool,
+}
+
+fn domain_of(url: &str) -> Option<String> {
+    let url = if url.contains("://") {
+        Url::parse(url)
+    } else {
+        Url::parse(&format!("http://{url}"))
+    };
+    Some(url.ok()?.host_str()?.to_lowercase())
+}
+
+impl Target {
+    pub fn from_message(msg: &Message) -> Option<Self> {
+        let text = msg.text().or(msg.caption())?;
+        let mut domains = Vec::new();
+        let entities = msg.parse_entities().or(msg.parse_caption_entities());
+        for entity in entities.iter().flatten() {
+            let url = match entity.kind() {
+                MessageEntityKind::Url => entity.text(),
+                MessageEntityKind::TextLink { url } => url.as_str(),
+                _ => continue,
+            };
+            domains.extend(domain_of(url));
+        }
+        if let Some(url) = msg.link_preview_options().and_then(|opt| opt.url.as_ref()) {
+            domains.extend(domain_of(url));
+        }
+        Some(Self {
+            text: text.replace(is_zero_width_char, ""),
+            domains,
+            channel_reply: msg.is_reply_to_channel() && is_contains_url(msg),
+        })
+    }
+
+    /// 用于 `/bestapo rule test`，把文本当作所有消息检查
+    pub fn from_text(text: &str) -> Self {
+        static URL: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"https?://[^\s]+").unwrap());
+        Self {
+            text: text.replace(is_zero_width_char, ""),
+            domains: URL
+                .find_iter(text)
+                .filter_map(|url| domain_of(url.as_str()))
+                .collect(),
+            channel_reply: true,
+        }
+    }
+}
+
+fn in_domains(domain: &str, list: &[String]) -> bool {
+    list.iter().any(|item| {
+        domain == item
+            || domain
+                .strip_suffix(item.as_str())
+                .is_some_and(|prefix| prefix.ends_with('.'))
+    })
+}
+
+/// 编译过的正则表达式，避免每条消息都重新编译
+fn compiled(pattern: &str) -> Option<Regex> {
+    static REGEX_CACHE: LazyLock<Cache<String, Option<Regex>>> = LazyLock::new(|| Cache::new(1000));
+    REGEX_CACHE
+        .get_or_insert_with(pattern, || Ok::<_, ()>(Regex::new(pattern).ok()))
+        .ok()
+        .flatten()
+}
+
+impl Matcher {
+    pub fn matches(&self, target: &Target) -> bool {
+        match self {
+            Matcher::Keywords(words) => {
+                let text = target.text.to_lowercase();
+                words.iter().any(|word| text.contains(word.as_str()))
+            }
+            Matcher::Regex(pattern) => {
+                compiled(pattern).is_some_and(|regex| regex.is_match(&target.text))
+            }
+            Matcher::DenyDomains(list) => {
+                target.domains.iter().any(|domain| in_domains(domain, list))
+            }
+            Matcher::AllowDomains(list) => target
+                .domains
+                .iter()
+                .any(|domain| !in_domains(domain, list)),
+        }
+    }
+
+    /// 从命令参数解析，例如 `keyword nft 空投`
+    pub fn parse(kind: &str, args: &str) -> Result<Self, String> {
+        let list = || -> Result<Vec<String>, String> {
+            let list = args
+                .split_whitespace()
+                .map(|item| item.to_lowercase())
+                .collect::<Vec<_>>();
+            if list.is_empty() {
+                Err("缺少规则内容".to_string())
+            } else {
+                Ok(list)
+            }
+        };
+        match kind {
+            "keyword" => Ok(Matcher::Keywords(list()?)),
+            "regex" => {
+                let pattern = args.trim();
+                if pattern.is_empty() {
+                    return Err("缺少规则内容".to_string());
+                }
+                Regex::new(pattern).map_err(|err| format!("正则表达式有误：{err}"))?;
+                Ok(Matcher::Regex(pattern.to_string()))
+            }
+            "deny_domain" => Ok(Matcher::DenyDomains(list()?)),
+            "allow_domain" => Ok(Matcher::AllowDomains(list()?)),
+            _ => Err(format!("未知的规则类型：{kind}")),
+        }
+    }
+}
+
+impl Action {
+    pub fn parse(src: &str) -> Option<Self> {
+        match src {
+            "warn" => Some(Action::Warn),
+            "delete" => Some(Action::Delete),
+            "ban" => Some(Action::DeleteAndBan),
+            _ => None,
+        }
+    }
+
+    pub fn describe(self) -> &'static str {
+        match self {
+            Action::Warn => "警告",
+            Action::Delete => "删除",
+            Action::DeleteAndBan => "删除并封禁",
+        }
+    }
+}
+
+impl Rule {
+    pub fn matches(&self, target: &Target) -> bool {
+        (self.scope == Scope::All || target.channel_reply) && self.matcher.matches(target)
+    }
+}
+
+impl Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let scope = match self.scope {
+            Scope::ChannelReplies => "频道回复",
+            Scope::All => "所有消息",
+        };
+        let action = self.action.describe();
+        let matcher = match &self.matcher {
+            Matcher::Keywords(words) => format!("关键词 {}", words.join(" ")),
+            Matcher::Regex(pattern) => format!("正则 {pattern}"),
+            Matcher::DenyDomains(list) => format!("禁止域名 {}", list.join(" ")),
+            Matcher::AllowDomains(list) => format!("只允许域名 {}", list.join(" ")),
+        };
+        write!(f, "#{} [{scope}] [{action}] {matcher}", self.id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules() -> BestapoRules {
+        let mut rules = BestapoRules::default();
+        rules.add(
+            Matcher::parse("regex", r"(?i)free\s+airdrop").unwrap(),
+            Scope::All,
+            Action::Warn,
+        );
+        rules.add(
+            Matcher::parse("deny_domain", "Scam.example").unwrap(),
+            Scope::All,
+            Action::DeleteAndBan,
+        );
+        rules
+    }
+
+    fn id_of(rules: &BestapoRules, target: &Target) -> Option<u32> {
+        rules.check(target).map(|rule| rule.id)
+    }
+
+    #[test]
+    fn check_rules() {
+        let rules = rules();
+        assert_eq!(
+            id_of(
+                &rules,
+                &Target::from_text("get free \u{2062}N\u{2062}F\u{2062}T")
+            ),
+            Some(1)
+        );
+        assert_eq!(id_of(&rules, &Target::from_text("FREE  Airdrop!")), Some(2));
+        assert_eq!(
+            id_of(&rules, &Target::from_text("see https://a.scam.example/x")),
+            Some(3)
+        );
+        assert_eq!(
+            id_of(&rules, &Target::from_text("see https://notscam.example/x")),
+            None
+        );
+
+        let target = Target {
+            channel_reply: false,
+            ..Target::from_text("nft")
+        };
+        assert_eq!(id_of(&rules, &target), None);
+    }
+
+    #[test]
+    fn allow_domains() {
+        let matcher = Matcher::parse("allow_domain", "github.com t.me").unwrap();
+        assert!(!matcher.matches(&Target::from_text("https://gist.github.com/a")));
+        assert!(!matcher.matches(&Target::from_text("no links")));
+        assert!(matcher.matches(&Target::from_text("https://t.me https://evil.com")));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(Matcher::parse("regex", "(").is_err());
+        assert!(Matcher::parse("keyword", " ").is_err());
+        assert!(Matcher::parse("emoji", "a").is_err());
+    }
+}
@@ -1,8 +1,11 @@
 use serde::{Deserialize, Serialize};
 use teloxide_core::{prelude::Request, types::Message};
 
+use crate::linquebot::db::DbData;
 use crate::{
-    linquebot::{msg_context::Context, types::Consumption, Module, ModuleDescription, ModuleKind},
+    linquebot::{
+        Module, ModuleDescription, ModuleKind, Permission, msg_context::Context, types::Consumption,
+    },
     utils::telegram::prelude::WarnOnError,
 };
 
@@ -11,16 +14,24 @@ pub struct BestapoCensor {
     pub censor_enabled: bool,
 }
 
+impl DbData for BestapoCensor {
+    const KEY: &str = "bestapo::BestapoCensor";
+}
+
 fn on_toggle(ctx: &mut Context, _: &Message) -> Consumption {
     let ctx = ctx.task();
     async move {
-        let mut stat = ctx
+        let mut stat = match ctx
             .app
             .db
             .of::<BestapoCensor>()
             .chat(ctx.chat_id)
             .get_or_insert(BestapoCensor::default)
-            .await;
+            .await
+        {
+            Ok(stat) => stat,
+            Err(err) => return ctx.reply_db_error("toggle_bestapo", err).await,
+        };
 
         stat.censor_enabled = !stat.censor_enabled;
 
@@ -45,6 +56,7 @@ pub static TOGGLE: Module = Module {
             "打开/关闭<b>北世太保</b>模块的频道回复审查功能。\n",
             "北世太保会帮助你管理群组：审查伪人的信息，并把他们送去见主席。"
         )),
+        permission: Permission::ChatAdmin,
     }),
     task: on_toggle,
 };
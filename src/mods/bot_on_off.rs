@@ -1,72 +1,234 @@
 //! bot的开关
-//! 默认开启，无持久化
+//! ```text
+//! /bot_on
+//! /bot_off [时长]
+//! ```
+//! 默认开启。开关状态按聊天持久化在数据库中，内存中的缓存供 [STOP_WHEN_BOT_OFF] 快速判断。
+//! 带时长关机时（例如 `/bot_off 2h`），由 [crate::linquebot::scheduler] 安排自动开机，到时间后通知。
 
-use log::error;
-use log::warn;
+use futures::StreamExt;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::pin::pin;
 use std::sync::LazyLock;
 use std::sync::RwLock;
+use std::time::{Duration, SystemTime};
 use teloxide_core::prelude::*;
 use teloxide_core::types::*;
 
+use crate::App;
 use crate::Consumption;
+use crate::MicroTask;
 use crate::Module;
 use crate::ModuleDescription;
 use crate::ModuleKind;
+use crate::Permission;
+use crate::linquebot::db::{DataType, DbData, DbError, Scan};
+use crate::linquebot::scheduler::{Job, NewJob};
 use crate::msg_context::Context;
+use crate::utils::telegram::prelude::WarnOnError;
+use crate::utils::time::parse_duration;
 
-static BOT_ON: LazyLock<RwLock<HashMap<ChatId, bool>>> = LazyLock::new(Default::default);
+const MODULE_NAME: &str = "bot_on_off";
 
-fn on_bot_on_message(ctx: &mut Context, _: &Message) -> Consumption {
-    let Ok(mut record) = BOT_ON.write() else {
-        error!("Failed to get bot on status!");
-        return Consumption::just_stop();
-    };
+/// 一个聊天的关机状态，开机时没有这一行
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct BotOff {
+    /// 自动开机的时间，`None` 表示一直关机
+    until: Option<SystemTime>,
+    /// 自动开机的定时任务
+    job_id: Option<u64>,
+}
 
-    let ctx = ctx.task();
-    if record.remove(&ctx.chat_id).is_some() {
-        async move {
-            if let Err(err) = ctx.reply("琳酱已开机").send().await {
-                warn!("Failed to send reply: {}", err);
+impl DbData for BotOff {
+    const KEY: &str = "bot_on_off::BotOff";
+}
+
+impl BotOff {
+    fn is_active(&self) -> bool {
+        self.until.is_none_or(|until| until > SystemTime::now())
+    }
+}
+
+pub static DATA_TYPES: &[DataType] = &[DataType::of::<BotOff>()];
+
+static BOT_OFF: LazyLock<RwLock<HashMap<ChatId, BotOff>>> = LazyLock::new(Default::default);
+
+/// 修改一个聊天的关机状态，`None` 为开机，返回之前的状态。之前安排的自动开机会被取消
+async fn update_state(
+    app: &'static App,
+    chat_id: ChatId,
+    state: Option<BotOff>,
+) -> Result<Option<BotOff>, DbError> {
+    let prev = app
+        .db
+        .of::<BotOff>()
+        .chat(chat_id)
+        .get()
+        .await?
+        .map(|prev| *prev);
+    match state {
+        Some(state) => app.db.of::<BotOff>().chat(chat_id).insert(state).await?,
+        None if prev.is_some() => app.db.of::<BotOff>().chat(chat_id).remove().await?,
+        None => {}
+    }
+    if let Some(job_id) = prev.and_then(|prev| prev.job_id)
+        && let Err(err) = app.scheduler.cancel(app, chat_id, job_id).await
+    {
+        warn!("Failed to cancel wake up job {job_id} in {chat_id}: {err}");
+    }
+    match BOT_OFF.write() {
+        Ok(mut record) => match state {
+            Some(state) => {
+                record.insert(chat_id, state);
             }
-        }
-        .into()
-    } else {
-        async move {
-            if let Err(err) = ctx.reply("琳酱处于开机状态").send().await {
-                warn!("Failed to send reply: {}", err);
+            None => {
+                record.remove(&chat_id);
             }
+        },
+        Err(_) => error!("Failed to get bot on status!"),
+    }
+    Ok(prev)
+}
+
+/// 到时间后自动开机，期间状态被修改过的话什么也不做
+fn on_wake_up(app: &'static App, job: &Job) -> Consumption {
+    let (chat_id, job_id) = (job.chat_id, job.id);
+    async move {
+        let current = match app.db.of::<BotOff>().chat(chat_id).get().await {
+            Ok(current) => current.map(|current| *current),
+            Err(err) => {
+                error!("Failed to wake up in {chat_id}: {err}");
+                return;
+            }
+        };
+        if current.and_then(|current| current.job_id) != Some(job_id) {
+            return;
+        }
+        if let Err(err) = update_state(app, chat_id, None).await {
+            error!("Failed to wake up in {chat_id}: {err}");
+            return;
         }
-        .into()
+        app.bot
+            .send_message(chat_id, "关机时间到，琳酱已自动开机")
+            .send()
+            .warn_on_error("bot-on-off")
+            .await;
     }
+    .into()
 }
-fn on_bot_off_message(ctx: &mut Context, _: &Message) -> Consumption {
-    let Ok(mut record) = BOT_ON.write() else {
-        error!("Failed to get bot on status!");
-        return Consumption::just_stop();
-    };
 
-    let ctx = ctx.task();
-    if let Some(false) = record.insert(ctx.chat_id, false) {
-        async move {
-            if let Err(err) = ctx.reply("琳酱处于关机状态").send().await {
-                warn!("Failed to send reply: {}", err);
+async fn schedule_wake_up(
+    app: &'static App,
+    chat_id: ChatId,
+    until: SystemTime,
+) -> Result<u64, DbError> {
+    app.scheduler
+        .schedule(
+            app,
+            NewJob {
+                module: MODULE_NAME,
+                chat_id,
+                thread_id: None,
+                message_id: None,
+                at: until,
+                payload: &(),
+            },
+        )
+        .await
+}
+
+/// 从数据库恢复关机状态，需要在 [crate::linquebot::scheduler::Scheduler::restore] 之后调用
+pub async fn restore(app: &'static App) -> Result<(), DbError> {
+    let mut chats = HashMap::new();
+    let mut rows = pin!(app.db.scan::<BotOff>(Scan::All));
+    while let Some(row) = rows.next().await {
+        match row {
+            Ok((id, state)) => {
+                if let Some(chat_id) = id.chat {
+                    chats.insert(chat_id, state);
+                }
             }
+            Err(err) => error!("Failed to restore bot off state: {err}"),
         }
-        .into()
+    }
+    match BOT_OFF.write() {
+        Ok(mut record) => *record = chats,
+        Err(_) => error!("Failed to get bot on status!"),
+    }
+    Ok(())
+}
+
+fn on_bot_on_message(ctx: &mut Context, _: &Message) -> Consumption {
+    let ctx = ctx.task();
+    async move {
+        let text = match update_state(ctx.app, ctx.chat_id, None).await {
+            Ok(Some(_)) => "琳酱已开机",
+            Ok(None) => "琳酱处于开机状态",
+            Err(err) => return ctx.reply_db_error("bot-on-off", err).await,
+        };
+        ctx.reply(text).send().warn_on_error("bot-on-off").await;
+    }
+    .into()
+}
+
+fn on_bot_off_message(ctx: &mut Context, _: &Message) -> Consumption {
+    let args = ctx.cmd?.content.trim();
+    let ctx = ctx.task();
+    let duration = if args.is_empty() {
+        None
     } else {
-        async move {
-            if let Err(err) = ctx.reply("琳酱已关机").send().await {
-                warn!("Failed to send reply: {}", err);
+        match parse_duration(args) {
+            Some(duration) if duration > Duration::ZERO => Some(duration),
+            _ => {
+                return ctx
+                    .reply("没法解析关机时长呢，可以试试 30m、2h 或者 1d")
+                    .send()
+                    .warn_on_error("bot-on-off")
+                    .into();
             }
         }
-        .into()
+    };
+    let until = match duration {
+        Some(duration) => match SystemTime::now().checked_add(duration) {
+            Some(until) => Some(until),
+            None => {
+                return ctx
+                    .reply("关机时长太长了，不写时长可以一直关机")
+                    .send()
+                    .warn_on_error("bot-on-off")
+                    .into();
+            }
+        },
+        None => None,
+    };
+    let args = args.to_string();
+    async move {
+        let job_id = match until {
+            Some(until) => match schedule_wake_up(ctx.app, ctx.chat_id, until).await {
+                Ok(job_id) => Some(job_id),
+                Err(err) => return ctx.reply_db_error("bot-on-off", err).await,
+            },
+            None => None,
+        };
+        let prev = match update_state(ctx.app, ctx.chat_id, Some(BotOff { until, job_id })).await {
+            Ok(prev) => prev,
+            Err(err) => return ctx.reply_db_error("bot-on-off", err).await,
+        };
+        let text = match (prev.filter(BotOff::is_active), duration) {
+            (_, Some(_)) => format!("琳酱已关机，将在 {args} 后自动开机"),
+            (Some(_), None) => "琳酱处于关机状态".to_string(),
+            (None, None) => "琳酱已关机".to_string(),
+        };
+        ctx.reply(text).send().warn_on_error("bot-on-off").await;
     }
+    .into()
 }
 
 fn stop_when_bot_off(ctx: &mut Context, _: &Message) -> Consumption {
-    if let Ok(record) = BOT_ON.read()
-        && let Some(false) = record.get(&ctx.chat_id)
+    if let Ok(record) = BOT_OFF.read()
+        && record.get(&ctx.chat_id).is_some_and(BotOff::is_active)
     {
         return Consumption::just_stop();
     }
@@ -78,6 +240,7 @@ pub static BOT_ON_MODULE: Module = Module {
         name: "bot_on",
         description: "打开 bot",
         description_detailed: None,
+        permission: Permission::ChatAdmin,
     }),
     task: on_bot_on_message,
 };
@@ -85,7 +248,11 @@ pub static BOT_OFF_MODULE: Module = Module {
     kind: ModuleKind::Command(ModuleDescription {
         name: "bot_off",
         description: "关闭 bot",
-        description_detailed: None,
+        description_detailed: Some(concat!(
+            "<code>/bot_off</code>: 关闭 bot，直到使用 <code>/bot_on</code> 打开\n",
+            "<code>/bot_off 2h</code>: 关闭 bot，两小时后自动打开。支持 1h30m、2d 这样的时长",
+        )),
+        permission: Permission::ChatAdmin,
     }),
     task: on_bot_off_message,
 };
@@ -93,3 +260,4 @@ pub static STOP_WHEN_BOT_OFF: Module = Module {
     kind: ModuleKind::General(None),
     task: stop_when_bot_off,
 };
+pub static ON_WAKE_UP: MicroTask = MicroTask::OnScheduledJob(MODULE_NAME, on_wake_up);
@@ -0,0 +1,664 @@
+//! 入群验证
+//! ```text
+//! /captcha [on|off]
+//! /captcha timeout <时长>
+//! /captcha log
+//! ```
+//! 打开后，新成员入群时会被禁言，并收到一道选择题（成语填字或简单算术）。
+//! 答对后解除禁言，答错或超时会被移出群聊（之后仍可以重新加入）。
+//!
+//! 验证状态保存在数据库中，超时由 [crate::linquebot::scheduler] 处理，重启后仍然有效。
+//! 琳酱需要是管理员，并且有封禁成员的权限。
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Local};
+use log::{info, warn};
+use rand::seq::{IndexedRandom, SliceRandom};
+use rand::{Rng, rng};
+use serde::{Deserialize, Serialize};
+use teloxide_core::RequestError;
+use teloxide_core::prelude::*;
+use teloxide_core::types::*;
+
+use crate::Consumption;
+use crate::assets::idiom::random_idiom;
+use crate::linquebot::callback::Callback;
+use crate::linquebot::db::{DataType, DbData, DbError, WarnDbError};
+use crate::linquebot::msg_context::Context;
+use crate::linquebot::scheduler::{Job, NewJob};
+use crate::linquebot::*;
+use crate::mods::moderation::lift_restriction;
+use crate::mods::welcome;
+use crate::utils::markup::{mention_id, text};
+use crate::utils::split_args;
+use crate::utils::telegram::prelude::*;
+use crate::utils::time::parse_duration;
+
+const MODULE_NAME: &str = "captcha";
+const DEFAULT_TIMEOUT: u64 = 120;
+const LOG_SIZE: usize = 50;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CaptchaSetting {
+    enabled: bool,
+    /// 超时时间，秒
+    timeout: u64,
+}
+
+impl DbData for CaptchaSetting {
+    const KEY: &str = "captcha::CaptchaSetting";
+}
+
+impl Default for CaptchaSetting {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+}
+
+/// 一个等待验证的成员
+#[derive(Debug, Serialize, Deserialize)]
+struct Pending {
+    full_name: String,
+    answer: usize,
+    message_id: MessageId,
+    job_id: u64,
+}
+
+/// 群里所有等待验证的成员
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PendingCaptchas {
+    users: HashMap<UserId, Pending>,
+}
+
+impl DbData for PendingCaptchas {
+    const KEY: &str = "captcha::PendingCaptchas";
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+enum Outcome {
+    Passed,
+    Failed,
+    TimedOut,
+    Left,
+}
+
+impl Outcome {
+    fn describe(self) -> &'static str {
+        match self {
+            Outcome::Passed => "通过",
+            Outcome::Failed => "答错",
+            Outcome::TimedOut => "超时",
+            Outcome::Left => "退群",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct LogEntry {
+    at: SystemTime,
+    user_id: UserId,
+    full_name: String,
+    outcome: Outcome,
+}
+
+/// 每个群最近的验证结果
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CaptchaLog {
+    entries: VecDeque<LogEntry>,
+}
+
+impl DbData for CaptchaLog {
+    const KEY: &str = "captcha::CaptchaLog";
+}
+
+pub static DATA_TYPES: &[DataType] = &[
+    DataType::of::<CaptchaSetting>(),
+    DataType::of::<PendingCaptchas>(),
+    DataType::of::<CaptchaLog>(),
+];
+
+/// 一道选择题
+struct Challenge {
+    question: String,
+    options: Vec<String>,
+    answer: usize,
+}
+
+impl Challenge {
+    fn new(question: String, answer: String, mut wrong: Vec<String>) -> Self {
+        wrong.retain(|option| *option != answer);
+        wrong.sort();
+        wrong.dedup();
+        wrong.shuffle(&mut rng());
+        let mut options = wrong.into_iter().take(3).collect::<Vec<_>>();
+        let answer_idx = rng().random_range(0..=options.len());
+        options.insert(answer_idx, answer);
+        Self {
+            question,
+            options,
+            answer: answer_idx,
+        }
+    }
+
+    /// 成语填字
+    fn idiom() -> Option<Self> {
+        let word = random_idiom().word.chars().collect::<Vec<_>>();
+        let hole = *(0..word.len()).collect::<Vec<_>>().choose(&mut rng())?;
+        let question = word
+            .iter()
+            .enumerate()
+            .map(|(i, ch)| if i == hole { '□' } else { *ch })
+            .collect::<String>();
+        let wrong = (0..10)
+            .filter_map(|_| {
+                random_idiom()
+                    .word
+                    .chars()
+                    .collect::<Vec<_>>()
+                    .choose(&mut rng())
+                    .copied()
+            })
+            .map(String::from)
+            .collect();
+        Some(Self::new(
+            format!("请选择成语中缺少的字：{question}"),
+            word[hole].to_string(),
+            wrong,
+        ))
+    }
+
+    /// 简单算术
+    fn arithmetic() -> Self {
+        let mut rng = rng();
+        let (op, calc): (_, fn(i32, i32) -> i32) = if rng.random_bool(0.5) {
+            ('+', |a, b| a + b)
+        } else {
+            ('×', |a, b| a * b)
+        };
+        let mut operands = || (rng.random_range(2..20), rng.random_range(2..10));
+        let (a, b) = operands();
+        let answer = calc(a, b).to_string();
+        // 错误选项是另外几道同类题目的答案，不会集中在正确答案附近
+        let mut wrong = vec![];
+        while wrong.len() < 3 {
+            let (a, b) = operands();
+            let n = calc(a, b).to_string();
+            if n != answer && !wrong.contains(&n) {
+                wrong.push(n);
+            }
+        }
+        Self::new(format!("请计算：{a} {op} {b} = ?"), answer, wrong)
+    }
+
+    fn random() -> Self {
+        if rng().random_bool(0.5) {
+            Self::idiom().unwrap_or_else(Self::arithmetic)
+        } else {
+            Self::arithmetic()
+        }
+    }
+
+    fn keyboard(&self, user_id: UserId, timeout: Duration) -> InlineKeyboardMarkup {
+        InlineKeyboardMarkup::new([self
+            .options
+            .iter()
+            .enumerate()
+            .filter_map(|(i, option)| {
+                Callback::new(MODULE_NAME, &(user_id, i))
+                    .expires_in(timeout)
+                    .button(option)
+            })
+            .collect::<Vec<_>>()])
+    }
+}
+
+async fn log_outcome(
+    app: &'static App,
+    chat_id: ChatId,
+    user_id: UserId,
+    full_name: String,
+    outcome: Outcome,
+) {
+    info!(target: "captcha", "{full_name} ({user_id}) in {chat_id}: {outcome:?}");
+    let Some(mut log) = app
+        .db
+        .of::<CaptchaLog>()
+        .chat(chat_id)
+        .get_or_insert(Default::default)
+        .await
+        .ok_or_warn("captcha")
+    else {
+        return;
+    };
+    log.entries.push_back(LogEntry {
+        at: SystemTime::now(),
+        user_id,
+        full_name,
+        outcome,
+    });
+    while log.entries.len() > LOG_SIZE {
+        log.entries.pop_front();
+    }
+}
+
+/// 取出等待中的验证，并取消超时任务
+async fn take_pending(app: &'static App, chat_id: ChatId, user_id: UserId) -> Option<Pending> {
+    let pending = app
+        .db
+        .of::<PendingCaptchas>()
+        .chat(chat_id)
+        .get()
+        .await
+        .ok_or_warn("captcha")??
+        .users
+        .remove(&user_id)?;
+    if let Err(err) = app.scheduler.cancel(app, chat_id, pending.job_id).await {
+        warn!(target: "captcha", "failed to cancel job {}: {err}", pending.job_id);
+    }
+    Some(pending)
+}
+
+/// 移出群聊，但允许重新加入
+async fn kick(app: &'static App, chat_id: ChatId, user_id: UserId) {
+    app.bot
+        .ban_chat_member(chat_id, user_id)
+        .send()
+        .warn_on_error("captcha-kick")
+        .await;
+    app.bot
+        .unban_chat_member(chat_id, user_id)
+        .only_if_banned(true)
+        .send()
+        .warn_on_error("captcha-kick")
+        .await;
+}
+
+/// 这个群是否打开了入群验证
+pub async fn enabled(app: &'static App, chat_id: ChatId) -> bool {
+    app.db
+        .of::<CaptchaSetting>()
+        .chat(chat_id)
+        .get()
+        .await
+        .ok_or_warn("captcha")
+        .flatten()
+        .is_some_and(|setting| setting.enabled)
+}
+
+/// 开始验证，没能开始的话返回 `false`，这时成员可以直接发言
+async fn start_captcha(app: &'static App, chat_id: ChatId, user: &User, timeout: u64) -> bool {
+    if let Err(err) = app
+        .bot
+        .restrict_chat_member(chat_id, user.id, ChatPermissions::empty())
+        .send()
+        .await
+    {
+        warn!(target: "captcha", "failed to restrict {} in {chat_id}: {err}", user.id);
+        return false;
+    }
+    let challenge = Challenge::random();
+    let text = (text("欢迎 ")
+        + mention_id(user.id, user.full_name())
+        + format!("，请在 {timeout} 秒内完成验证，否则会被移出群聊\n")
+        + &challenge.question)
+        .html();
+    let request = app
+        .bot
+        .send_message(chat_id, text)
+        .parse_mode(ParseMode::Html)
+        .reply_markup(challenge.keyboard(user.id, Duration::from_secs(timeout)));
+    let message = loop {
+        match app.outbox.send(chat_id, &request).await {
+            Ok(message) => break message,
+            // 大量成员同时入群时会一直被限速，这时要继续等待，不能放行
+            Err(RequestError::RetryAfter(secs)) => {
+                warn!(target: "captcha", "challenge is rate limited in {chat_id}, keep waiting");
+                tokio::time::sleep(secs.duration()).await;
+            }
+            Err(err) => {
+                warn!(target: "captcha", "failed to send challenge: {err}");
+                lift_restriction(app, chat_id, user.id).await;
+                return false;
+            }
+        }
+    };
+    let job_id = match app
+        .scheduler
+        .schedule(
+            app,
+            NewJob {
+                module: MODULE_NAME,
+                chat_id,
+                thread_id: None,
+                message_id: Some(message.id),
+                at: SystemTime::now() + Duration::from_secs(timeout),
+                payload: &user.id,
+            },
+        )
+        .await
+    {
+        Ok(job_id) => job_id,
+        Err(err) => {
+            warn!(target: "captcha", "failed to schedule timeout: {err}");
+            lift_restriction(app, chat_id, user.id).await;
+            return false;
+        }
+    };
+    let Some(mut pending) = app
+        .db
+        .of::<PendingCaptchas>()
+        .chat(chat_id)
+        .get_or_insert(Default::default)
+        .await
+        .ok_or_warn("captcha")
+    else {
+        lift_restriction(app, chat_id, user.id).await;
+        return false;
+    };
+    let old = pending.users.insert(
+        user.id,
+        Pending {
+            full_name: user.full_name(),
+            answer: challenge.answer,
+            message_id: message.id,
+            job_id,
+        },
+    );
+    drop(pending);
+    // 验证完成前重新入群，取消之前的验证
+    if let Some(old) = old {
+        if let Err(err) = app.scheduler.cancel(app, chat_id, old.job_id).await {
+            warn!(target: "captcha", "failed to cancel job {}: {err}", old.job_id);
+        }
+        app.bot
+            .delete_message(chat_id, old.message_id)
+            .send()
+            .warn_on_error("captcha")
+            .await;
+    }
+    true
+}
+
+fn on_chat_member(app: &'static App, data: &ChatMemberUpdated) -> Consumption {
+    let chat_id = data.chat.id;
+    let user = data.new_chat_member.user.clone();
+    if user.is_bot {
+        return Consumption::just_next();
+    }
+    if !data.new_chat_member.is_present() {
+        return Consumption::next_with(async move {
+            if let Some(pending) = take_pending(app, chat_id, user.id).await {
+                app.bot
+                    .delete_message(chat_id, pending.message_id)
+                    .send()
+                    .warn_on_error("captcha")
+                    .await;
+                log_outcome(app, chat_id, user.id, pending.full_name, Outcome::Left).await;
+            }
+        });
+    }
+    if data.old_chat_member.is_present() {
+        return Consumption::just_next();
+    }
+    let chat = data.chat.clone();
+    Consumption::next_with(async move {
+        let timeout = {
+            let Some(setting) = app
+                .db
+                .of::<CaptchaSetting>()
+                .chat(chat_id)
+                .get()
+                .await
+                .ok_or_warn("captcha")
+                .flatten()
+            else {
+                return;
+            };
+            if !setting.enabled {
+                return;
+            }
+            setting.timeout
+        };
+        // 没能开始验证时 welcome 不会欢迎，在这里补上
+        if !start_captcha(app, chat_id, &user, timeout).await {
+            welcome::greet(app, &chat, &user).await;
+        }
+    })
+}
+
+fn on_callback(app: &'static App, cq: &CallbackQuery, callback: &Callback) -> Consumption {
+    let (user_id, choice) = callback.payload::<(UserId, usize)>()?;
+    let chat = cq.message.as_ref()?.chat().clone();
+    let chat_id = chat.id;
+    let from = cq.from.clone();
+    let cq_id = cq.id.clone();
+
+    async move {
+        if from.id != user_id {
+            app.bot
+                .answer_callback_query(cq_id)
+                .text("这不是给你的验证哦")
+                .send()
+                .warn_on_error("captcha")
+                .await;
+            return;
+        }
+        let Some(pending) = take_pending(app, chat_id, user_id).await else {
+            app.bot
+                .answer_callback_query(cq_id)
+                .text("验证已经结束了")
+                .send()
+                .warn_on_error("captcha")
+                .await;
+            return;
+        };
+        let outcome = if choice == pending.answer {
+            lift_restriction(app, chat_id, user_id).await;
+            Outcome::Passed
+        } else {
+            kick(app, chat_id, user_id).await;
+            Outcome::Failed
+        };
+        app.bot
+            .answer_callback_query(cq_id)
+            .text(format!("验证{}", outcome.describe()))
+            .send()
+            .warn_on_error("captcha")
+            .await;
+        app.bot
+            .delete_message(chat_id, pending.message_id)
+            .send()
+            .warn_on_error("captcha")
+            .await;
+        log_outcome(app, chat_id, user_id, pending.full_name, outcome).await;
+        if let Outcome::Passed = outcome {
+            welcome::greet(app, &chat, &from).await;
+        }
+    }
+    .into()
+}
+
+fn on_timeout(app: &'static App, job: &Job) -> Consumption {
+    let user_id = job.payload::<UserId>()?;
+    let chat_id = job.chat_id;
+    let job_id = job.id;
+    async move {
+        // 重新入群后旧的超时任务不再有效
+        let Some(pending) = app
+            .db
+            .of::<PendingCaptchas>()
+            .chat(chat_id)
+            .get()
+            .await
+            .ok_or_warn("captcha")
+            .flatten()
+            .filter(|captchas| {
+                captchas
+                    .users
+                    .get(&user_id)
+                    .is_some_and(|p| p.job_id == job_id)
+            })
+            .and_then(|mut captchas| captchas.users.remove(&user_id))
+        else {
+            return;
+        };
+        kick(app, chat_id, user_id).await;
+        app.bot
+            .delete_message(chat_id, pending.message_id)
+            .send()
+            .warn_on_error("captcha")
+            .await;
+        log_outcome(app, chat_id, user_id, pending.full_name, Outcome::TimedOut).await;
+    }
+    .into()
+}
+
+async fn show_log(app: &'static App, chat_id: ChatId) -> Result<String, DbError> {
+    let Some(log) = app.db.of::<CaptchaLog>().chat(chat_id).get().await? else {
+        return Ok("还没有入群验证记录".to_string());
+    };
+    if log.entries.is_empty() {
+        return Ok("还没有入群验证记录".to_string());
+    }
+    let text = log
+        .entries
+        .iter()
+        .rev()
+        .take(20)
+        .map(|entry| {
+            format!(
+                "{} {} ({}) {}",
+                DateTime::<Local>::from(entry.at).format("%m-%d %H:%M"),
+                entry.full_name,
+                entry.user_id,
+                entry.outcome.describe()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    Ok(text)
+}
+
+fn on_captcha(ctx: &mut Context, msg: &Message) -> Consumption {
+    if msg.chat.is_private() {
+        return ctx
+            .task()
+            .reply("只能在群里使用入群验证哦")
+            .send()
+            .warn_on_error("captcha")
+            .into();
+    }
+    let [cmd, rest] = split_args(ctx.cmd?.content);
+    let (cmd, rest) = (cmd.to_string(), rest.to_string());
+    let ctx = ctx.task();
+    async move {
+        ctx.with_db("captcha", async {
+            let text =
+                if cmd == "log" {
+                    show_log(ctx.app, ctx.chat_id).await?
+                } else {
+                    let mut setting = ctx
+                        .app
+                        .db
+                        .of::<CaptchaSetting>()
+                        .chat(ctx.chat_id)
+                        .get_or_insert(Default::default)
+                        .await?;
+                    match cmd.as_str() {
+                        "" => format!(
+                            "本群的入群验证已{}，超时时间 {} 秒",
+                            if setting.enabled { "打开" } else { "关闭" },
+                            setting.timeout
+                        ),
+                        "on" => {
+                            setting.enabled = true;
+                            "本群的入群验证已打开，请确保琳酱有封禁成员的权限".to_string()
+                        }
+                        "off" => {
+                            setting.enabled = false;
+                            "本群的入群验证已关闭".to_string()
+                        }
+                        "timeout" => match parse_duration(&rest).map(|dur| dur.as_secs()) {
+                            Some(secs @ 30..=86400) => {
+                                setting.timeout = secs;
+                                format!("超时时间已设置为 {secs} 秒")
+                            }
+                            _ => "超时时间需要在 30 秒到 1 天之间，例如 /captcha timeout 5m"
+                                .to_string(),
+                        },
+                        _ => "用法：/captcha [on|off]、/captcha timeout <时长>、/captcha log"
+                            .to_string(),
+                    }
+                };
+            ctx.reply(text).send().warn_on_error("captcha").await;
+            Ok(())
+        })
+        .await
+    }
+    .into()
+}
+
+pub static MODULE: Module = Module {
+    kind: ModuleKind::Command(ModuleDescription {
+        name: "captcha",
+        description: "设置入群验证",
+        description_detailed: Some(concat!(
+            "/captcha on 或 /captcha off 打开或关闭入群验证\n",
+            "/captcha timeout 5m 设置验证的超时时间\n",
+            "/captcha log 查看最近的验证结果\n",
+            "新成员入群后会被禁言，答对选择题后解除，答错或超时会被移出群聊\n",
+            "琳酱需要是管理员，并且有封禁成员的权限"
+        )),
+        permission: Permission::ChatAdmin,
+    }),
+    task: on_captcha,
+};
+
+pub static ON_CHAT_MEMBER: MicroTask = MicroTask::OnChatMember(on_chat_member);
+
+pub static ON_CALLBACK: MicroTask = MicroTask::OnCallback(MODULE_NAME, on_callback);
+
+pub static ON_TIMEOUT: MicroTask = MicroTask::OnScheduledJob(MODULE_NAME, on_timeout);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn challenge_has_answer() {
+        for _ in 0..100 {
+            let challenge = Challenge::arithmetic();
+            assert_eq!(challenge.options.len(), 4);
+            let mut options = challenge.options.clone();
+            options.sort();
+            options.dedup();
+            assert_eq!(options.len(), 4);
+            assert!(
+                options
+                    .iter()
+                    .all(|option| option.parse::<i32>().unwrap() >= 4)
+            );
+            let (a, b) = challenge
+                .question
+                .trim_start_matches("请计算：")
+                .trim_end_matches(" = ?")
+                .split_once(['+', '×'])
+                .unwrap();
+            let (a, b) = (
+                a.trim().parse::<i32>().unwrap(),
+                b.trim().parse::<i32>().unwrap(),
+            );
+            let expected = if challenge.question.contains('+') {
+                a + b
+            } else {
+                a * b
+            };
+            assert_eq!(challenge.options[challenge.answer], expected.to_string());
+        }
+    }
+}
@@ -1,11 +1,48 @@
+use crate::Consumption;
 use crate::linquebot::*;
+use crate::utils::markup::{Markup, blockquote, code, text};
 use crate::utils::telegram::prelude::*;
-use crate::Consumption;
 /// 随机选择器
-use msg_context::Context;
+use msg_context::{Context, TaskContext};
 use teloxide_core::prelude::*;
 use teloxide_core::types::*;
 
+/// 最近被隔离的损坏数据
+fn on_quarantine(ctx: &mut Context, _: &Message) -> Consumption {
+    show_quarantine(ctx.task()).into()
+}
+
+async fn show_quarantine(ctx: TaskContext) {
+    let (rows, count) = match ctx.app.db.quarantined(10).await {
+        Ok(res) => res,
+        Err(err) => return ctx.reply_db_error("debugger", err).await,
+    };
+    if rows.is_empty() {
+        ctx.reply("没有被隔离的数据")
+            .send()
+            .warn_on_error("debugger")
+            .await;
+        return;
+    }
+    let list = rows
+        .iter()
+        .map(|row| {
+            text("\n")
+                + code(&row.ty)
+                + format!(" user={} chat={} {}", row.user, row.chat, row.at)
+                + blockquote(&row.error)
+        })
+        .collect::<Markup>();
+    let reply = text(format!(
+        "共有 {count} 条被隔离的数据，最近 {} 条：",
+        rows.len()
+    )) + list;
+    ctx.reply_html(reply.html())
+        .send()
+        .warn_on_error("debugger")
+        .await;
+}
+
 fn on_debugger(ctx: &mut Context, message: &Message) -> Consumption {
     ctx.task()
         .reply_html(format!(
@@ -27,6 +64,17 @@ pub static DEBUGGER: Module = Module {
         name: "debugger",
         description: "调试器",
         description_detailed: None,
+        permission: Permission::Anyone,
     }),
     task: on_debugger,
 };
+
+pub static QUARANTINE: Module = Module {
+    kind: ModuleKind::Command(ModuleDescription {
+        name: "quarantine",
+        description: "查看被隔离的损坏数据",
+        description_detailed: Some("列出最近 10 条无法读取而被隔离的数据，只有琳酱的主人可以使用"),
+        permission: Permission::BotOwner,
+    }),
+    task: on_quarantine,
+};
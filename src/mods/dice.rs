@@ -9,17 +9,10 @@ use crate::Consumption;
 use crate::linquebot::*;
 use crate::utils::telegram::prelude::WarnOnError;
 
-pub fn dice(ctx: &mut Context, message: &Message) -> Consumption {
+/// 解析 `xdy` 并抛掷骰子，返回结果文本或错误提示
+pub fn roll(text: &str) -> Result<String, &'static str> {
     use crate::utils::pattern::*;
 
-    let text = ctx.cmd?.content;
-    let Some(from) = message.from.clone() else {
-        warn!("No reply target.");
-        return Consumption::just_stop();
-    };
-
-    let ctx = ctx.task();
-
     let Some((_, (x, _, y))) = (
         of_pred(|c| c.is_ascii_digit()),
         "d",
@@ -27,61 +20,53 @@ pub fn dice(ctx: &mut Context, message: &Message) -> Consumption {
     )
         .check_pattern(text)
     else {
-        return ctx
-            .reply("参数必须是 xdy 的格式，其中 x 和 y 是正整数")
-            .send()
-            .warn_on_error("dice")
-            .into();
+        return Err("参数必须是 xdy 的格式，其中 x 和 y 是正整数");
     };
 
     if x.is_empty() || y.is_empty() {
-        return ctx
-            .reply("参数必须是 xdy 的格式，其中 x 和 y 是正整数")
-            .send()
-            .warn_on_error("dice")
-            .into();
+        return Err("参数必须是 xdy 的格式，其中 x 和 y 是正整数");
     }
 
     let Ok(x) = x.parse::<u16>() else {
-        return ctx
-            .reply("提供的 x 太大了！")
-            .send()
-            .warn_on_error("dice")
-            .into();
+        return Err("提供的 x 太大了！");
     };
 
     let Ok(y) = y.parse::<u32>() else {
-        return ctx
-            .reply("提供的 y 太大了！")
-            .send()
-            .warn_on_error("dice")
-            .into();
+        return Err("提供的 y 太大了！");
     };
 
     if x > 500 {
-        return ctx
-            .reply("提供的 x 太大了！")
-            .send()
-            .warn_on_error("dice")
-            .into();
+        return Err("提供的 x 太大了！");
+    }
+
+    if y == 0 {
+        return Err("y 必须是正整数");
     }
 
+    let results = (0..x)
+        .map(|_| rand::rng().random_range(1..=y as u64))
+        .collect::<Vec<_>>();
+
+    // x 个 u32 的和肯定不会超过 u64，可以放心不会 panic
+    let sum: u64 = results.iter().sum();
+    Ok(format!("{}: {:?}", sum, results))
+}
+
+pub fn dice(ctx: &mut Context, message: &Message) -> Consumption {
+    let text = ctx.cmd?.content.to_string();
+    let Some(from) = message.from.clone() else {
+        warn!("No reply target.");
+        return Consumption::just_stop();
+    };
+
+    let ctx = ctx.task();
+
     async move {
-        let results = (0..x)
-            .map(|_| rand::rng().random_range(1..=y as u64))
-            .collect::<Vec<_>>();
-
-        // x 个 u32 的和肯定不会超过 u64，可以放心不会 panic
-        let sum: u64 = results.iter().sum();
-        let str = format!("{} 掷出了：{}: {:?}", from.full_name(), sum, results);
-        if str.len() >= 4095 {
-            ctx.reply("你的 xdy 太大了，超过了能发送的长度")
-                .send()
-                .warn_on_error("dice")
-                .await;
-        } else {
-            ctx.reply(str).send().warn_on_error("dice").await;
-        }
+        let text = match roll(&text) {
+            Ok(result) => format!("{} 掷出了：{}", from.full_name(), result),
+            Err(err) => err.to_string(),
+        };
+        ctx.reply(text).send().warn_on_error("dice").await;
     }
     .into()
 }
@@ -95,6 +80,21 @@ pub static MODULE: Module = Module {
             "结果返回 骰子总和: [每个骰子点数...]\n",
             "注意 x 最大不能超过 500，y 最大不能超过 4294967295"
         )),
+        permission: Permission::Anyone,
     }),
     task: dice,
 };
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roll_dice() {
+        assert!(roll("3d6").is_ok());
+        assert_eq!(roll("1d1").unwrap(), "1: [1]");
+        assert_eq!(roll("1d0"), Err("y 必须是正整数"));
+        assert!(roll("501d6").is_err());
+        assert!(roll("d6").is_err());
+    }
+}
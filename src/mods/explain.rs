@@ -92,6 +92,7 @@ pub static MODULE: Module = Module {
         name: "explain",
         description: "解释名词",
         description_detailed: Some("需要一个参数，即等待解释的名词"),
+        permission: Permission::Anyone,
     }),
     task: send_explain,
 };
@@ -0,0 +1,39 @@
+//! 删除琳酱保存的个人数据
+//! ```text
+//! /forget_me
+//! ```
+//! 删除所有按用户保存的数据（比如时区设置），群里的记录（比如成员名单、警告）不受影响。
+
+use teloxide_core::prelude::*;
+use teloxide_core::types::*;
+
+use crate::Consumption;
+use crate::linquebot::db::Scan;
+use crate::linquebot::msg_context::Context;
+use crate::linquebot::*;
+use crate::mods::DATA_TYPES;
+use crate::utils::telegram::prelude::*;
+
+fn on_forget_me(ctx: &mut Context, msg: &Message) -> Consumption {
+    let user_id = msg.from.as_ref()?.id;
+    let ctx = ctx.task();
+    async move {
+        let text = match ctx.app.db.remove_all(DATA_TYPES, Scan::User(user_id)).await {
+            Ok(0) => "琳酱没有保存你的数据".to_string(),
+            Ok(removed) => format!("已删除琳酱保存的 {removed} 条你的数据"),
+            Err(err) => return ctx.reply_db_error("forget", err).await,
+        };
+        ctx.reply(text).send().warn_on_error("forget").await;
+    }
+    .into()
+}
+
+pub static MODULE: Module = Module {
+    kind: ModuleKind::Command(ModuleDescription {
+        name: "forget_me",
+        description: "删除琳酱保存的你的数据",
+        description_detailed: Some("删除所有按用户保存的数据，比如时区设置。群里的记录不受影响"),
+        permission: Permission::Anyone,
+    }),
+    task: on_forget_me,
+};
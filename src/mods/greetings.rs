@@ -1,7 +1,10 @@
 use std::{collections::HashMap, time::SystemTime};
 
+use crate::linquebot::db::{DataType, DbData, WarnDbError};
 use crate::{
-    linquebot::{Module, ModuleDescription, ModuleKind, msg_context::Context, types::Consumption},
+    linquebot::{
+        Module, ModuleDescription, ModuleKind, Permission, msg_context::Context, types::Consumption,
+    },
     utils::telegram::prelude::WarnOnError,
 };
 use rand::seq::IteratorRandom;
@@ -17,6 +20,12 @@ struct GreetingStat {
     last_3_msg_date: HashMap<UserId, [SystemTime; 3]>,
 }
 
+impl DbData for GreetingStat {
+    const KEY: &str = "greetings::GreetingStat";
+}
+
+pub static DATA_TYPES: &[DataType] = &[DataType::of::<GreetingStat>()];
+
 impl Default for GreetingStat {
     fn default() -> Self {
         GreetingStat {
@@ -29,13 +38,17 @@ impl Default for GreetingStat {
 fn toggle_greeting(ctx: &mut Context, _msg: &Message) -> Consumption {
     let ctx = ctx.task();
     async move {
-        let mut db = ctx
+        let mut db = match ctx
             .app
             .db
             .of::<GreetingStat>()
             .chat(ctx.chat_id)
             .get_or_insert(Default::default)
-            .await;
+            .await
+        {
+            Ok(db) => db,
+            Err(err) => return ctx.reply_db_error("greeting", err).await,
+        };
         db.enabled = !db.enabled;
         let text = if db.enabled { "打开" } else { "关闭" };
         ctx.reply(format!("本群的打招呼功能已{text}"))
@@ -67,12 +80,14 @@ mod morning {
         )
     }
     fn morning_3(ctx: TaskContext, _: User) -> TaskResult {
-        Box::pin(
+        Box::pin(async move {
+            ctx.app.outbox.wait(ctx.chat_id).await;
             ctx.app
                 .bot
-                .send_sticker_by_file_id(ctx.chat_id, "CAACAgUAAx0CYVtTrQABBnNDZ679Ld8mNB2n3255MtxXjUPeObUAAkwGAAJYxiBXkpKDglGMbcc2BA")
-                .warn_on_error("greeting"),
-        )
+                .send_sticker_by_file_id(ctx.chat_id, ctx.thread_id, "CAACAgUAAx0CYVtTrQABBnNDZ679Ld8mNB2n3255MtxXjUPeObUAAkwGAAJYxiBXkpKDglGMbcc2BA")
+                .warn_on_error("greeting")
+                .await
+        })
     }
 
     pub static MORNING: [fn(TaskContext, User) -> TaskResult; 3] =
@@ -101,13 +116,17 @@ fn say_greeting(ctx: &mut Context, msg: &Message) -> Consumption {
     let is_reply = msg.reply_to_message().is_some();
 
     Consumption::next_with(async move {
-        let mut db = ctx
+        let Some(mut db) = ctx
             .app
             .db
             .of::<GreetingStat>()
             .chat(ctx.chat_id)
             .get_or_insert(Default::default)
-            .await;
+            .await
+            .ok_or_warn("greeting")
+        else {
+            return;
+        };
 
         if !db.enabled {
             return;
@@ -158,6 +177,7 @@ pub static TOGGLE: Module = Module {
         name: "toggle_greeting",
         description: "打开/关闭打招呼",
         description_detailed: None,
+        permission: Permission::ChatAdmin,
     }),
     task: toggle_greeting,
 };
@@ -1,13 +1,16 @@
 /// 显示帮助和关于信息
+use log::warn;
+use serde::{Deserialize, Serialize};
 use teloxide_core::prelude::*;
 use teloxide_core::types::{
-    CallbackQuery, InlineKeyboardButton, InlineKeyboardMarkup, LinkPreviewOptions, Message,
-    ParseMode,
+    CallbackQuery, InlineKeyboardMarkup, LinkPreviewOptions, Message, ParseMode,
 };
 
+use crate::chat_modules::ChatModules;
+use crate::linquebot::callback::Callback;
 use crate::msg_context::Context;
-use crate::utils::telegram::prelude::WarnOnError;
-use crate::{App, Consumption, MicroTask, Module, ModuleDescription, ModuleKind};
+use crate::utils::telegram::prelude::{InTopic, WarnOnError};
+use crate::{App, Consumption, MicroTask, Module, ModuleDescription, ModuleKind, Permission};
 
 fn read_description(kind: &ModuleKind) -> Option<&ModuleDescription> {
     match kind {
@@ -19,12 +22,21 @@ fn read_description(kind: &ModuleKind) -> Option<&ModuleDescription> {
 
 static HELP_HEAD: &str = "OoO 这里是琳酱的帮助";
 
-fn gen_help_message(app: &App) -> (String, InlineKeyboardMarkup) {
+const MODULE_NAME: &str = "help";
+
+/// 帮助按钮对应的页面
+#[derive(Debug, Serialize, Deserialize)]
+enum HelpPage {
+    Index,
+    Module(String),
+}
+
+fn gen_help_message(app: &App, chat_modules: &ChatModules) -> (String, InlineKeyboardMarkup) {
     let mut command_texts = Vec::<String>::new();
     let mut general_texts = Vec::<String>::new();
     let mut detailed_modules = Vec::<&str>::new();
 
-    for module in app.modules {
+    for module in chat_modules.enabled_modules(app) {
         match &module.kind {
             ModuleKind::Command(cmd) => {
                 command_texts.push(format!("/{}: {}", cmd.name, cmd.description));
@@ -47,7 +59,9 @@ fn gen_help_message(app: &App) -> (String, InlineKeyboardMarkup) {
         .map(|chunk| {
             chunk
                 .iter()
-                .map(|name| InlineKeyboardButton::callback(*name, format!("help {name}")))
+                .filter_map(|name| {
+                    Callback::new(MODULE_NAME, &HelpPage::Module(name.to_string())).button(*name)
+                })
                 .collect()
         })
         .collect::<Vec<Vec<_>>>();
@@ -75,27 +89,27 @@ fn gen_help_message(app: &App) -> (String, InlineKeyboardMarkup) {
 
 fn gen_partial_help_message(
     app: &App,
+    chat_modules: &ChatModules,
     module_name: &str,
 ) -> Option<(String, InlineKeyboardMarkup)> {
-    app.modules.iter().find_map(|module| {
+    chat_modules.enabled_modules(app).find_map(|module| {
         let desc = read_description(&module.kind)?;
 
         let ModuleDescription {
             name,
             description_detailed: Some(details),
             description,
+            ..
         } = desc
         else {
             return None;
         };
 
         if module_name == *name {
+            let back = Callback::new(MODULE_NAME, &HelpPage::Index).button("返回");
             return Some((
                 format!("{HELP_HEAD}\n\n<b>{name}</b>: {description}\n\n{details}"),
-                InlineKeyboardMarkup::new(vec![vec![InlineKeyboardButton::callback(
-                    "返回",
-                    "help {default}",
-                )]]),
+                InlineKeyboardMarkup::new([Vec::from_iter(back)]),
             ));
         }
 
@@ -114,16 +128,23 @@ fn disabled_link_preview() -> LinkPreviewOptions {
 }
 
 fn send_help(ctx: &mut Context, _msg: &Message) -> Consumption {
-    let module_name = ctx.cmd?.content;
+    let module_name = ctx.cmd?.content.to_string();
     let ctx = ctx.task();
-    let (msg, btn) =
-        gen_partial_help_message(ctx.app, module_name).unwrap_or_else(|| gen_help_message(ctx.app));
-    ctx.reply_html(msg)
-        .reply_markup(btn)
-        .link_preview_options(disabled_link_preview())
-        .send()
-        .warn_on_error("help")
-        .into()
+    async move {
+        let chat_modules = match ChatModules::load(ctx.app, ctx.chat_id).await {
+            Ok(chat_modules) => chat_modules,
+            Err(err) => return ctx.reply_db_error("help", err).await,
+        };
+        let (msg, btn) = gen_partial_help_message(ctx.app, &chat_modules, &module_name)
+            .unwrap_or_else(|| gen_help_message(ctx.app, &chat_modules));
+        ctx.reply_html(msg)
+            .reply_markup(btn)
+            .link_preview_options(disabled_link_preview())
+            .send()
+            .warn_on_error("help")
+            .await
+    }
+    .into()
 }
 
 fn say_hi(ctx: &mut Context, msg: &Message) -> Consumption {
@@ -141,6 +162,7 @@ fn say_hi(ctx: &mut Context, msg: &Message) -> Consumption {
                 ),
             )
             .link_preview_options(disabled_link_preview())
+            .in_topic(ctx.thread_id)
             .send()
             .warn_on_error("say-hi")
             .into()
@@ -149,25 +171,42 @@ fn say_hi(ctx: &mut Context, msg: &Message) -> Consumption {
     }
 }
 
-fn on_help_callback(app: &'static App, cq: &CallbackQuery) -> Consumption {
-    use crate::utils::pattern::*;
-    let (_, (_, help_module_name)) =
-        ("help ", of_pred(|_| true)).check_pattern(cq.data.as_ref()?)?;
-
+fn on_help_callback(app: &'static App, cq: &CallbackQuery, callback: &Callback) -> Consumption {
+    let page = callback.payload::<HelpPage>()?;
     let message = cq.message.clone()?;
     let chat_id = message.chat().id;
+    let cq_id = cq.id.clone();
 
-    let (msg, btn) =
-        gen_partial_help_message(app, help_module_name).unwrap_or_else(|| gen_help_message(app));
-
-    app.bot
-        .edit_message_text(chat_id, message.id(), msg)
-        .parse_mode(ParseMode::Html)
-        .link_preview_options(disabled_link_preview())
-        .reply_markup(btn)
-        .send()
-        .warn_on_error("edit-help")
-        .into()
+    async move {
+        // 先结束按钮上的加载动画
+        app.bot
+            .answer_callback_query(cq_id)
+            .send()
+            .warn_on_error("edit-help")
+            .await;
+        let chat_modules = match ChatModules::load(app, chat_id).await {
+            Ok(chat_modules) => chat_modules,
+            Err(err) => {
+                warn!(target: "edit-help", "{err}");
+                return;
+            }
+        };
+        let (msg, btn) = match page {
+            HelpPage::Module(name) => gen_partial_help_message(app, &chat_modules, &name),
+            HelpPage::Index => None,
+        }
+        .unwrap_or_else(|| gen_help_message(app, &chat_modules));
+
+        app.bot
+            .edit_message_text(chat_id, message.id(), msg)
+            .parse_mode(ParseMode::Html)
+            .link_preview_options(disabled_link_preview())
+            .reply_markup(btn)
+            .send()
+            .warn_on_error("edit-help")
+            .await
+    }
+    .into()
 }
 
 pub static MODULE: Module = Module {
@@ -175,6 +214,7 @@ pub static MODULE: Module = Module {
         name: "help",
         description: "显示帮助",
         description_detailed: None,
+        permission: Permission::Anyone,
     }),
     task: send_help,
 };
@@ -184,4 +224,4 @@ pub static SAY_HI: Module = Module {
     task: say_hi,
 };
 
-pub static HELP_CALLBACK: MicroTask = MicroTask::OnCallbackQuery(on_help_callback);
+pub static HELP_CALLBACK: MicroTask = MicroTask::OnCallback(MODULE_NAME, on_help_callback);
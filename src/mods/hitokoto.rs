@@ -79,6 +79,7 @@ pub static MODULE: Module = Module {
             "l	抖机灵\n",
             "其他	作为 动画 类型处理"
         )),
+        permission: Permission::Anyone,
     }),
     task: send_hitokoto,
 };
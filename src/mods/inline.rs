@@ -0,0 +1,125 @@
+//! inline 模式
+//! ```text
+//! @bot 3d6
+//! @bot rand A还是B
+//! @bot answer
+//! @bot tarot [数量]
+//! ```
+//! 在任意聊天中分享随机结果。需要先在 BotFather 中打开 inline mode。
+
+use teloxide_core::prelude::*;
+use teloxide_core::types::*;
+
+use crate::Consumption;
+use crate::linquebot::*;
+use crate::mods::answer_book::random_answer;
+use crate::mods::dice::roll;
+use crate::mods::rand::rand_result;
+use crate::utils::markup;
+use crate::utils::split::MESSAGE_LIMIT;
+use crate::utils::split_args;
+use crate::utils::telegram::prelude::WarnOnError;
+
+fn article(id: &str, title: &str, description: &str, html: String) -> InlineQueryResult {
+    InlineQueryResult::Article(
+        InlineQueryResultArticle::new(
+            id,
+            title,
+            InputMessageContent::Text(
+                InputMessageContentText::new(html).parse_mode(ParseMode::Html),
+            ),
+        )
+        .description(description),
+    )
+}
+
+fn dice_result(from: &User, text: &str) -> Option<InlineQueryResult> {
+    let result = roll(text).ok()?;
+    let markup = markup::text(format!("{} 掷出了 {text}：{result}", from.full_name()));
+    if markup.plain().encode_utf16().count() > MESSAGE_LIMIT {
+        return None;
+    }
+    Some(article(
+        "dice",
+        &format!("掷骰子 {text}"),
+        "抛掷骰子并发送结果",
+        markup.html(),
+    ))
+}
+
+fn rand_article(from: &User, text: &str) -> InlineQueryResult {
+    let description = if text.is_empty() {
+        "随机一个 0 到 100 的数"
+    } else {
+        "随机一下"
+    };
+    article("rand", "随机一下", description, rand_result(from, text))
+}
+
+fn answer_article(from: &User, question: &str) -> InlineQueryResult {
+    // 有问题时把答案藏起来，点开才能看到
+    let markup = if question.is_empty() {
+        markup::text(random_answer())
+    } else {
+        markup::text(format!("{} 问：{question}\n答案之书：", from.full_name()))
+            + markup::spoiler(random_answer())
+    };
+    article("answer", "答案之书", "翻开《答案之书》", markup.html())
+}
+
+#[cfg(feature = "tarot")]
+fn tarot_article(from: &User, text: &str) -> Option<InlineQueryResult> {
+    use crate::mods::tarot::{draw, parse_count};
+    let num = parse_count(text).ok()?;
+    Some(article(
+        "tarot",
+        &format!("抽取 {num} 张塔罗牌"),
+        "抽取塔罗牌并发送结果",
+        markup::text(draw(&from.full_name(), num)).html(),
+    ))
+}
+
+#[cfg(not(feature = "tarot"))]
+fn tarot_article(_from: &User, _text: &str) -> Option<InlineQueryResult> {
+    None
+}
+
+fn inline_results(query: &InlineQuery) -> Vec<InlineQueryResult> {
+    let from = &query.from;
+    let [cmd, rest] = split_args::<2>(&query.query);
+    match cmd {
+        "rand" => vec![rand_article(from, rest)],
+        "answer" => vec![answer_article(from, rest)],
+        "tarot" => tarot_article(from, rest).into_iter().collect(),
+        "" => [
+            Some(answer_article(from, "")),
+            Some(rand_article(from, "")),
+            tarot_article(from, ""),
+            dice_result(from, "1d6"),
+        ]
+        .into_iter()
+        .flatten()
+        .collect(),
+        _ => match dice_result(from, cmd) {
+            Some(dice) => vec![dice],
+            None => vec![rand_article(from, &query.query)],
+        },
+    }
+}
+
+fn on_inline_query(app: &'static App, query: &InlineQuery) -> Consumption {
+    let query = query.clone();
+    async move {
+        app.bot
+            .answer_inline_query(query.id.clone(), inline_results(&query))
+            // 结果是随机的，不要缓存
+            .cache_time(0)
+            .is_personal(true)
+            .send()
+            .warn_on_error("inline")
+            .await;
+    }
+    .into()
+}
+
+pub static INLINE_QUERY: MicroTask = MicroTask::OnInlineQuery(on_inline_query);
@@ -11,6 +11,7 @@ use log::error;
 use log::warn;
 use msg_context::Context;
 use msg_context::TaskContext;
+use msg_context::Topic;
 use rand::random;
 use teloxide_core::prelude::*;
 use teloxide_core::types::*;
@@ -100,7 +101,8 @@ impl Jielong {
     }
 }
 
-static CHAT_JIELONG_STATUS: LazyLock<RwLock<HashMap<ChatId, Jielong>>> =
+/// 打开 `PER_TOPIC_STATE` 时，论坛中每个话题可以分别接龙
+static CHAT_JIELONG_STATUS: LazyLock<RwLock<HashMap<Topic, Jielong>>> =
     LazyLock::new(Default::default);
 
 static HELP_MESSAGE: &str = concat!(
@@ -111,31 +113,24 @@ static HELP_MESSAGE: &str = concat!(
     "<code>/jielong [成语]</code>: 以你提供的成语开始成语接龙\n",
 );
 
-fn stop_jielong(chat_id: ChatId, nonce: u64) {
+fn stop_jielong(topic: Topic, nonce: u64) {
     let Ok(mut status) = CHAT_JIELONG_STATUS.write() else {
         error!("Failed to read CHAT_JIELONG_STATUS");
         return;
     };
-    if let Some(jielong) = status.get(&chat_id)
+    if let Some(jielong) = status.get(&topic)
         && jielong.nonce != nonce
     {
         return;
     }
-    if let Some(jielong) = status.remove(&chat_id) {
+    if let Some(jielong) = status.remove(&topic) {
         tokio::spawn(async move {
-            let _ = jielong
-                .ctx
-                .app
-                .bot
-                .send_message(
-                    chat_id,
-                    format!(
-                        "成语接龙结束啦！琳酱来宣布结果：\n\n{}",
-                        jielong.pretty_result()
-                    ),
-                )
-                .send()
-                .await;
+            let ctx = &jielong.ctx;
+            let request = ctx.send_message(format!(
+                "成语接龙结束啦！琳酱来宣布结果：\n\n{}",
+                jielong.pretty_result()
+            ));
+            let _ = ctx.app.outbox.send(ctx.chat_id, &request).await;
         });
     }
 }
@@ -149,7 +144,7 @@ fn try_stop_jielong_with(ctx: TaskContext, from: &User) -> Consumption {
             .warn_on_error("jielong-report-error")
             .into();
     };
-    if let Some(jielong) = status.remove(&ctx.chat_id) {
+    if let Some(jielong) = status.remove(&ctx.scope()) {
         ctx.reply(format!(
             "成语接龙被 {} 结束啦！琳酱来宣布结果：\n\n{}",
             from.full_name(),
@@ -175,7 +170,7 @@ fn try_start_jielong_with(ctx: TaskContext, init: &str) -> Consumption {
             .warn_on_error("jielong-report-error")
             .into();
     };
-    if status.get(&ctx.chat_id).is_some() {
+    if status.get(&ctx.scope()).is_some() {
         return ctx
             .reply("已经有一个接龙正在进行中啦！")
             .send()
@@ -197,11 +192,12 @@ fn try_start_jielong_with(ctx: TaskContext, init: &str) -> Consumption {
     };
     let current = jielong.idiom;
     let nonce = jielong.nonce;
-    status.insert(jielong.ctx.chat_id, jielong);
+    status.insert(jielong.ctx.scope(), jielong);
 
+    let scope = task.scope();
     tokio::spawn(async move {
         tokio::time::sleep(Duration::from_mins(10)).await;
-        stop_jielong(task.chat_id, nonce);
+        stop_jielong(scope, nonce);
     });
 
     let hint = if init.is_empty() {
@@ -228,7 +224,7 @@ fn show_jielong_status(ctx: TaskContext) -> Consumption {
             .warn_on_error("jielong-report-error")
             .into();
     };
-    if let Some(jielong) = status.get(&ctx.chat_id) {
+    if let Some(jielong) = status.get(&ctx.scope()) {
         ctx.reply(format!(
             "接龙游戏开始于：{} \n当前成语：{}，请接：{}\n\n{}",
             match SystemTime::now().duration_since(jielong.start_at) {
@@ -275,7 +271,7 @@ fn on_jielong_message(ctx: &mut Context, message: &Message) -> Consumption {
         error!("Failed to read CHAT_JIELONG_STATUS");
         return Consumption::just_next();
     };
-    let Some(record) = status.get_mut(&ctx.chat_id) else {
+    let Some(record) = status.get_mut(&ctx.scope()) else {
         return Consumption::just_next();
     };
     if msg_idiom.first != record.idiom.last {
@@ -300,27 +296,25 @@ fn on_jielong_message(ctx: &mut Context, message: &Message) -> Consumption {
         record.update_users(from);
 
         let full_name = from.full_name();
-        if combo >= 3 {
-            ctx.app
-                .bot
-                .send_message(
-                    ctx.chat_id,
-                    format!("{full_name} {combo} 连击！下一个: {}", msg_idiom.last),
-                )
-                .send()
-                .warn_on_error("check-jielong")
-                .into()
+        let request = if combo >= 3 {
+            ctx.send_message(format!(
+                "{full_name} {combo} 连击！下一个: {}",
+                msg_idiom.last
+            ))
         } else {
+            ctx.send_message(format!(
+                "接龙成功！{full_name} 分数+1。下一个: {}",
+                msg_idiom.last
+            ))
+        };
+        async move {
             ctx.app
-                .bot
-                .send_message(
-                    ctx.chat_id,
-                    format!("接龙成功！{full_name} 分数+1。下一个: {}", msg_idiom.last),
-                )
-                .send()
+                .outbox
+                .send(ctx.chat_id, &request)
                 .warn_on_error("check-jielong")
-                .into()
+                .await;
         }
+        .into()
     }
 }
 
@@ -329,6 +323,7 @@ pub static COMMAND: Module = Module {
         name: "jielong",
         description: "成语接龙",
         description_detailed: Some(HELP_MESSAGE),
+        permission: Permission::Anyone,
     }),
     task: on_jielong_command,
 };
@@ -1,16 +1,16 @@
 /// 基于 Markov Chain 的简单 AI 模块
 use std::collections::HashMap;
+use std::future::ready;
 
+use futures::StreamExt;
 use rand::{SeedableRng, rngs::SmallRng, seq::IndexedRandom};
 use serde::{Deserialize, Serialize};
-use teloxide_core::{
-    prelude::{Request, Requester},
-    types::Message,
-};
+use teloxide_core::{prelude::Request, types::Message};
 
+use crate::linquebot::db::{DataType, DbData, DbError, Scan, WarnDbError};
 use crate::{
     Consumption, Module,
-    linquebot::{ModuleDescription, ModuleKind, msg_context::TaskContext},
+    linquebot::{ModuleDescription, ModuleKind, Permission, msg_context::TaskContext},
     msg_context::Context,
     utils::telegram::prelude::WarnOnError,
 };
@@ -59,17 +59,32 @@ struct Markov {
     weight: HashMap<Gram, HashMap<char, u32>>,
 }
 
+impl DbData for Markov {
+    const KEY: &str = "markov::Markov";
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct MarkovChat {
     learn_enabled: bool,
 }
 
-async fn get_said(text: String, ctx: &TaskContext) -> String {
-    let db = ctx.app.db.of::<Markov>().get_or_insert(|| Markov {
-        weight: HashMap::new(),
-    });
+impl DbData for MarkovChat {
+    const KEY: &str = "markov::MarkovChat";
+}
+
+pub static DATA_TYPES: &[DataType] = &[DataType::of::<Markov>(), DataType::of::<MarkovChat>()];
 
-    let weight = &mut db.await.weight;
+async fn get_said(text: String, ctx: &TaskContext) -> Result<String, DbError> {
+    let mut db = ctx
+        .app
+        .db
+        .of::<Markov>()
+        .get_or_insert(|| Markov {
+            weight: HashMap::new(),
+        })
+        .await?;
+
+    let weight = &mut db.weight;
     let mut pre = ['\0'; 3];
     for (i, c) in text.chars().rev().take(3).enumerate() {
         pre[2 - i] = c;
@@ -117,12 +132,36 @@ async fn get_said(text: String, ctx: &TaskContext) -> String {
     }
 
     if res.trim().is_empty() {
-        "琳酱不知道哦".to_string()
+        Ok("琳酱不知道哦".to_string())
     } else {
-        text + &res
+        Ok(text + &res)
     }
 }
 
+/// 是否打开了语料学习。话题中没有单独的设置时使用整个群的设置
+async fn learn_enabled(ctx: &TaskContext) -> Result<bool, DbError> {
+    let (chat_id, thread_id) = ctx.scope();
+    if thread_id.is_some()
+        && let Some(stat) = ctx
+            .app
+            .db
+            .of::<MarkovChat>()
+            .topic(ctx.scope())
+            .get()
+            .await?
+    {
+        return Ok(stat.learn_enabled);
+    }
+    Ok(ctx
+        .app
+        .db
+        .of::<MarkovChat>()
+        .chat(chat_id)
+        .get()
+        .await?
+        .is_some_and(|stat| stat.learn_enabled))
+}
+
 pub fn on_message(ctx: &mut Context<'_>, msg: &Message) -> Consumption {
     const PROMPT: &str = "琳酱说说话";
     let text = msg.text()?;
@@ -132,33 +171,24 @@ pub fn on_message(ctx: &mut Context<'_>, msg: &Message) -> Consumption {
     let text = text.split_at(PROMPT.len()).1.trim().to_string();
     let ctx = ctx.task();
     async move {
-        {
-            let enabled = ctx
-                .app
-                .db
-                .of::<MarkovChat>()
-                .chat(ctx.chat_id)
-                .get_or_insert(|| MarkovChat {
-                    learn_enabled: false,
-                })
-                .await
-                .learn_enabled;
-
-            if !enabled {
+        ctx.with_db("markov", async {
+            if !learn_enabled(&ctx).await? {
                 ctx.reply("只有打开语料学习的群聊可以使用琳酱说说话功能哦")
                     .send()
                     .warn_on_error("markov")
                     .await;
-                return;
+                return Ok(());
             }
-        }
 
-        ctx.app
-            .bot
-            .send_message(ctx.chat_id, get_said(text, &ctx).await)
-            .send()
-            .warn_on_error("markov")
-            .await;
+            let request = ctx.send_message(get_said(text, &ctx).await?);
+            ctx.app
+                .outbox
+                .send(ctx.chat_id, &request)
+                .warn_on_error("markov")
+                .await;
+            Ok(())
+        })
+        .await
     }
     .into()
 }
@@ -166,26 +196,53 @@ pub fn on_message(ctx: &mut Context<'_>, msg: &Message) -> Consumption {
 pub fn toggle_learn(ctx: &mut Context, _: &Message) -> Consumption {
     let ctx = ctx.task();
     async move {
-        let mut stat = ctx
+        // 话题中第一次设置时从整个群的设置开始切换
+        let inherited = match learn_enabled(&ctx).await {
+            Ok(enabled) => enabled,
+            Err(err) => return ctx.reply_db_error("toggle_markov", err).await,
+        };
+        let mut stat = match ctx
             .app
             .db
             .of::<MarkovChat>()
-            .chat(ctx.chat_id)
+            .topic(ctx.scope())
             .get_or_insert(|| MarkovChat {
-                learn_enabled: false,
+                learn_enabled: inherited,
             })
-            .await;
+            .await
+        {
+            Ok(stat) => stat,
+            Err(err) => return ctx.reply_db_error("toggle_markov", err).await,
+        };
 
         stat.learn_enabled = !stat.learn_enabled;
+        let enabled = stat.learn_enabled;
+        drop(stat);
 
-        if stat.learn_enabled {
-            ctx.reply("语料学习已打开")
+        let mut text = if enabled {
+            "语料学习已打开".to_string()
         } else {
-            ctx.reply("语料学习已关闭")
+            "语料学习已关闭".to_string()
+        };
+        // 在整个群中切换时，提醒有单独设置的话题不受影响
+        let (chat_id, thread_id) = ctx.scope();
+        if thread_id.is_none() {
+            let topics = ctx
+                .app
+                .db
+                .scan::<MarkovChat>(Scan::Chat(chat_id))
+                .filter(|row| {
+                    ready(row.as_ref().is_ok_and(|(id, stat)| {
+                        id.thread.is_some() && stat.learn_enabled != enabled
+                    }))
+                })
+                .count()
+                .await;
+            if topics > 0 {
+                text += &format!("，但是有 {topics} 个话题单独设置过，不受影响");
+            }
         }
-        .send()
-        .warn_on_error("toggle_markov")
-        .await;
+        ctx.reply(text).send().warn_on_error("toggle_markov").await;
     }
     .into()
 }
@@ -202,26 +259,18 @@ pub fn train_data(ctx: &mut Context<'_>, msg: &Message) -> Consumption {
     }
 
     let text = text.to_string();
-
-    let stat = ctx
-        .app
-        .db
-        .of::<MarkovChat>()
-        .chat(ctx.chat_id)
-        .get_or_insert(|| MarkovChat {
-            learn_enabled: false,
-        });
-
-    let db = ctx.app.db.of::<Markov>().get_or_insert(|| Markov {
-        weight: HashMap::new(),
-    });
+    let ctx = ctx.task();
 
     Consumption::next_with(async move {
-        let mut db = db.await;
-        let stat = stat.await;
-        if !stat.learn_enabled {
+        if learn_enabled(&ctx).await.ok_or_warn("markov") != Some(true) {
             return;
         }
+        let db = ctx.app.db.of::<Markov>().get_or_insert(|| Markov {
+            weight: HashMap::new(),
+        });
+        let Some(mut db) = db.await.ok_or_warn("markov") else {
+            return;
+        };
         let mut pre = Gram::default();
         let weight = &mut db.weight;
         for ch in text.chars() {
@@ -249,8 +298,10 @@ pub static GEN_CTNT: Module = Module {
             "直接说琳酱说说话来让琳酱随便说话, ",
             "<code>琳酱说说话 [一句话]</code>让琳酱接话.\n\n",
             "琳酱会从所有聊天记录里训练, 不会保存具体的聊天语料.\n",
-            "使用 <code>/toggle_markov</code> 打开/关闭本聊天的语料学习功能。"
+            "使用 <code>/toggle_markov</code> 打开/关闭本聊天的语料学习功能。",
+            "设置了 PER_TOPIC_STATE 时论坛中每个话题可以分别设置，没有单独设置的话题使用整个群的设置。"
         )),
+        permission: Permission::Anyone,
     })),
     task: on_message,
 };
@@ -260,6 +311,7 @@ pub static TOGGLE: Module = Module {
         name: "toggle_markov",
         description: "打开/关闭<b>琳酱说说话</b>模块的学习功能",
         description_detailed: None,
+        permission: Permission::ChatAdmin,
     }),
     task: toggle_learn,
 };
@@ -1,23 +1,33 @@
-use crate::{linquebot::Module, MicroTask};
+use crate::{
+    MicroTask,
+    linquebot::{Module, backlog, chat_modules, db::DataType, scheduler},
+};
 
 pub mod answer_book;
+pub mod antiflood;
 pub mod bestapo;
 pub mod bot_on_off;
+pub mod captcha;
 pub mod debuger;
 pub mod dice;
 #[cfg(feature = "explain")]
 pub mod explain;
+pub mod forget;
 pub mod greetings;
 pub mod help;
 pub mod hitokoto;
+pub mod inline;
 #[cfg(feature = "jielong")]
 pub mod jielong;
 #[cfg(feature = "lm")]
 pub mod lm;
 pub mod markov;
+pub mod moderation;
+pub mod modules;
 pub mod rand;
 pub mod repeater;
 pub mod rong;
+pub mod roster;
 pub mod say;
 pub mod search;
 pub mod set_title;
@@ -28,6 +38,7 @@ pub mod tarot_ai;
 pub mod todo;
 pub mod tools;
 pub mod waife;
+pub mod welcome;
 
 /// Module Handles 的顺序很重要  
 /// 请确保这些函数是拓扑排序的
@@ -35,12 +46,16 @@ pub static MODULES: &[&Module] = &[
     // --- super commands ---
     &help::MODULE,
     &help::SAY_HI,
+    &modules::MODULE,
     &bot_on_off::BOT_ON_MODULE,
     &bot_on_off::BOT_OFF_MODULE,
     &bot_on_off::STOP_WHEN_BOT_OFF,
-    &waife::ADD_USER,
+    &roster::RECORDER,
+    &antiflood::MODULE,
     // --- normal commands ---
     &debuger::DEBUGGER,
+    &debuger::QUARANTINE,
+    &forget::MODULE,
     &todo::MODULE,
     &hitokoto::MODULE,
     &answer_book::MODULE,
@@ -49,6 +64,16 @@ pub static MODULES: &[&Module] = &[
     &repeater::TOGGLE,
     &markov::TOGGLE,
     &bestapo::TOGGLE,
+    &bestapo::COMMAND,
+    &bestapo::SPAM,
+    &bestapo::HAM,
+    &moderation::WARN,
+    &moderation::MUTE,
+    &moderation::BAN,
+    &moderation::UNBAN,
+    &moderation::MODLOG,
+    &moderation::MODPOLICY,
+    &antiflood::COMMAND,
     &search::TOGGLE_SEARCH,
     &search::TOGGLE_SEARCH_RECORDING,
     &search::SEARCH,
@@ -68,6 +93,8 @@ pub static MODULES: &[&Module] = &[
     &waife::SET_WAIFE_LIMIT,
     &waife::WAIFE_GRAPH,
     &greetings::TOGGLE,
+    &welcome::MODULE,
+    &captcha::MODULE,
     // --- special command: rongslashbot ---
     &rong::MODULE,
     // --- normal message handles ---
@@ -81,4 +108,72 @@ pub static MODULES: &[&Module] = &[
     &search::RECORDER,
 ];
 
-pub static MICRO_TASKS: &[&MicroTask] = &[&help::HELP_CALLBACK, &set_title::ADMIN_CALLBACK];
+/// 也会处理被编辑的消息的模块，它们的回复会被编辑而不是重新发送
+pub static EDIT_MODULES: &[&Module] = &[
+    &bot_on_off::STOP_WHEN_BOT_OFF,
+    &search::SEARCH,
+    &rand::MODULE,
+    &tools::MODULE,
+    &dice::MODULE,
+    #[cfg(feature = "explain")]
+    &explain::MODULE,
+    &bestapo::MESSAGE_HANDLER,
+    &search::RECORDER,
+];
+
+pub static MICRO_TASKS: &[&MicroTask] = &[
+    &help::HELP_CALLBACK,
+    &set_title::ADMIN_CALLBACK,
+    &captcha::ON_CALLBACK,
+    &todo::ON_JOB,
+    &captcha::ON_TIMEOUT,
+    &bot_on_off::ON_WAKE_UP,
+    &roster::ON_CHAT_MEMBER,
+    &captcha::ON_CHAT_MEMBER,
+    &welcome::ON_CHAT_MEMBER,
+    &inline::INLINE_QUERY,
+];
+
+/// 保存在数据库中的类型，启动时用来检查数据库，[forget] 用来删除用户的数据
+pub static DATA_TYPES: &[&[DataType]] = &[
+    backlog::DATA_TYPES,
+    chat_modules::DATA_TYPES,
+    scheduler::DATA_TYPES,
+    antiflood::DATA_TYPES,
+    bestapo::DATA_TYPES,
+    bot_on_off::DATA_TYPES,
+    captcha::DATA_TYPES,
+    greetings::DATA_TYPES,
+    markov::DATA_TYPES,
+    moderation::DATA_TYPES,
+    roster::DATA_TYPES,
+    search::DATA_TYPES,
+    todo::DATA_TYPES,
+    waife::DATA_TYPES,
+    welcome::DATA_TYPES,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(modules: &[&Module], module: &Module) -> Option<usize> {
+        modules.iter().position(|m| std::ptr::eq(*m, module))
+    }
+
+    #[test]
+    fn edit_modules_follow_modules() {
+        let positions = EDIT_MODULES
+            .iter()
+            .map(|module| position(MODULES, module))
+            .collect::<Option<Vec<_>>>()
+            .expect("every edit module should be in MODULES");
+        assert!(positions.is_sorted());
+    }
+
+    #[test]
+    fn edited_messages_are_censored() {
+        // 发出后再编辑成斯帕姆的消息也要检查
+        assert!(position(EDIT_MODULES, &bestapo::MESSAGE_HANDLER).is_some());
+    }
+}
@@ -0,0 +1,644 @@
+//! 群管理
+//! ```text
+//! /warn [原因]
+//! /mute <时长> [原因]
+//! /ban [原因]
+//! /unban
+//! /modlog [条数]
+//! /modpolicy [warns <次数>] [steps <1d,ban>] [expire <时长>]
+//! ```
+//! 回复一条消息使用，对消息的发送者生效。
+//!
+//! 警告次数达到上限后，按照本群的升级策略依次禁言或封禁，警告和升级记录会在一段时间后过期。
+//! 所有操作（包括北世太保的自动删除）都会记录在 `/modlog` 中。
+
+use std::collections::{HashMap, VecDeque};
+use std::ops::RangeInclusive;
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Local, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use teloxide_core::prelude::*;
+use teloxide_core::types::*;
+
+use crate::Consumption;
+use crate::linquebot::db::{DataType, DbData, DbError, WarnDbError};
+use crate::linquebot::msg_context::{Context, TaskContext};
+use crate::linquebot::*;
+use crate::utils::split_args;
+use crate::utils::telegram::prelude::*;
+use crate::utils::time::{describe_duration, duration, parse_duration};
+
+const LOG_SIZE: usize = 200;
+
+/// Telegram 把少于 30 秒或多于 366 天的禁言视为永久禁言
+pub const MUTE_RANGE: RangeInclusive<Duration> =
+    Duration::from_secs(30)..=Duration::from_secs(366 * 86400);
+
+/// 限时禁言的时长超出范围时的提示
+pub const MUTE_RANGE_HINT: &str = "禁言时长需要在 30 秒到 366 天之间";
+
+/// 执行操作的人
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Actor {
+    Bot,
+    Admin { id: UserId, full_name: String },
+}
+
+impl Actor {
+    pub fn admin(user: &User) -> Self {
+        Actor::Admin {
+            id: user.id,
+            full_name: user.full_name(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ModAction {
+    Warn {
+        count: u32,
+        limit: u32,
+    },
+    /// 为 None 时是永久禁言
+    Mute {
+        duration: Option<Duration>,
+    },
+    Ban,
+    Unban,
+    Delete,
+}
+
+impl ModAction {
+    fn describe(&self) -> String {
+        match self {
+            ModAction::Warn { count, limit } => format!("警告（{count}/{limit}）"),
+            ModAction::Mute {
+                duration: Some(duration),
+            } => format!("禁言 {}", describe_duration(*duration)),
+            ModAction::Mute { duration: None } => "禁言".to_string(),
+            ModAction::Ban => "封禁".to_string(),
+            ModAction::Unban => "解封".to_string(),
+            ModAction::Delete => "删除消息".to_string(),
+        }
+    }
+}
+
+/// 管理日志中的一条记录
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub at: SystemTime,
+    pub actor: Actor,
+    pub target_id: Option<UserId>,
+    pub target_name: String,
+    pub action: ModAction,
+    pub reason: String,
+}
+
+impl LogEntry {
+    pub fn new(actor: Actor, target: &User, action: ModAction, reason: impl Into<String>) -> Self {
+        Self {
+            at: SystemTime::now(),
+            actor,
+            target_id: Some(target.id),
+            target_name: target.full_name(),
+            action,
+            reason: reason.into(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ModLog {
+    entries: VecDeque<LogEntry>,
+}
+
+impl DbData for ModLog {
+    const KEY: &str = "moderation::ModLog";
+}
+
+/// 写入管理日志
+pub async fn record(app: &'static App, chat_id: ChatId, entry: LogEntry) {
+    info!(target: "moderation", "{chat_id}: {entry:?}");
+    let Some(mut log) = app
+        .db
+        .of::<ModLog>()
+        .chat(chat_id)
+        .get_or_insert(Default::default)
+        .await
+        .ok_or_warn("moderation")
+    else {
+        return;
+    };
+    log.entries.push_back(entry);
+    while log.entries.len() > LOG_SIZE {
+        log.entries.pop_front();
+    }
+}
+
+/// 升级策略的一步
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum Step {
+    Mute(Duration),
+    Ban,
+}
+
+impl Step {
+    fn parse(src: &str) -> Option<Self> {
+        match src {
+            "ban" => Some(Step::Ban),
+            src => parse_duration(src)
+                .filter(|duration| MUTE_RANGE.contains(duration))
+                .map(Step::Mute),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Step::Mute(duration) => format!("禁言 {}", describe_duration(*duration)),
+            Step::Ban => "封禁".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Policy {
+    warn_limit: u32,
+    /// 第 n 次达到警告上限时执行第 n 步，超出后一直执行最后一步
+    steps: Vec<Step>,
+    /// 警告和升级记录的有效期
+    expire: Duration,
+}
+
+impl DbData for Policy {
+    const KEY: &str = "moderation::Policy";
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            warn_limit: 3,
+            steps: vec![Step::Mute(Duration::from_secs(86400)), Step::Ban],
+            expire: Duration::from_secs(7 * 86400),
+        }
+    }
+}
+
+impl Policy {
+    fn describe(&self) -> String {
+        format!(
+            "警告 {} 次后依次：{}\n警告和升级记录 {} 后过期",
+            self.warn_limit,
+            self.steps
+                .iter()
+                .map(Step::describe)
+                .collect::<Vec<_>>()
+                .join(" → "),
+            describe_duration(self.expire)
+        )
+    }
+
+    /// 解析 `warns 3 steps 1d,ban expire 7d`，只修改提到的部分，有错误时不做任何修改
+    fn update(&mut self, mut args: &str) -> Option<()> {
+        let mut policy = self.clone();
+        while !args.is_empty() {
+            let [key, value, rest] = split_args(args);
+            match key {
+                "warns" => policy.warn_limit = value.parse().ok().filter(|limit| *limit > 0)?,
+                "steps" => {
+                    policy.steps = value
+                        .split(',')
+                        .map(Step::parse)
+                        .collect::<Option<Vec<_>>>()?;
+                }
+                "expire" => {
+                    policy.expire =
+                        parse_duration(value).filter(|expire| MUTE_RANGE.contains(expire))?
+                }
+                _ => return None,
+            }
+            args = rest;
+        }
+        *self = policy;
+        Some(())
+    }
+}
+
+/// 一个成员的警告记录
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Strikes {
+    warns: Vec<SystemTime>,
+    escalations: Vec<SystemTime>,
+}
+
+impl Strikes {
+    /// 记一次警告，返回当前的警告次数，以及达到上限时要执行的一步
+    fn warn(&mut self, policy: &Policy, now: SystemTime) -> (u32, Option<Step>) {
+        let valid = |time: &SystemTime| {
+            now.duration_since(*time)
+                .is_ok_and(|elapsed| elapsed < policy.expire)
+        };
+        self.warns.retain(valid);
+        self.escalations.retain(valid);
+        self.warns.push(now);
+        let count = self.warns.len() as u32;
+        if count < policy.warn_limit {
+            return (count, None);
+        }
+        self.warns.clear();
+        let step = policy
+            .steps
+            .get(self.escalations.len())
+            .or(policy.steps.last())
+            .copied();
+        self.escalations.push(now);
+        (count, step)
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Warnings {
+    users: HashMap<UserId, Strikes>,
+}
+
+impl DbData for Warnings {
+    const KEY: &str = "moderation::Warnings";
+}
+
+pub static DATA_TYPES: &[DataType] = &[
+    DataType::of::<ModLog>(),
+    DataType::of::<Policy>(),
+    DataType::of::<Warnings>(),
+];
+
+/// 恢复成员在群里的默认权限
+pub async fn lift_restriction(app: &'static App, chat_id: ChatId, user_id: UserId) {
+    let permissions = match app.bot.get_chat(chat_id).send().await {
+        Ok(chat) => chat.permissions().unwrap_or(ChatPermissions::all()),
+        Err(err) => {
+            warn!(target: "moderation", "failed to get permissions of {chat_id}: {err}");
+            ChatPermissions::all()
+        }
+    };
+    app.bot
+        .restrict_chat_member(chat_id, user_id, permissions)
+        .send()
+        .warn_on_error("moderation-lift")
+        .await;
+}
+
+/// 执行操作并写入日志，返回给管理员的回复
+pub async fn execute(
+    ctx: &TaskContext,
+    actor: Actor,
+    target: &User,
+    action: ModAction,
+    reason: &str,
+) -> String {
+    let (app, chat_id) = (ctx.app, ctx.chat_id);
+    let res = match &action {
+        ModAction::Warn { .. } | ModAction::Delete => Ok(()),
+        ModAction::Mute { duration } => {
+            let request =
+                app.bot
+                    .restrict_chat_member(chat_id, target.id, ChatPermissions::empty());
+            let request = match duration {
+                Some(duration) => {
+                    let until = Some(duration)
+                        .filter(|duration| MUTE_RANGE.contains(duration))
+                        .and_then(|duration| chrono::Duration::from_std(*duration).ok())
+                        .and_then(|duration| Utc::now().checked_add_signed(duration));
+                    let Some(until) = until else {
+                        return MUTE_RANGE_HINT.to_string();
+                    };
+                    request.until_date(until)
+                }
+                None => request,
+            };
+            request.send().await.map(|_| ())
+        }
+        ModAction::Ban => app
+            .bot
+            .ban_chat_member(chat_id, target.id)
+            .send()
+            .await
+            .map(|_| ()),
+        ModAction::Unban => {
+            let res = app
+                .bot
+                .unban_chat_member(chat_id, target.id)
+                .only_if_banned(true)
+                .send()
+                .await
+                .map(|_| ());
+            if res.is_ok() {
+                lift_restriction(app, chat_id, target.id).await;
+            }
+            res
+        }
+    };
+    if let Err(err) = res {
+        warn!(target: "moderation", "failed to {action:?} {}: {err}", target.id);
+        return format!("{}失败：{err}", action.describe());
+    }
+    let text = format!("已对 {} {}", target.full_name(), action.describe());
+    record(app, chat_id, LogEntry::new(actor, target, action, reason)).await;
+    text
+}
+
+/// 被回复的消息的发送者
+fn target_of(msg: &Message) -> Option<&User> {
+    let target = msg.reply_to_message()?;
+    if target.sender_chat.is_some() {
+        return None;
+    }
+    target.from.as_ref()
+}
+
+/// 检查目标并执行，`run` 得到操作者、目标、命令参数
+fn moderate<F, Fut>(ctx: &mut Context, msg: &Message, run: F) -> Consumption
+where
+    F: FnOnce(TaskContext, Actor, User, String) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<String, DbError>> + Send + 'static,
+{
+    let Some(target) = target_of(msg).cloned() else {
+        return ctx
+            .task()
+            .reply("请回复要处理的成员的消息")
+            .send()
+            .warn_on_error("moderation")
+            .into();
+    };
+    let actor = Actor::admin(msg.from.as_ref()?);
+    let args = ctx.cmd?.content.trim().to_string();
+    let ctx = ctx.task();
+    let app = ctx.app;
+    async move {
+        ctx.with_db("moderation", async {
+            let text = if target.id == app.bot_id {
+                "琳酱不能对自己这么做哦".to_string()
+            } else if app
+                .permissions
+                .is_chat_admin(&app.bot, ctx.chat_id, target.id)
+                .await
+            {
+                "不能对管理员这么做哦".to_string()
+            } else {
+                run(ctx.clone(), actor, target, args).await?
+            };
+            ctx.reply(text).send().warn_on_error("moderation").await;
+            Ok(())
+        })
+        .await
+    }
+    .into()
+}
+
+fn on_warn(ctx: &mut Context, msg: &Message) -> Consumption {
+    moderate(ctx, msg, |ctx, actor, target, reason| async move {
+        let policy = match ctx.app.db.of::<Policy>().chat(ctx.chat_id).get().await? {
+            Some(policy) => policy.clone(),
+            None => Policy::default(),
+        };
+        let (count, step) = ctx
+            .app
+            .db
+            .of::<Warnings>()
+            .chat(ctx.chat_id)
+            .get_or_insert(Default::default)
+            .await?
+            .users
+            .entry(target.id)
+            .or_default()
+            .warn(&policy, SystemTime::now());
+        let action = ModAction::Warn {
+            count,
+            limit: policy.warn_limit,
+        };
+        let mut text = execute(&ctx, actor.clone(), &target, action, &reason).await;
+        if let Some(step) = step {
+            let action = match step {
+                Step::Mute(duration) => ModAction::Mute {
+                    duration: Some(duration),
+                },
+                Step::Ban => ModAction::Ban,
+            };
+            let reason = format!("警告达到 {} 次", policy.warn_limit);
+            text.push('\n');
+            text.push_str(&execute(&ctx, actor, &target, action, &reason).await);
+        }
+        Ok(text)
+    })
+}
+
+fn on_mute(ctx: &mut Context, msg: &Message) -> Consumption {
+    moderate(ctx, msg, |ctx, actor, target, args| async move {
+        let (duration, reason) = match duration(&args) {
+            Some((duration, reason)) => (Some(duration), reason.trim()),
+            None => (None, args.as_str()),
+        };
+        Ok(execute(&ctx, actor, &target, ModAction::Mute { duration }, reason).await)
+    })
+}
+
+fn on_ban(ctx: &mut Context, msg: &Message) -> Consumption {
+    moderate(ctx, msg, |ctx, actor, target, reason| async move {
+        Ok(execute(&ctx, actor, &target, ModAction::Ban, &reason).await)
+    })
+}
+
+fn on_unban(ctx: &mut Context, msg: &Message) -> Consumption {
+    moderate(ctx, msg, |ctx, actor, target, reason| async move {
+        if let Some(mut warnings) = ctx.app.db.of::<Warnings>().chat(ctx.chat_id).get().await? {
+            warnings.users.remove(&target.id);
+        }
+        Ok(execute(&ctx, actor, &target, ModAction::Unban, &reason).await)
+    })
+}
+
+fn format_entry(entry: &LogEntry) -> String {
+    let actor = match &entry.actor {
+        Actor::Bot => "琳酱",
+        Actor::Admin { full_name, .. } => full_name,
+    };
+    let mut line = format!(
+        "{} {actor} 对 {} {}",
+        DateTime::<Local>::from(entry.at).format("%m-%d %H:%M"),
+        entry.target_name,
+        entry.action.describe()
+    );
+    if !entry.reason.is_empty() {
+        line.push_str(&format!("：{}", entry.reason));
+    }
+    line
+}
+
+fn on_modlog(ctx: &mut Context, msg: &Message) -> Consumption {
+    let num = ctx.cmd?.content.trim().parse().unwrap_or(10).clamp(1, 50);
+    let target = target_of(msg).map(|user| user.id);
+    let ctx = ctx.task();
+    async move {
+        let text = match ctx.app.db.of::<ModLog>().chat(ctx.chat_id).get().await {
+            Ok(Some(log)) => log
+                .entries
+                .iter()
+                .rev()
+                .filter(|entry| target.is_none() || entry.target_id == target)
+                .take(num)
+                .map(format_entry)
+                .collect::<Vec<_>>()
+                .join("\n"),
+            Ok(None) => String::new(),
+            Err(err) => return ctx.reply_db_error("modlog", err).await,
+        };
+        let text = if text.is_empty() {
+            "还没有管理记录".to_string()
+        } else {
+            text
+        };
+        ctx.reply(text).send().warn_on_error("modlog").await;
+    }
+    .into()
+}
+
+fn on_modpolicy(ctx: &mut Context, _: &Message) -> Consumption {
+    let args = ctx.cmd?.content.trim().to_string();
+    let ctx = ctx.task();
+    async move {
+        let mut policy = match ctx
+            .app
+            .db
+            .of::<Policy>()
+            .chat(ctx.chat_id)
+            .get_or_insert(Default::default)
+            .await
+        {
+            Ok(policy) => policy,
+            Err(err) => return ctx.reply_db_error("modpolicy", err).await,
+        };
+        let text = match policy.update(&args) {
+            Some(()) => policy.describe(),
+            None => concat!(
+                "用法：/modpolicy [warns <次数>] [steps <1d,ban>] [expire <时长>]\n",
+                "禁言和过期时长需要在 30 秒到 366 天之间"
+            )
+            .to_string(),
+        };
+        drop(policy);
+        ctx.reply(text).send().warn_on_error("modpolicy").await;
+    }
+    .into()
+}
+
+pub static WARN: Module = Module {
+    kind: ModuleKind::Command(ModuleDescription {
+        name: "warn",
+        description: "警告回复的成员",
+        description_detailed: Some("/warn [原因]\n警告达到上限后会按 /modpolicy 自动禁言或封禁"),
+        permission: Permission::ChatAdmin,
+    }),
+    task: on_warn,
+};
+
+pub static MUTE: Module = Module {
+    kind: ModuleKind::Command(ModuleDescription {
+        name: "mute",
+        description: "禁言回复的成员",
+        description_detailed: Some(
+            "/mute [时长] [原因]\n不写时长时永久禁言，时长需要在 30 秒到 366 天之间",
+        ),
+        permission: Permission::ChatAdmin,
+    }),
+    task: on_mute,
+};
+
+pub static BAN: Module = Module {
+    kind: ModuleKind::Command(ModuleDescription {
+        name: "ban",
+        description: "封禁回复的成员",
+        description_detailed: Some("/ban [原因]"),
+        permission: Permission::ChatAdmin,
+    }),
+    task: on_ban,
+};
+
+pub static UNBAN: Module = Module {
+    kind: ModuleKind::Command(ModuleDescription {
+        name: "unban",
+        description: "解封回复的成员",
+        description_detailed: Some("解除封禁和禁言，并清空警告记录"),
+        permission: Permission::ChatAdmin,
+    }),
+    task: on_unban,
+};
+
+pub static MODLOG: Module = Module {
+    kind: ModuleKind::Command(ModuleDescription {
+        name: "modlog",
+        description: "查看管理记录",
+        description_detailed: Some("/modlog [条数]\n回复一条消息时只显示对发送者的记录"),
+        permission: Permission::ChatAdmin,
+    }),
+    task: on_modlog,
+};
+
+pub static MODPOLICY: Module = Module {
+    kind: ModuleKind::Command(ModuleDescription {
+        name: "modpolicy",
+        description: "设置警告的升级策略",
+        description_detailed: Some(concat!(
+            "/modpolicy warns 3 steps 1d,ban expire 7d\n",
+            "警告 3 次后禁言 1 天，再警告 3 次后封禁，警告和升级记录 7 天后过期\n",
+            "不加参数时显示当前策略"
+        )),
+        permission: Permission::ChatAdmin,
+    }),
+    task: on_modpolicy,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escalation() {
+        let policy = Policy::default();
+        let mut strikes = Strikes::default();
+        let day = Duration::from_secs(86400);
+        let start = SystemTime::UNIX_EPOCH + day * 100;
+        assert_eq!(strikes.warn(&policy, start), (1, None));
+        assert_eq!(strikes.warn(&policy, start), (2, None));
+        assert_eq!(strikes.warn(&policy, start), (3, Some(Step::Mute(day))));
+        assert_eq!(strikes.warn(&policy, start + day), (1, None));
+        // 过期的警告不算
+        assert_eq!(strikes.warn(&policy, start + day * 9), (1, None));
+        assert_eq!(strikes.warn(&policy, start + day * 9), (2, None));
+        assert_eq!(
+            strikes.warn(&policy, start + day * 9),
+            (3, Some(Step::Mute(day)))
+        );
+        assert_eq!(strikes.warn(&policy, start + day * 10), (1, None));
+        assert_eq!(strikes.warn(&policy, start + day * 10), (2, None));
+        assert_eq!(
+            strikes.warn(&policy, start + day * 10),
+            (3, Some(Step::Ban))
+        );
+    }
+
+    #[test]
+    fn update_policy() {
+        let mut policy = Policy::default();
+        assert!(policy.update("warns 2 steps 1h,1d,ban").is_some());
+        assert_eq!(policy.warn_limit, 2);
+        assert_eq!(policy.steps.len(), 3);
+        assert!(policy.update("warns 0").is_none());
+        assert!(policy.update("steps 1h,forever").is_none());
+        assert!(policy.update("steps 10s").is_none());
+        assert!(policy.update("steps 400d").is_none());
+        // 有错误时不做任何修改
+        assert!(policy.update("warns 5 expire 1000d").is_none());
+        assert_eq!(policy.warn_limit, 2);
+        assert_eq!(policy.expire, Duration::from_secs(7 * 86400));
+    }
+}
@@ -0,0 +1,151 @@
+//! 模块开关
+//! ```text
+//! /modules list
+//! /modules enable <name>
+//! /modules disable <name>
+//! ```
+
+use msg_context::{Context, TaskContext};
+use teloxide_core::prelude::*;
+use teloxide_core::types::*;
+
+use crate::Consumption;
+use crate::chat_modules::{self, ChatModules};
+use crate::linquebot::*;
+use crate::utils::markup::code;
+use crate::utils::split_args;
+use crate::utils::telegram::prelude::WarnOnError;
+
+static HELP_MESSAGE: &str = concat!(
+    "<code>/modules list</code>: 列出本聊天的模块开关\n",
+    "<code>/modules enable [模块名]</code>: 打开模块\n",
+    "<code>/modules disable [模块名]</code>: 关闭模块\n",
+    "只有管理员可以开关模块，关闭的模块不会响应消息，也不会显示在帮助和命令菜单中。",
+);
+
+async fn list_modules(ctx: TaskContext) {
+    let chat_modules = match ChatModules::load(ctx.app, ctx.chat_id).await {
+        Ok(chat_modules) => chat_modules,
+        Err(err) => return ctx.reply_db_error("modules", err).await,
+    };
+    let list = ctx
+        .app
+        .modules
+        .iter()
+        .filter(|module| chat_modules::is_toggleable(module))
+        .filter_map(|module| module.name())
+        .map(|name| {
+            let status = if chat_modules.is_enabled_name(name) {
+                "✅"
+            } else {
+                "❌"
+            };
+            format!("{status} {}", code(name).html())
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    ctx.reply_html(format!("本聊天的模块：\n{list}"))
+        .send()
+        .warn_on_error("modules")
+        .await;
+}
+
+async fn switch_module(ctx: TaskContext, message: Message, name: String, enable: bool) {
+    let permissions = &ctx.app.permissions;
+    if !permissions
+        .check(&ctx.app.bot, &message, Permission::ChatAdmin)
+        .await
+    {
+        ctx.reply_html(Permission::ChatAdmin.refusal())
+            .send()
+            .warn_on_error("modules")
+            .await;
+        return;
+    }
+
+    let Some(module) = ctx
+        .app
+        .modules
+        .iter()
+        .find(|module| module.name() == Some(name.as_str()))
+    else {
+        ctx.reply(format!("没有叫 {name} 的模块哦"))
+            .send()
+            .warn_on_error("modules")
+            .await;
+        return;
+    };
+    if !chat_modules::is_toggleable(module) {
+        ctx.reply(format!("{name} 模块不能被关闭"))
+            .send()
+            .warn_on_error("modules")
+            .await;
+        return;
+    }
+
+    let chat_modules = {
+        let chat_modules = ctx
+            .app
+            .db
+            .of::<ChatModules>()
+            .chat(ctx.chat_id)
+            .get_or_insert(Default::default)
+            .await;
+        let mut chat_modules = match chat_modules {
+            Ok(chat_modules) => chat_modules,
+            Err(err) => return ctx.reply_db_error("modules", err).await,
+        };
+        let changed = if enable {
+            chat_modules.enable(&name)
+        } else {
+            chat_modules.disable(&name)
+        };
+        if !changed {
+            let status = if enable { "打开" } else { "关闭" };
+            ctx.reply(format!("{name} 模块已经是{status}状态了"))
+                .send()
+                .warn_on_error("modules")
+                .await;
+            return;
+        }
+        chat_modules.clone()
+    };
+
+    chat_modules::sync_chat_commands(ctx.app, ctx.chat_id, &chat_modules)
+        .warn_on_error("modules-sync-commands")
+        .await;
+
+    let status = if enable { "打开" } else { "关闭" };
+    ctx.reply(format!("已{status} {name} 模块"))
+        .send()
+        .warn_on_error("modules")
+        .await;
+}
+
+fn on_modules(ctx: &mut Context, message: &Message) -> Consumption {
+    let [action, name] = split_args::<2>(ctx.cmd?.content);
+    let message = message.clone();
+    let name = name.to_string();
+    let ctx = ctx.task();
+    match action {
+        "" | "list" => list_modules(ctx).into(),
+        "enable" | "disable" if !name.is_empty() => {
+            switch_module(ctx, message, name, action == "enable").into()
+        }
+        _ => ctx
+            .reply_html(HELP_MESSAGE)
+            .send()
+            .warn_on_error("modules")
+            .into(),
+    }
+}
+
+pub static MODULE: Module = Module {
+    kind: ModuleKind::Command(ModuleDescription {
+        name: "modules",
+        description: "开关本聊天的模块",
+        description_detailed: Some(HELP_MESSAGE),
+        permission: Permission::Anyone,
+    }),
+    task: on_modules,
+};
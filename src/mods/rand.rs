@@ -1,7 +1,6 @@
 /// 随机选择器
 use log::warn;
 use msg_context::Context;
-use msg_context::TaskContext;
 use rand::Rng;
 use rand::seq::SliceRandom;
 use teloxide_core::prelude::*;
@@ -9,26 +8,23 @@ use teloxide_core::types::*;
 
 use crate::Consumption;
 use crate::linquebot::*;
-use crate::utils::telegram::prelude::*;
-use crate::utils::*;
+use crate::utils::markup::{mention, text};
 
-async fn send_raw_rand(ctx: TaskContext, from: User, text_body: String) {
-    let result = rand::rng().random_range(0..=100);
-    let msg = format!(
-        "{} {}",
-        from.html_link(),
-        if text_body.trim().is_empty() {
-            format!("掷出了: {result}")
-        } else {
-            format!("{} 的概率是: {result}%", escape_html(text_body.trim()))
-        }
-    );
-    if let Err(err) = ctx.reply_html(&msg).send().await {
-        warn!("Failed to send reply: {}", err);
+/// 随机一下，返回 HTML 格式的结果
+pub fn rand_result(from: &User, text_body: &str) -> String {
+    if text_body.contains("还是") {
+        return selective_rand(text_body, "还是");
     }
+    let result = rand::rng().random_range(0..=100);
+    let result = if text_body.trim().is_empty() {
+        format!(" 掷出了: {result}")
+    } else {
+        format!(" {} 的概率是: {result}%", text_body.trim())
+    };
+    (mention(from) + result).html()
 }
 
-async fn send_selective_rand(ctx: TaskContext, text_body: String, spliter: &str) {
+fn selective_rand(text_body: &str, spliter: &str) -> String {
     let mut result = text_body
         .split(&spliter)
         .map(|str| str.trim())
@@ -39,26 +35,23 @@ async fn send_selective_rand(ctx: TaskContext, text_body: String, spliter: &str)
 
     let result = result.first().unwrap_or(&spliter);
 
-    if let Err(err) = ctx
-        .reply_html(format!("{}!", escape_html(result)))
-        .send()
-        .await
-    {
-        warn!("Failed to send reply: {}", err);
-    }
+    text(format!("{result}!")).html()
 }
 
 pub fn on_message(ctx: &mut Context, message: &Message) -> Consumption {
-    let text = ctx.cmd?.content.to_string();
-    let Some(from) = message.from.clone() else {
+    let text = ctx.cmd?.content;
+    let Some(from) = message.from.as_ref() else {
         warn!("No reply target.");
         return Consumption::just_stop();
     };
-    if text.contains("还是") {
-        send_selective_rand(ctx.task(), text, "还是").into()
-    } else {
-        send_raw_rand(ctx.task(), from, text).into()
+    let msg = rand_result(from, text);
+    let ctx = ctx.task();
+    async move {
+        if let Err(err) = ctx.reply_html(&msg).send().await {
+            warn!("Failed to send reply: {}", err);
+        }
     }
+    .into()
 }
 
 pub static MODULE: Module = Module {
@@ -71,6 +64,7 @@ pub static MODULE: Module = Module {
             "如果事件被省略，则会回复“掷出了 {rand}”\n\n",
             "特殊关键字：当发送 <code>/rand A还是B</code> 的时候，会在这些“还是”中选择一个发送回来\n",
         )),
+        permission: Permission::Anyone,
     }),
     task: on_message,
 };
@@ -1,6 +1,6 @@
 //! 复读机
 use crate::Consumption;
-use crate::linquebot::msg_context::TaskContext;
+use crate::linquebot::msg_context::{TaskContext, Topic};
 use crate::linquebot::*;
 use crate::utils::telegram::prelude::*;
 use msg_context::Context;
@@ -44,27 +44,32 @@ impl MessageHistory {
                 // 彩蛋
                 let egg = text == "没有" || text == "没有！";
 
-                let mut req = ctx.app.bot.send_message(ctx.chat_id, text);
+                let mut req = ctx.send_message(text);
 
                 if let Some(reply_to_msg_id) = self.reply_to_msg_id {
                     req = req.reply_parameters(ReplyParameters::new(reply_to_msg_id));
                 }
 
-                req.send().warn_on_error("repeater").await;
+                ctx.app
+                    .outbox
+                    .send(ctx.chat_id, &req)
+                    .warn_on_error("repeater")
+                    .await;
 
                 if egg {
+                    let req = ctx.send_message("通过！");
                     ctx.app
-                        .bot
-                        .send_message(ctx.chat_id, "通过！")
-                        .send()
+                        .outbox
+                        .send(ctx.chat_id, &req)
                         .warn_on_error("repeater")
                         .await;
                 }
             }
             MsgKind::Sticker(sticker) => {
+                ctx.app.outbox.wait(ctx.chat_id).await;
                 ctx.app
                     .bot
-                    .send_sticker_by_file_id(ctx.chat_id, &sticker.0)
+                    .send_sticker_by_file_id(ctx.chat_id, ctx.thread_id, &sticker.0)
                     .warn_on_error("repeater")
                     .await;
             }
@@ -84,8 +89,8 @@ impl Default for MessageHistory {
     }
 }
 
-static LAST_MSG: LazyLock<RwLock<HashMap<ChatId, MessageHistory>>> =
-    LazyLock::new(Default::default);
+/// 按 [TaskContext::scope] 分开复读，打开 `PER_TOPIC_STATE` 时论坛中每个话题分别复读
+static LAST_MSG: LazyLock<RwLock<HashMap<Topic, MessageHistory>>> = LazyLock::new(Default::default);
 
 /// repeat the message if 3 continous same text
 pub fn on_message(ctx: &mut Context, msg: &Message) -> Consumption {
@@ -94,8 +99,8 @@ pub fn on_message(ctx: &mut Context, msg: &Message) -> Consumption {
         log::error!("Error get history lock. This is not expected. {}", err);
     })?;
 
-    let Some(history) = manager.get_mut(&ctx.chat_id) else {
-        manager.insert(ctx.chat_id, Default::default());
+    let Some(history) = manager.get_mut(&ctx.scope()) else {
+        manager.insert(ctx.scope(), Default::default());
         return Consumption::just_next();
     };
     if history.off {
@@ -119,7 +124,7 @@ pub fn toggle_repeat(ctx: &mut Context, _: &Message) -> Consumption {
     let mut manager = LAST_MSG.write().map_err(|err| {
         log::error!("Error get history lock. This is not expected. {}", err);
     })?;
-    let history = manager.entry(ctx.chat_id).or_default();
+    let history = manager.entry(ctx.scope()).or_default();
     *history = MessageHistory {
         off: !history.off,
         ..Default::default()
@@ -148,8 +153,10 @@ pub static TOGGLE: Module = Module {
         description: "开关复读姬",
         description_detailed: Some(concat!(
             "默认打开复读。\n",
-            "不接受任何参数，每次收到这个命令时，如果复读姬是关的，就打开它，反之则关闭它。"
+            "不接受任何参数，每次收到这个命令时，如果复读姬是关的，就打开它，反之则关闭它。\n",
+            "设置了 PER_TOPIC_STATE 时，论坛中每个话题分别开关。"
         )),
+        permission: Permission::ChatAdmin,
     }),
     task: toggle_repeat,
 };
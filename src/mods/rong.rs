@@ -5,13 +5,15 @@ use std::sync::LazyLock;
 use teloxide_core::prelude::*;
 use teloxide_core::types::*;
 
-use crate::msg_context::Context;
-use crate::utils::telegram::prelude::*;
-use crate::utils::*;
 use crate::Consumption;
 use crate::Module;
 use crate::ModuleDescription;
 use crate::ModuleKind;
+use crate::Permission;
+use crate::msg_context::Context;
+use crate::utils::markup::Markup;
+use crate::utils::telegram::prelude::*;
+use crate::utils::*;
 
 // 常见其它 bot 的命令名单，防止意外回复
 static RONG_BLACKLIST: LazyLock<HashSet<&'static str>> = LazyLock::new(|| {
@@ -42,10 +44,10 @@ impl RongUser {
         })
     }
 
-    fn html_link(&self) -> String {
+    fn markup(&self) -> Markup {
         match &self.turl {
-            Some(url) => format!("<a href=\"{url}\">{}</a>", escape_html(&self.name)),
-            None => format!("<b>{}</b>", self.name),
+            Some(url) => markup::link(url, &self.name),
+            None => markup::bold(&self.name),
         }
     }
 }
@@ -82,19 +84,13 @@ pub fn rong(ctx: &mut Context, message: &Message) -> Consumption {
         return Consumption::just_next();
     }
 
-    let mut reply = format!(
-        "{} {} {}",
-        actor.html_link(),
-        escape_html(action),
-        actee.html_link(),
-    );
+    let mut reply = actor.markup() + " " + action + " " + actee.markup();
 
     if !addition.is_empty() {
-        reply.push(' ');
-        reply.push_str(&escape_html(addition));
+        reply = reply + " " + addition;
     }
 
-    reply.push('!');
+    let reply = (reply + "!").html();
 
     let ctx = ctx.task();
 
@@ -116,6 +112,7 @@ pub static MODULE: Module = Module {
         name: "rong",
         description: "Rong一下人",
         description_detailed: Some("对于没有在模块记录内的命令，如果你对某个人回复 <code>/动作 短语</code>，会回复“你 动作 某个人 短语！”"),
+        permission: Permission::Anyone,
     })),
     task: rong,
 };
@@ -0,0 +1,220 @@
+//! 群成员名单
+//!
+//! 根据 ChatMember 更新、入群/退群的服务消息和群内发言维护每个群的成员名单，
+//! 供 [crate::mods::waife] 等需要知道群里有谁的模块使用。
+//!
+//! 只有琳酱是管理员时 Telegram 才会发送 ChatMember 更新，
+//! 否则只能从服务消息和发言中得知成员变化。
+
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use log::{info, trace, warn};
+use serde::{Deserialize, Serialize};
+use teloxide_core::prelude::*;
+use teloxide_core::types::*;
+
+use crate::Consumption;
+use crate::linquebot::db::{DataType, DbData, DbError, WarnDbError};
+use crate::linquebot::msg_context::Context;
+use crate::linquebot::*;
+use crate::utils::telegram::prelude::*;
+
+/// 名单中的一个成员
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Member {
+    pub id: UserId,
+    pub full_name: String,
+    /// 琳酱最早知道这个成员在群里的时间
+    pub since: SystemTime,
+}
+
+impl Member {
+    pub fn from_user(user: &User) -> Self {
+        Self {
+            id: user.id,
+            full_name: user.full_name(),
+            since: SystemTime::now(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Roster {
+    members: HashMap<UserId, Member>,
+    /// 是否已经尝试过把群管理员加入名单
+    seeded: bool,
+}
+
+impl DbData for Roster {
+    const KEY: &str = "roster::Roster";
+}
+
+pub static DATA_TYPES: &[DataType] = &[DataType::of::<Roster>()];
+
+impl Roster {
+    /// 更新成员信息，返回是否是新成员
+    fn add(&mut self, user: &User) -> bool {
+        match self.members.get_mut(&user.id) {
+            Some(member) => {
+                member.full_name = user.full_name();
+                false
+            }
+            None => {
+                self.members.insert(user.id, Member::from_user(user));
+                true
+            }
+        }
+    }
+}
+
+/// 第一次用到名单时，先把群管理员加进去。只尝试一次，失败的话之后靠入群和发言补全
+async fn seed(app: &'static App, chat_id: ChatId) -> Result<(), DbError> {
+    let seeded = app
+        .db
+        .of::<Roster>()
+        .chat(chat_id)
+        .get()
+        .await?
+        .is_some_and(|roster| roster.seeded);
+    if seeded {
+        return Ok(());
+    }
+    // 请求管理员列表时不能拿着名单，否则同一个群的其他更新都要等它
+    let admins = app.bot.get_chat_administrators(chat_id).send().await;
+    let mut roster = app
+        .db
+        .of::<Roster>()
+        .chat(chat_id)
+        .get_or_insert(Default::default)
+        .await?;
+    if roster.seeded {
+        return Ok(());
+    }
+    roster.seeded = true;
+    match admins {
+        Ok(admins) => {
+            for member in admins {
+                if member.user.id != app.bot_id {
+                    roster.add(&member.user);
+                }
+            }
+        }
+        Err(err) => warn!(target: "roster", "failed to get admins of {chat_id}: {err}"),
+    }
+    Ok(())
+}
+
+/// 把用户加入名单
+pub async fn add(app: &'static App, chat_id: ChatId, user: &User) {
+    if user.id == app.bot_id {
+        return;
+    }
+    seed(app, chat_id).warn_on_error("roster-seed").await;
+    if let Some(mut roster) = app
+        .db
+        .of::<Roster>()
+        .chat(chat_id)
+        .get_or_insert(Default::default)
+        .await
+        .ok_or_warn("roster")
+    {
+        roster.add(user);
+    }
+}
+
+/// 把以前记录的成员加入名单，已经在名单中的成员不变
+pub async fn import(
+    app: &'static App,
+    chat_id: ChatId,
+    members: impl IntoIterator<Item = Member>,
+) -> Result<(), DbError> {
+    let mut roster = app
+        .db
+        .of::<Roster>()
+        .chat(chat_id)
+        .get_or_insert(Default::default)
+        .await?;
+    for member in members {
+        roster.members.entry(member.id).or_insert(member);
+    }
+    Ok(())
+}
+
+/// 把用户移出名单
+pub async fn remove(app: &'static App, chat_id: ChatId, user_id: UserId) {
+    if let Some(mut roster) = app
+        .db
+        .of::<Roster>()
+        .chat(chat_id)
+        .get()
+        .await
+        .ok_or_warn("roster")
+        .flatten()
+    {
+        roster.members.remove(&user_id);
+    }
+}
+
+/// 群里的所有已知成员
+pub async fn members(app: &'static App, chat_id: ChatId) -> Result<Vec<Member>, DbError> {
+    seed(app, chat_id).await?;
+    let roster = app
+        .db
+        .of::<Roster>()
+        .chat(chat_id)
+        .get_or_insert(Default::default)
+        .await?;
+    Ok(roster.members.values().cloned().collect())
+}
+
+fn on_message(ctx: &mut Context, msg: &Message) -> Consumption {
+    if msg.chat.is_private() {
+        return Consumption::just_next();
+    }
+    let app = ctx.app;
+    let chat_id = msg.chat.id;
+    if let Some(users) = msg.new_chat_members() {
+        let users = users.to_vec();
+        return Consumption::next_with(async move {
+            for user in users {
+                add(app, chat_id, &user).await;
+            }
+        });
+    }
+    if let Some(user) = msg.left_chat_member() {
+        let user_id = user.id;
+        return Consumption::next_with(remove(app, chat_id, user_id));
+    }
+    // Telegram says for backward compatibility, if the message was sent on behalf of a chat,
+    // the field contains a fake sender user in non-channel chats.
+    // But we don't need a fake user. Drop it.
+    if msg.sender_chat.is_some() {
+        return Consumption::just_next();
+    }
+    // 聊天群和绑定的 channel 可能有不同的人，丢弃来自 forward 的消息。
+    if msg.is_automatic_forward() || msg.is_reply_to_channel() {
+        trace!("Droped channel message/reply: {:?}", msg.text());
+        return Consumption::just_next();
+    }
+    let from = msg.from.as_ref()?.clone();
+    Consumption::next_with(async move { add(app, chat_id, &from).await })
+}
+
+fn on_chat_member(app: &'static App, data: &ChatMemberUpdated) -> Consumption {
+    let chat_id = data.chat.id;
+    let user = data.new_chat_member.user.clone();
+    if data.new_chat_member.is_present() {
+        Consumption::next_with(async move { add(app, chat_id, &user).await })
+    } else {
+        info!("{} left chat {chat_id}", user.full_name());
+        Consumption::next_with(remove(app, chat_id, user.id))
+    }
+}
+
+pub static RECORDER: Module = Module {
+    kind: ModuleKind::General(None),
+    task: on_message,
+};
+
+pub static ON_CHAT_MEMBER: MicroTask = MicroTask::OnChatMember(on_chat_member);
@@ -10,7 +10,7 @@ use crate::Consumption;
 
 pub fn on_message(ctx: &mut Context, msg: &Message) -> Consumption {
     let text = ctx.cmd?.content;
-    let mut reply = ctx.app.bot.send_message(ctx.chat_id, text);
+    let mut reply = ctx.task().send_message(text);
     if let Some(reply_to_message) = msg.reply_to_message() {
         reply = reply.reply_parameters(ReplyParameters::new(reply_to_message.id));
     }
@@ -22,6 +22,7 @@ pub static MODULE: Module = Module {
         name: "say",
         description: "复述内容",
         description_detailed: None,
+        permission: Permission::Anyone,
     }),
     task: on_message,
 };
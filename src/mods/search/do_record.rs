@@ -1,4 +1,5 @@
 use super::toggle::Search;
+use crate::linquebot::db::WarnDbError;
 use crate::{
     linquebot::{msg_context::Context, types::Consumption, vector_db::VectorData, Module},
     mods::search::embedding::text_embedding,
@@ -29,7 +30,8 @@ fn on_message(ctx: &mut Context, msg: &Message) -> Consumption {
             .chat(ctx.chat_id)
             .get_or_insert(Search::default)
             .await
-            .search_recording_enabled;
+            .ok_or_warn("search")
+            .is_some_and(|stat| stat.search_recording_enabled);
         if !enabled {
             return;
         }
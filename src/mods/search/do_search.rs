@@ -1,10 +1,10 @@
 use super::{embedding::text_embedding, toggle::Search};
 use crate::{
     linquebot::{
+        Module, ModuleDescription, ModuleKind, Permission,
         msg_context::Context,
         types::Consumption,
         vector_db::{VectorQuery, VectorResult},
-        Module, ModuleDescription, ModuleKind,
     },
     utils::telegram::prelude::WarnOnError,
 };
@@ -32,14 +32,17 @@ fn on_search(ctx: &mut Context, _: &Message) -> Consumption {
     let text = ctx.cmd?.content.to_owned();
     let ctx = ctx.task();
     async move {
-        let enabled = ctx
+        let enabled = match ctx
             .app
             .db
             .of::<Search>()
             .chat(ctx.chat_id)
             .get_or_insert(Search::default)
             .await
-            .search_enabled;
+        {
+            Ok(stat) => stat.search_enabled,
+            Err(err) => return ctx.reply_db_error("search", err).await,
+        };
         if !enabled {
             ctx.reply("搜索功能尚未启用")
                 .send()
@@ -116,6 +119,7 @@ pub static SEARCH: Module = Module {
         name: "search",
         description: "搜索内容",
         description_detailed: None,
+        permission: Permission::Anyone,
     }),
     task: on_search,
 };
@@ -1,8 +1,12 @@
 mod do_record;
 mod do_search;
-mod embedding;
+pub mod embedding;
 mod toggle;
 
 pub use do_record::RECORDER;
 pub use do_search::SEARCH;
 pub use toggle::{TOGGLE_SEARCH, TOGGLE_SEARCH_RECORDING};
+
+use crate::linquebot::db::DataType;
+
+pub static DATA_TYPES: &[DataType] = &[DataType::of::<toggle::Search>()];
@@ -1,5 +1,8 @@
+use crate::linquebot::db::DbData;
 use crate::{
-    linquebot::{msg_context::Context, types::Consumption, Module, ModuleDescription, ModuleKind},
+    linquebot::{
+        Module, ModuleDescription, ModuleKind, Permission, msg_context::Context, types::Consumption,
+    },
     utils::telegram::prelude::WarnOnError,
 };
 use serde::{Deserialize, Serialize};
@@ -11,16 +14,24 @@ pub struct Search {
     pub search_recording_enabled: bool,
 }
 
+impl DbData for Search {
+    const KEY: &str = "search::Search";
+}
+
 fn on_toggle_recording(ctx: &mut Context, _: &Message) -> Consumption {
     let ctx = ctx.task();
     async move {
-        let mut stat = ctx
+        let mut stat = match ctx
             .app
             .db
             .of::<Search>()
             .chat(ctx.chat_id)
             .get_or_insert(Search::default)
-            .await;
+            .await
+        {
+            Ok(stat) => stat,
+            Err(err) => return ctx.reply_db_error("toggle_search_recording", err).await,
+        };
 
         stat.search_recording_enabled = !stat.search_recording_enabled;
 
@@ -39,13 +50,17 @@ fn on_toggle_recording(ctx: &mut Context, _: &Message) -> Consumption {
 fn on_toggle_search(ctx: &mut Context, _: &Message) -> Consumption {
     let ctx = ctx.task();
     async move {
-        let mut stat = ctx
+        let mut stat = match ctx
             .app
             .db
             .of::<Search>()
             .chat(ctx.chat_id)
             .get_or_insert(Search::default)
-            .await;
+            .await
+        {
+            Ok(stat) => stat,
+            Err(err) => return ctx.reply_db_error("toggle_search", err).await,
+        };
 
         stat.search_enabled = !stat.search_enabled;
 
@@ -70,6 +85,7 @@ pub static TOGGLE_SEARCH_RECORDING: Module = Module {
             "打开/关闭<b>搜索</b>模块的群组消息记录功能。\n",
             "开启后，群组消息会被记录到数据库中，但数据库中不会保存原文本，也无法从中读取或恢复消息。\n",
         )),
+        permission: Permission::ChatAdmin,
     }),
     task: on_toggle_recording,
 };
@@ -82,6 +98,7 @@ pub static TOGGLE_SEARCH: Module = Module {
             "该命令不需要参数。\n",
             "打开/关闭<b>搜索</b>模块的群组消息搜索功能。\n",
         )),
+        permission: Permission::ChatAdmin,
     }),
     task: on_toggle_search,
 };
@@ -6,7 +6,7 @@ use teloxide_core::ApiError;
 use teloxide_core::RequestError;
 
 use crate::linquebot::*;
-use crate::utils::telegram::prelude::WarnOnError;
+use crate::utils::telegram::prelude::{InTopic, MessageExtension, WarnOnError};
 use crate::Consumption;
 use msg_context::Context;
 
@@ -14,6 +14,7 @@ async fn reply_info(bot: &Bot, message: Message, info: &str) {
     if let Err(err) = bot
         .send_message(message.chat.id, info)
         .reply_parameters(ReplyParameters::new(message.id))
+        .in_topic(message.topic_id())
         .send()
         .await
     {
@@ -135,6 +136,7 @@ pub static MODULE: Module = Module {
             "加参数的 /t xxx 设置头衔为 xxx。\n",
             "琳酱必须具有设置管理员的权限，琳酱没办法对非琳酱设置的管理员设置头衔"
         )),
+        permission: Permission::Anyone,
     }),
     task: on_message,
 };
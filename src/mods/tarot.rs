@@ -11,41 +11,46 @@ use crate::linquebot::*;
 use crate::utils::telegram::prelude::*;
 use crate::Consumption;
 
+/// 解析要抽取的牌数，默认 3 张
+pub fn parse_count(text: &str) -> Result<usize, &'static str> {
+    let num = if text.is_empty() {
+        3
+    } else if let Ok(parsed) = text.parse::<usize>() {
+        parsed
+    } else {
+        return Err("数字不对，不准乱玩琳酱呀");
+    };
+    if num == 0 {
+        return Err("不给你牌可以，可以给你一拳");
+    }
+    if num > 21 {
+        return Err("牌都给你摸完了，不准乱玩琳酱");
+    }
+    Ok(num)
+}
+
+/// 抽取 `num` 张牌，返回结果文本
+pub fn draw(name: &str, num: usize) -> String {
+    let text = tarot::n_random_majors(num)
+        .into_iter()
+        .map(|tarot| tarot.to_string())
+        .collect::<Vec<_>>();
+    format!("{name} 抽到的牌组是: \n{}", text.join("\n"))
+}
+
 pub fn on_message(ctx: &mut Context, message: &Message) -> Consumption {
     let text = ctx.cmd?.content;
     let Some(from) = message.from.clone() else {
         warn!("No reply target.");
         return Consumption::just_stop();
     };
-    let num;
 
     let ctx = ctx.task();
 
-    if text.is_empty() {
-        num = 3;
-    } else if let Ok(parsed) = text.parse::<usize>() {
-        num = parsed;
-    } else {
-        return ctx
-            .reply("数字不对，不准乱玩琳酱呀")
-            .send()
-            .warn_on_error("tarot")
-            .into();
+    let num = match parse_count(text) {
+        Ok(num) => num,
+        Err(err) => return ctx.reply(err).send().warn_on_error("tarot").into(),
     };
-    if num == 0 {
-        return ctx
-            .reply("不给你牌可以，可以给你一拳")
-            .send()
-            .warn_on_error("tarot")
-            .into();
-    }
-    if num > 21 {
-        return ctx
-            .reply("牌都给你摸完了，不准乱玩琳酱")
-            .send()
-            .warn_on_error("tarot")
-            .into();
-    }
 
     async move {
         ctx.reply(format!(
@@ -65,19 +70,10 @@ pub fn on_message(ctx: &mut Context, message: &Message) -> Consumption {
 
         tokio::time::sleep(Duration::from_millis(2500)).await;
 
-        let text = tarot::n_random_majors(num)
-            .into_iter()
-            .map(|tarot| tarot.to_string())
-            .collect::<Vec<_>>();
-
-        ctx.reply(format!(
-            "{} 抽到的牌组是: \n{}",
-            from.full_name(),
-            text.join("\n")
-        ))
-        .send()
-        .warn_on_error("tarot")
-        .await;
+        ctx.reply(draw(&from.full_name(), num))
+            .send()
+            .warn_on_error("tarot")
+            .await;
     }
     .into()
 }
@@ -87,6 +83,7 @@ pub static MODULE: Module = Module {
         name: "tarot",
         description: "抽取塔罗牌",
         description_detailed: Some(concat!("可选参数：数量\n", "默认摸 3 张。\n",)),
+        permission: Permission::Anyone,
     }),
     task: on_message,
 };
@@ -186,6 +186,7 @@ pub static MODULE: Module = Module {
         name: "tarot_ai",
         description: "塔罗牌（AI版）",
         description_detailed: Some("必选参数：提出的问题（最好是 YES OR NO 能回答的）"),
+        permission: Permission::Anyone,
     }),
     task: send_tarot,
 };
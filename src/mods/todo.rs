@@ -1,114 +1,434 @@
-//! todo command  
+//! todo command
 //! ```
 //! /todo time thing
+//! /todo list
+//! /todo cancel id
+//! /todo tz [chat] [offset]
 //! ```
-//! Remind the user to do <thing> after <time> minutes. If the message is a reply, set the user as the repliee.
+//! Remind the user to do <thing> at <time>. If the message is a reply, set the user as the repliee.
+//! <time> can be a duration, an absolute time or a recurring time, see [crate::utils::time].
+//! Reminders are stored by [crate::linquebot::scheduler], so they survive restarts.
 
-use log::{error, warn};
-use msg_context::Context;
-use std::time::Duration;
+use chrono::{DateTime, FixedOffset, Utc};
+use log::error;
+use msg_context::{Context, TaskContext};
+use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
 use teloxide_core::prelude::*;
 use teloxide_core::types::*;
 
+use crate::Consumption;
+use crate::linquebot::db::{DataType, DbData, DbError};
+use crate::linquebot::scheduler::{Job, NewJob};
 use crate::linquebot::*;
+use crate::utils::markup::{Markup, code, mention, text};
 use crate::utils::telegram::prelude::*;
+use crate::utils::time::{Recurrence, When, default_offset, parse_offset, parse_when};
 use crate::utils::*;
-use crate::Consumption;
 
-pub fn on_message(ctx: &mut Context, message: &Message) -> Consumption {
-    let args = ctx.cmd?.content;
-    let user = match message.reply_to_message() {
-        Some(msg) => msg.from.as_ref(),
-        None => message.from.as_ref(),
-    }?
-    .clone();
-    let ctx = ctx.task();
+/// 保存在定时任务中的提醒
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Todo {
+    /// 设置提醒的人
+    creator: UserId,
+    /// 被提醒的人
+    user_id: UserId,
+    /// 提醒时对被提醒的人的称呼
+    mention: String,
+    thing: String,
+    /// 重复提醒
+    repeat: Option<Recurrence>,
+    /// 设置提醒时使用的时区，以秒为单位的 UTC 偏移
+    offset: i32,
+}
 
-    let (pre, Some(thing)) = split_n::<2>(args) else {
-        return ctx
-            .reply("/todo 需要至少两个参数哦，第一个参数是分钟，第二个参数是琳酱要提醒干什么事")
+impl Todo {
+    fn offset(&self) -> FixedOffset {
+        FixedOffset::east_opt(self.offset).unwrap_or_else(default_offset)
+    }
+}
+
+/// 用户或聊天设置的时区，以秒为单位的 UTC 偏移
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct TimeZoneSetting(i32);
+
+impl DbData for TimeZoneSetting {
+    const KEY: &str = "todo::TimeZoneSetting";
+}
+
+pub static DATA_TYPES: &[DataType] = &[DataType::of::<TimeZoneSetting>()];
+
+/// 用户的时区优先，其次是聊天的时区，默认为 UTC+8
+async fn offset_of(
+    app: &'static App,
+    chat_id: ChatId,
+    user_id: UserId,
+) -> Result<FixedOffset, DbError> {
+    let setting = match app.db.of::<TimeZoneSetting>().user(user_id).get().await? {
+        Some(setting) => Some(*setting),
+        None => app
+            .db
+            .of::<TimeZoneSetting>()
+            .chat(chat_id)
+            .get()
+            .await?
+            .map(|setting| *setting),
+    };
+    Ok(setting
+        .and_then(|setting| FixedOffset::east_opt(setting.0))
+        .unwrap_or_else(default_offset))
+}
+
+fn format_time(time: SystemTime, offset: FixedOffset) -> String {
+    DateTime::<Utc>::from(time)
+        .with_timezone(&offset)
+        .format("%Y-%m-%d %H:%M")
+        .to_string()
+}
+
+async fn list_todos(ctx: TaskContext) -> Result<(), DbError> {
+    let jobs = ctx
+        .app
+        .scheduler
+        .jobs_of(ctx.app, MODULE_NAME, ctx.chat_id)
+        .await?;
+    if jobs.is_empty() {
+        ctx.reply("本群没有待办的提醒哦")
             .send()
             .warn_on_error("todo")
-            .into();
-    };
+            .await;
+        return Ok(());
+    }
+    let list = jobs
+        .iter()
+        .filter_map(|job| {
+            let todo = job.payload::<Todo>()?;
+            let time = format_time(job.at, todo.offset());
+            let time = match &todo.repeat {
+                Some(repeat) => format!("{}（下次 {time}）", repeat.describe()),
+                None => time,
+            };
+            Some(
+                text("\n")
+                    + code(job.id.to_string())
+                    + format!(": {time} 提醒 {} {}", todo.mention, todo.thing),
+            )
+        })
+        .collect::<Markup>();
+    ctx.reply_html((text("本群的提醒：") + list).html())
+        .send()
+        .warn_on_error("todo")
+        .await;
+    Ok(())
+}
 
-    let [time] = pre[..] else {
-        unreachable!(
-            "split_n should return None for second result if pre have less than N elements"
-        );
+async fn cancel_todo(ctx: TaskContext, message: Message, id: &str) -> Result<(), DbError> {
+    let jobs = ctx
+        .app
+        .scheduler
+        .jobs_of(ctx.app, MODULE_NAME, ctx.chat_id)
+        .await?;
+    let Some(todo) = id
+        .parse::<u64>()
+        .ok()
+        .and_then(|id| jobs.into_iter().find(|job| job.id == id))
+        .and_then(|job| Some((job.id, job.payload::<Todo>()?)))
+    else {
+        ctx.reply("本群没有这个提醒哦")
+            .send()
+            .warn_on_error("todo")
+            .await;
+        return Ok(());
     };
+    let (id, todo) = todo;
+    let is_related = message
+        .from
+        .as_ref()
+        .is_some_and(|user| user.id == todo.creator || user.id == todo.user_id);
+    if !is_related
+        && !ctx
+            .app
+            .permissions
+            .check(&ctx.app.bot, &message, Permission::ChatAdmin)
+            .await
+    {
+        ctx.reply("只有设置提醒的人、被提醒的人和管理员才能取消提醒哦")
+            .send()
+            .warn_on_error("todo")
+            .await;
+        return Ok(());
+    }
+    ctx.app.scheduler.cancel(ctx.app, ctx.chat_id, id).await?;
+    ctx.reply_html((text("已取消提醒：") + &todo.thing).html())
+        .send()
+        .warn_on_error("todo")
+        .await;
+    Ok(())
+}
 
-    let Ok(time) = time.parse::<f64>() else {
-        return ctx
-            .reply("没法解析出要几分钟后提醒呢")
+async fn set_timezone(ctx: TaskContext, message: Message, args: String) -> Result<(), DbError> {
+    let Some(user_id) = message.from.as_ref().map(|user| user.id) else {
+        return Ok(());
+    };
+    let (for_chat, offset) = match args.strip_prefix("chat") {
+        Some(rest) => (true, rest.trim()),
+        None => (false, args.as_str()),
+    };
+    if offset.is_empty() {
+        let offset = offset_of(ctx.app, ctx.chat_id, user_id).await?;
+        ctx.reply(format!(
+            "当前使用的时区是 UTC{offset}\n使用 /todo tz +8 设置你的时区，/todo tz chat +8 设置本群的时区"
+        ))
+        .send()
+        .warn_on_error("todo")
+        .await;
+        return Ok(());
+    }
+    let Some(offset) = parse_offset(offset) else {
+        ctx.reply("没法解析时区呢，可以试试 +8、-5 或者 +5:30")
             .send()
             .warn_on_error("todo")
-            .into();
+            .await;
+        return Ok(());
     };
+    if for_chat {
+        if !ctx
+            .app
+            .permissions
+            .check(&ctx.app.bot, &message, Permission::ChatAdmin)
+            .await
+        {
+            ctx.reply_html(Permission::ChatAdmin.refusal())
+                .send()
+                .warn_on_error("todo")
+                .await;
+            return Ok(());
+        }
+        ctx.app
+            .db
+            .of::<TimeZoneSetting>()
+            .chat(ctx.chat_id)
+            .insert(TimeZoneSetting(offset.local_minus_utc()))
+            .await?;
+        ctx.reply(format!("本群的时区已设置为 UTC{offset}"))
+    } else {
+        ctx.app
+            .db
+            .of::<TimeZoneSetting>()
+            .user(user_id)
+            .insert(TimeZoneSetting(offset.local_minus_utc()))
+            .await?;
+        ctx.reply(format!("你的时区已设置为 UTC{offset}"))
+    }
+    .send()
+    .warn_on_error("todo")
+    .await;
+    Ok(())
+}
 
-    if time < 0.0 {
-        return ctx
-            .reply("琳酱暂未研究出时间折跃技术，没法在过去提醒呢")
+async fn add_todo(
+    ctx: TaskContext,
+    args: String,
+    creator: UserId,
+    user: User,
+) -> Result<(), DbError> {
+    let offset = offset_of(ctx.app, ctx.chat_id, user.id).await?;
+    let now = Utc::now().with_timezone(&offset);
+
+    let Some((when, thing)) = parse_when(&args, now) else {
+        ctx.reply_html(concat!(
+            "没法解析出提醒时间呢，可以试试这些格式：\n",
+            "<code>/todo 30 喝水</code>、<code>/todo 1h30m 喝水</code>、",
+            "<code>/todo 明天下午三点 开会</code>、<code>/todo 2026-12-24 20:00 过节</code>、",
+            "<code>/todo 每天 8:00 吃药</code>"
+        ))
+        .send()
+        .warn_on_error("todo")
+        .await;
+        return Ok(());
+    };
+
+    if thing.is_empty() {
+        ctx.reply("琳酱要提醒干什么事呢？请在时间后面写上要做的事情")
             .send()
             .warn_on_error("todo")
-            .into();
+            .await;
+        return Ok(());
     }
 
-    if time > (365 * 24 * 60) as f64 {
-        return ctx.reply("太久远啦！").send().warn_on_error("todo").into();
+    let at = when.first_after(now);
+    if at <= now {
+        ctx.reply("琳酱暂未研究出时间折跃技术，没法在过去提醒呢")
+            .send()
+            .warn_on_error("todo")
+            .await;
+        return Ok(());
     }
 
-    let thing = String::from(thing);
+    if at - now > chrono::Duration::days(365) {
+        ctx.reply("太久远啦！").send().warn_on_error("todo").await;
+        return Ok(());
+    }
 
-    async move {
-        if let Err(err) = ctx
-            .reply_html(format!(
-                "设置成功！将在 {time} 分钟后提醒 {} {}",
-                user.html_link(),
-                escape_html(&thing)
-            ))
-            .send()
-            .await
-        {
-            warn!("Failed to send reply: {}", err);
-            return;
+    let repeat = match when {
+        When::Every(recurrence) => Some(recurrence),
+        When::At(_) => None,
+    };
+    let todo = Todo {
+        creator,
+        user_id: user.id,
+        mention: user.mention().unwrap_or_else(|| user.first_name.clone()),
+        thing: String::from(thing),
+        repeat,
+        offset: offset.local_minus_utc(),
+    };
+
+    let id = ctx
+        .app
+        .scheduler
+        .schedule(
+            ctx.app,
+            NewJob {
+                module: MODULE_NAME,
+                chat_id: ctx.chat_id,
+                thread_id: ctx.thread_id,
+                message_id: Some(ctx.message_id),
+                at: at.into(),
+                payload: &todo,
+            },
+        )
+        .await?;
+    let time = match repeat {
+        Some(repeat) => repeat.describe(),
+        None => format!("在 {}", at.format("%Y-%m-%d %H:%M")),
+    };
+    let reply = text(format!("设置成功！将{time}提醒 "))
+        + mention(&user)
+        + format!(" {}\n取消提醒：", todo.thing)
+        + code(format!("/todo cancel {id}"));
+    ctx.reply_html(reply.html())
+        .send()
+        .warn_on_error("todo")
+        .await;
+    Ok(())
+}
+
+/// 执行命令，数据库出错时回复用户
+fn run_todo(
+    ctx: TaskContext,
+    task: impl Future<Output = Result<(), DbError>> + Send + 'static,
+) -> Consumption {
+    async move { ctx.with_db("todo", task).await }.into()
+}
+
+pub fn on_message(ctx: &mut Context, message: &Message) -> Consumption {
+    let args = ctx.cmd?.content;
+    match split_args::<2>(args) {
+        ["list", ""] => return run_todo(ctx.task(), list_todos(ctx.task())),
+        ["cancel", id] => {
+            let id = id.to_string();
+            let message = message.clone();
+            let ctx = ctx.task();
+            return run_todo(
+                ctx.clone(),
+                async move { cancel_todo(ctx, message, &id).await },
+            );
+        }
+        ["tz", rest] => {
+            let task = set_timezone(ctx.task(), message.clone(), rest.to_string());
+            return run_todo(ctx.task(), task);
         }
+        _ => {}
+    }
+    let creator = message.from.as_ref()?.id;
+    let user = match message.reply_to_message() {
+        Some(msg) => msg.from.as_ref(),
+        None => message.from.as_ref(),
+    }?
+    .clone();
+    let ctx = ctx.task();
+
+    if args.is_empty() {
+        return ctx
+            .reply("/todo 需要至少两个参数哦，第一个参数是时间，第二个参数是琳酱要提醒干什么事")
+            .send()
+            .warn_on_error("todo")
+            .into();
+    }
 
-        tokio::time::sleep(Duration::from_millis((time * 60.0 * 1000.0) as u64)).await;
+    run_todo(ctx.clone(), add_todo(ctx, args.to_string(), creator, user))
+}
 
-        for retries in 0..3 {
-            if let Err(err) = ctx
-                .reply_html(format!(
-                    "{} 该{}啦！",
-                    &user.mention().as_ref().unwrap_or(&user.first_name),
-                    escape_html(&thing)
-                ))
-                .send()
+fn on_todo_job(app: &'static App, job: &Job) -> Consumption {
+    let todo = job.payload::<Todo>()?;
+    let request = app
+        .bot
+        .send_message(
+            job.chat_id,
+            text(format!("{} 该{}啦！", todo.mention, todo.thing)).html(),
+        )
+        .in_topic(job.thread_id);
+    let request = match job.message_id {
+        Some(message_id) => {
+            request.reply_parameters(ReplyParameters::new(message_id).allow_sending_without_reply())
+        }
+        None => request,
+    };
+    let request = request.parse_mode(ParseMode::Html);
+    let job = job.clone();
+
+    async move {
+        if let Some(repeat) = todo.repeat {
+            let offset = todo.offset();
+            let last = DateTime::<Utc>::from(job.at).with_timezone(&offset);
+            let now = Utc::now().with_timezone(&offset);
+            let next = repeat.next_after(last.max(now));
+            if let Err(err) = app
+                .scheduler
+                .schedule(
+                    app,
+                    NewJob {
+                        module: MODULE_NAME,
+                        chat_id: job.chat_id,
+                        thread_id: job.thread_id,
+                        message_id: job.message_id,
+                        at: next.into(),
+                        payload: &todo,
+                    },
+                )
                 .await
             {
-                warn!(
-                    "(retry {} times) Failed to send reply: {}",
-                    retries,
-                    err
-                );
-            } else {
-                return;
+                error!("Failed to schedule the next reminder: {err}");
             }
         }
 
-        error!("too many request errors, stop.")
+        if let Err(err) = app.outbox.send(job.chat_id, &request).await {
+            error!("Failed to send reminder: {err}");
+        }
     }
     .into()
 }
 
+const MODULE_NAME: &str = "todo";
+
 pub static MODULE: Module = Module {
     kind: ModuleKind::Command(ModuleDescription {
-        name: "todo",
+        name: MODULE_NAME,
         description: "定时提醒",
         description_detailed: Some(concat!(
-            "必须有两个参数。使用 <code>/todo n [事情]</code> 在 n 分钟后提醒你做 [事情]。\n",
-            "n 必须是数字。如果 n 太大的话，琳酱会拒绝提醒的"
+            "使用 <code>/todo [时间] [事情]</code> 在指定的时间提醒你做 [事情]。\n",
+            "时间可以是：\n",
+            "时长，例如 <code>30</code>（分钟）、<code>1h30m</code>、<code>2d</code>、<code>半小时</code>\n",
+            "时刻，例如 <code>14:30</code>、<code>2026-12-24 20:00</code>、<code>明天下午三点</code>、<code>下周一</code>\n",
+            "重复，例如 <code>每天 8:00</code>、<code>每周五</code>\n",
+            "如果时间太久远的话，琳酱会拒绝提醒的\n",
+            "<code>/todo list</code>: 列出本群的提醒\n",
+            "<code>/todo cancel [id]</code>: 取消提醒\n",
+            "<code>/todo tz [+8]</code>: 查看或设置你的时区，<code>/todo tz chat [+8]</code> 设置本群的时区"
         )),
+        permission: Permission::Anyone,
     }),
     task: on_message,
 };
+
+pub static ON_JOB: MicroTask = MicroTask::OnScheduledJob(MODULE_NAME, on_todo_job);
@@ -5,6 +5,7 @@ use teloxide_core::types::*;
 
 use crate::linquebot::*;
 use crate::utils::base64;
+use crate::utils::markup::code;
 use crate::utils::split_args;
 use crate::utils::telegram::prelude::WarnOnError;
 use crate::Consumption;
@@ -20,13 +21,11 @@ fn on_message(ctx: &mut Context, _message: &Message) -> Consumption {
     let task = ctx.task();
     match toolname {
         "base64" => {
-            let res = format!("`{}`", base64::encode(content));
-            if res.len() == 2 {
+            let res = base64::encode(content);
+            if res.is_empty() {
                 task.reply("<空串>")
-            } else if res.len() >= 4095 {
-                task.reply("你给出的输入太长了！")
             } else {
-                task.reply_markdown(&res)
+                task.reply_markdown(code(res).markdown())
             }
         }
         "base64d" => task.reply(
@@ -53,6 +52,7 @@ pub static MODULE: Module = Module {
         name: "tools",
         description: "实用工具",
         description_detailed: Some(TOOLS_HELP),
+        permission: Permission::Anyone,
     }),
     task: on_message,
 };
@@ -1,10 +1,9 @@
 //! 随机群老婆
+use futures::StreamExt;
 use graphviz_rust::dot_structures::Graph;
 use graphviz_rust::printer::DotPrinter;
 use graphviz_rust::printer::PrinterContext;
-use log::error;
-use log::info;
-use log::warn;
+use log::{error, info, warn};
 use msg_context::Context;
 use rand::seq::SliceRandom;
 use rand::rng;
@@ -14,15 +13,17 @@ use std::cmp::min;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::ops::DerefMut;
+use std::pin::pin;
 use std::time::SystemTime;
 use teloxide_core::prelude::*;
 use teloxide_core::types::*;
 
-use crate::linquebot::msg_context::TaskContext;
+use crate::Consumption;
+use crate::linquebot::db::{DataType, DbData, DbError, Scan, WarnDbError};
 use crate::linquebot::*;
-use crate::utils::escape_html;
+use crate::mods::roster::{self, Member};
+use crate::utils::markup::bold;
 use crate::utils::telegram::prelude::*;
-use crate::Consumption;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct WaifeUser {
@@ -31,15 +32,15 @@ struct WaifeUser {
 }
 
 impl WaifeUser {
-    fn from_user(user: &User) -> Self {
+    fn from_member(member: &Member) -> Self {
         Self {
-            id: user.id,
-            full_name: user.full_name(),
+            id: member.id,
+            full_name: member.full_name.clone(),
         }
     }
 
     fn html_link(&self) -> String {
-        format!("<b>{}</b>", escape_html(&self.full_name))
+        bold(&self.full_name).html()
     }
 
     fn escaped_name(&self) -> String {
@@ -63,6 +64,28 @@ struct WaifeStatus {
     waife_limit: Option<usize>,                 // 每个人的 waife limit
 }
 
+impl DbData for WaifeStatus {
+    const KEY: &str = "waife::WaifeStatus";
+}
+
+/// 以前记录的用户在各个群里的状态，现在由 [roster] 维护，启动时转换为名单
+#[derive(Debug, Serialize, Deserialize)]
+struct UserCache {
+    chats: HashMap<ChatId, UserChatCache>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct UserChatCache {
+    cache_at: SystemTime,
+    joined: bool,
+}
+
+impl DbData for UserCache {
+    const KEY: &str = "waife::UserCache";
+}
+
+pub static DATA_TYPES: &[DataType] = &[DataType::of::<WaifeStatus>(), DataType::of::<UserCache>()];
+
 impl Default for WaifeStatus {
     fn default() -> Self {
         Self {
@@ -74,44 +97,73 @@ impl Default for WaifeStatus {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct UserChatCache {
-    cache_at: SystemTime,
-    joined: bool,
+/// 成员的名字，今天抽过老婆的用户直接使用记录的名字，否则向 Telegram 查询，已经退群的返回 `None`
+async fn member_of(app: &'static App, chat_id: ChatId, user_id: UserId) -> Option<Member> {
+    let known = app
+        .db
+        .of::<WaifeStatus>()
+        .chat(chat_id)
+        .get()
+        .await
+        .ok_or_warn("waife")
+        .flatten()
+        .and_then(|status| status.users.get(&user_id).cloned());
+    if let Some(user) = known {
+        return Some(Member {
+            id: user.id,
+            full_name: user.full_name,
+            since: SystemTime::now(),
+        });
+    }
+    match app.bot.get_chat_member(chat_id, user_id).send().await {
+        Ok(member) if member.is_present() => Some(Member::from_user(&member.user)),
+        Ok(_) => None,
+        Err(err) => {
+            warn!(target: "waife", "failed to get {user_id} in {chat_id}: {err}");
+            None
+        }
+    }
 }
 
-impl UserChatCache {
-    fn new(joined: bool) -> Self {
-        Self {
-            cache_at: SystemTime::now(),
-            joined,
+/// 把以前的 [UserCache] 转换为群成员名单，避免升级后老婆池被清空
+pub async fn migrate_user_cache(app: &'static App) -> Result<(), DbError> {
+    let mut caches = vec![];
+    let mut rows = pin!(app.db.scan::<UserCache>(Scan::All));
+    while let Some(row) = rows.next().await {
+        match row {
+            Ok((id, cache)) => {
+                if let Some(user_id) = id.user {
+                    caches.push((user_id, cache));
+                }
+            }
+            Err(err) => error!("Failed to read user cache: {err}"),
         }
     }
-
-    fn invalid(&self) -> bool {
-        SystemTime::now()
-            .duration_since(self.cache_at)
-            .is_ok_and(|d| d.as_secs() > 3600)
+    if caches.is_empty() {
+        return Ok(());
     }
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct UserCache {
-    chats: HashMap<ChatId, UserChatCache>,
-    // avatar: Option<...>
-}
-
-fn truncate_names(names: &mut String) {
-    if names.len() > 4000 {
-        while names.len() >= 4000 {
-            names.pop();
+    let mut chats = HashMap::<ChatId, Vec<Member>>::new();
+    for (user_id, cache) in &caches {
+        for (&chat_id, chat) in &cache.chats {
+            if chat.joined
+                && let Some(member) = member_of(app, chat_id, *user_id).await
+            {
+                chats.entry(chat_id).or_default().push(member);
+            }
         }
-        names.push_str(" （...太多了写不下了）");
     }
+    for (chat_id, members) in chats {
+        roster::import(app, chat_id, members).await?;
+    }
+    for (user_id, _) in &caches {
+        app.db.of::<UserCache>().user(*user_id).remove().await?;
+    }
+    info!(target: "waife", "migrated {} users into rosters", caches.len());
+    Ok(())
 }
 
 fn get_waife(ctx: &mut Context, msg: &Message) -> Consumption {
-    let from = WaifeUser::from_user(msg.from.as_ref()?);
+    let from = msg.from.as_ref()?.clone();
     let num = ctx.cmd?.content.parse::<isize>().unwrap_or(1);
     let poly = ctx.cmd?.content == "poly" || num > 1;
     let ctx = ctx.task();
@@ -124,18 +176,21 @@ fn get_waife(ctx: &mut Context, msg: &Message) -> Consumption {
                 .await;
         }
 
-        let mut waife_storage = match ctx.app.db.of::<WaifeStatus>().chat(ctx.chat_id).get().await {
-            Some(x) => x,
-            None => {
-                ctx.reply("稍等 >_<").send().warn_on_error("waife").await;
-                ctx.app
-                    .db
-                    .of::<WaifeStatus>()
-                    .chat(ctx.chat_id)
-                    .get()
-                    .await
-                    .unwrap()
-            }
+        roster::add(ctx.app, ctx.chat_id, &from).await;
+        let members = match roster::members(ctx.app, ctx.chat_id).await {
+            Ok(members) => members,
+            Err(err) => return ctx.reply_db_error("waife", err).await,
+        };
+        let mut waife_storage = match ctx
+            .app
+            .db
+            .of::<WaifeStatus>()
+            .chat(ctx.chat_id)
+            .get_or_insert(Default::default)
+            .await
+        {
+            Ok(waife_storage) => waife_storage,
+            Err(err) => return ctx.reply_db_error("waife", err).await,
         };
 
         let now = SystemTime::now();
@@ -156,17 +211,6 @@ fn get_waife(ctx: &mut Context, msg: &Message) -> Consumption {
             waife_storage.last_waife_date = now;
             waife_storage.waife_of = HashMap::new();
             waife_storage.users.clear();
-            add_admins_to_users(&mut waife_storage.users, &ctx)
-                .warn_on_error("waife-auto-add")
-                .await;
-        }
-
-        if !check_and_add(&mut waife_storage.users, &ctx, from.clone()).await {
-            ctx.reply("失败：琳酱无法获取你在群内的状态 >_<")
-                .send()
-                .warn_on_error("waife")
-                .await;
-            return;
         }
 
         let WaifeStatus {
@@ -216,16 +260,13 @@ fn get_waife(ctx: &mut Context, msg: &Message) -> Consumption {
                     false
                 }
             });
-            truncate_names(&mut divorced_names);
 
-            let mut waife_names = waife_uids
+            let waife_names = waife_uids
                 .iter()
                 .map(|uid| users.get(uid).unwrap().html_link())
                 .collect::<Vec<_>>()
                 .join(", ");
 
-            truncate_names(&mut waife_names);
-
             let html_text = if waife_uids.is_empty() {
                 format!("你和 {divorced_names} 离婚了，现在你没有老婆了",)
             } else {
@@ -245,14 +286,12 @@ fn get_waife(ctx: &mut Context, msg: &Message) -> Consumption {
 
         // 一元关系
         if !(waife_uids.is_empty() || poly) {
-            let mut waife_names = waife_uids
+            let waife_names = waife_uids
                 .iter()
                 .map(|uid| users.get(uid).unwrap().html_link())
                 .collect::<Vec<_>>()
                 .join(", ");
 
-            truncate_names(&mut waife_names);
-
             ctx.reply_html(format!("你今天已经有老婆了，你的群老婆：{waife_names}"))
                 .send()
                 .warn_on_error("waife")
@@ -271,7 +310,7 @@ fn get_waife(ctx: &mut Context, msg: &Message) -> Consumption {
             return;
         }
 
-        if poly && waife_uids.len() > 1 && waife_uids.len() >= users.len() - 1 {
+        if poly && waife_uids.len() > 1 && waife_uids.len() >= members.len() - 1 {
             ctx.reply("别贪心了，琳酱认识的群成员已经全是你老婆了！")
                 .send()
                 .warn_on_error("waife")
@@ -279,9 +318,10 @@ fn get_waife(ctx: &mut Context, msg: &Message) -> Consumption {
             return;
         }
 
-        let mut available_waifes = users
-            .values()
-            .filter(|user| !waife_uids.contains(&user.id) && user.id != from.id)
+        let mut available_waifes = members
+            .iter()
+            .filter(|member| !waife_uids.contains(&member.id) && member.id != from.id)
+            .map(WaifeUser::from_member)
             .collect::<Vec<_>>();
 
         if available_waifes.is_empty() {
@@ -303,16 +343,22 @@ fn get_waife(ctx: &mut Context, msg: &Message) -> Consumption {
 
         let mut waife_names = String::new();
 
-        for user in available_waifes.iter().take(num) {
+        users.insert(
+            from.id,
+            WaifeUser {
+                id: from.id,
+                full_name: from.full_name(),
+            },
+        );
+        for user in available_waifes.into_iter().take(num) {
             waife_uids.insert(user.id);
             if !waife_names.is_empty() {
                 waife_names.push_str(", ");
             }
             waife_names.push_str(&user.html_link());
+            users.insert(user.id, user);
         }
 
-        truncate_names(&mut waife_names);
-
         ctx.reply_html(format!("获取成功！你今天的群老婆是 {waife_names}"))
             .send()
             .warn_on_error("waife")
@@ -321,117 +367,6 @@ fn get_waife(ctx: &mut Context, msg: &Message) -> Consumption {
     .into()
 }
 
-async fn add_admins_to_users(
-    users: &mut HashMap<UserId, WaifeUser>,
-    ctx: &TaskContext,
-) -> anyhow::Result<()> {
-    let admins = ctx
-        .app
-        .bot
-        .get_chat_administrators(ctx.chat_id)
-        .send()
-        .await?;
-    for member in admins {
-        if member.user.id == ctx.app.bot_id {
-            continue;
-        }
-        users.insert(member.user.id, WaifeUser::from_user(&member.user));
-    }
-    Ok(())
-}
-
-async fn check_and_add(
-    users: &mut HashMap<UserId, WaifeUser>,
-    ctx: &TaskContext,
-    user: WaifeUser,
-) -> bool {
-    let mut user_cache = ctx
-        .app
-        .db
-        .of()
-        .user(user.id)
-        .get_or_insert(|| UserCache {
-            chats: HashMap::new(),
-        })
-        .await;
-
-    if let Some(cache) = user_cache.chats.get(&ctx.chat_id)
-        && !cache.invalid() {
-            // we still need update user info
-            if cache.joined {
-                users.insert(user.id, user);
-            }
-            return cache.joined;
-        }
-
-    let membership = match ctx
-        .app
-        .bot
-        .get_chat_member(ctx.chat_id, user.id)
-        .send()
-        .await
-    {
-        Ok(x) => x,
-        Err(err) => {
-            user_cache
-                .chats
-                .insert(ctx.chat_id, UserChatCache::new(false));
-            warn!("failed to fetch memebership: {err}");
-            return false;
-        }
-    };
-
-    if membership.is_present() {
-        user_cache
-            .chats
-            .insert(ctx.chat_id, UserChatCache::new(true));
-        users.insert(user.id, user);
-    } else {
-        user_cache
-            .chats
-            .insert(ctx.chat_id, UserChatCache::new(false));
-        // 即使没有在群里也不要 remove user，防止退群引起老婆图缺失。
-        info!("Ignored out-of-group user：{}", user.full_name);
-    }
-
-    membership.is_present()
-}
-
-fn auto_add_user(ctx: &mut Context, msg: &Message) -> Consumption {
-    if msg.chat.is_private() {
-        return Consumption::just_next();
-    }
-    // Telegram says for backward compatibility, if the message was sent on behalf of a chat,
-    // the field contains a fake sender user in non-channel chats.
-    // But we don't need a fake user. Drop it.
-    if msg.sender_chat.is_some() {
-        return Consumption::just_next();
-    }
-    // 聊天群和绑定的 channel 可能有不同的人，为了保持 waife 不遇到晦气人，丢弃来自 forward 的消息。
-    if msg.is_automatic_forward() || msg.is_reply_to_channel() {
-        info!("Droped channel message/reply: {:?}", msg.text());
-        return Consumption::just_next();
-    }
-    let from = WaifeUser::from_user(msg.from.as_ref()?);
-    let ctx = ctx.task();
-    Consumption::next_with(async move {
-        let mut waife_storage = ctx
-            .app
-            .db
-            .of::<WaifeStatus>()
-            .chat(ctx.chat_id)
-            .get_or_insert(Default::default)
-            .await;
-
-        if waife_storage.users.is_empty() {
-            add_admins_to_users(&mut waife_storage.users, &ctx)
-                .warn_on_error("waife-auto-add")
-                .await
-        }
-        check_and_add(&mut waife_storage.users, &ctx, from).await;
-    })
-}
-
 #[derive(Clone, Copy)]
 enum WaifeGraphGenerator {
     Auto,
@@ -446,8 +381,13 @@ async fn generate_waife_graph(
     chat_id: ChatId,
     perfer: WaifeGraphGenerator,
 ) -> Result<Graph, &'static str> {
-    let Some(waife_storage) = app.db.of::<WaifeStatus>().chat(chat_id).get().await else {
-        return Err("群里还没人有老婆哦");
+    let waife_storage = match app.db.of::<WaifeStatus>().chat(chat_id).get().await {
+        Ok(Some(waife_storage)) => waife_storage,
+        Ok(None) => return Err("群里还没人有老婆哦"),
+        Err(err) => {
+            warn!(target: "waife", "{err}");
+            return Err(err.friendly());
+        }
     };
 
     if waife_storage.waife_of.is_empty() {
@@ -498,10 +438,8 @@ async fn generate_waife_graph(
                 perfer = WaifeGraphGenerator::Dot;
             }
         }
-        WaifeGraphGenerator::Neato => {
-            if used_userids.len() > 100 {
-                perfer = WaifeGraphGenerator::Fdp;
-            }
+        WaifeGraphGenerator::Neato if used_userids.len() > 100 => {
+            perfer = WaifeGraphGenerator::Fdp;
         }
         _ => {}
     };
@@ -580,6 +518,7 @@ fn on_waife_graph(ctx: &mut Context, _: &Message) -> Consumption {
                             .bot
                             .send_photo(ctx.chat_id, InputFile::memory(res))
                             .reply_parameters(ReplyParameters::new(ctx.message_id))
+                            .in_topic(ctx.thread_id)
                             .send()
                             .warn_on_error("waife-graph")
                             .await
@@ -608,57 +547,53 @@ fn on_waife_graph(ctx: &mut Context, _: &Message) -> Consumption {
     .into()
 }
 
-fn set_waife_limit(ctx: &mut Context, msg: &Message) -> Consumption {
+fn set_waife_limit(ctx: &mut Context, _msg: &Message) -> Consumption {
     let text = ctx.cmd?.content.to_ascii_lowercase();
     let ctx = ctx.task();
-    let sender_id = msg.from.as_ref()?.id;
     async move {
-        let has_admin_right = match ctx.app.bot.get_chat_member(ctx.chat_id, sender_id).send().await {
-            Ok(chat_member) => chat_member.is_privileged(),
-            Err(err) => {
-                warn!("Failed to check has_admin_right, fallback to false for userid: {sender_id}. {err}");
-                false
-            }
-        };
-        
-        if !has_admin_right {
-            ctx.reply_markdown("~You are not in the sudoers file. This incident will be reported.~ 只有管理员才能执行该命令哦。").send().warn_on_error("set-waife-limit").await;
-            return;
-        }
-
         let new_limit = match text.as_str() {
-            "" | "null" => {
-                None
-            }
-            num => {
-                Some(num.parse::<usize>())
-            }
-        }.transpose();
+            "" | "null" => None,
+            num => Some(num.parse::<usize>()),
+        }
+        .transpose();
 
         match new_limit {
             Err(_) => {
-                ctx.reply("不是一个合法的数字。请输入 usize 以内的整数，或者留空或null").send().warn_on_error("set-waife-limit").await;
-            },
+                ctx.reply("不是一个合法的数字。请输入 usize 以内的整数，或者留空或null")
+                    .send()
+                    .warn_on_error("set-waife-limit")
+                    .await;
+            }
             Ok(new_limit) => {
-                let mut waife_storage = ctx.app.db.of::<WaifeStatus>().chat(ctx.chat_id).get_or_insert(Default::default).await;
+                let mut waife_storage = match ctx
+                    .app
+                    .db
+                    .of::<WaifeStatus>()
+                    .chat(ctx.chat_id)
+                    .get_or_insert(Default::default)
+                    .await
+                {
+                    Ok(waife_storage) => waife_storage,
+                    Err(err) => return ctx.reply_db_error("set-waife-limit", err).await,
+                };
                 waife_storage.waife_limit = new_limit;
-                
+
                 let text = match new_limit {
                     None => "无限制".to_string(),
-                    Some(x) => x.to_string()
+                    Some(x) => x.to_string(),
                 };
-                ctx.reply(format!("设置成功！现在本群每人每日可抽取的老婆数为：{text}")).send().warn_on_error("set-waife-limit").await;
+                ctx.reply(format!(
+                    "设置成功！现在本群每人每日可抽取的老婆数为：{text}"
+                ))
+                .send()
+                .warn_on_error("set-waife-limit")
+                .await;
             }
         }
     }
     .into()
 }
 
-pub static ADD_USER: Module = Module {
-    kind: ModuleKind::General(None),
-    task: auto_add_user,
-};
-
 pub static GET_WAIFE: Module = Module {
     kind: ModuleKind::Command(ModuleDescription {
         name: "waife",
@@ -668,6 +603,7 @@ pub static GET_WAIFE: Module = Module {
             "琳酱会认识加入以来所有发言的用户和群管理员\n",
             "特别的，<code>/waife poly</code>可以让你有多个老婆——我们支持多元关系😋"
         )),
+        permission: Permission::Anyone,
     }),
     task: get_waife,
 };
@@ -677,6 +613,7 @@ pub static SET_WAIFE_LIMIT: Module = Module {
         name: "set_waife_limit",
         description: "设置老婆上限",
         description_detailed: Some(concat!("设置群老婆上限\n", "仅限管理员使用\n",)),
+        permission: Permission::ChatAdmin,
     }),
     task: set_waife_limit,
 };
@@ -691,6 +628,7 @@ pub static WAIFE_GRAPH: Module = Module {
             "默认会智能选择最合适的。",  
         )
         ),
+        permission: Permission::Anyone,
     }),
     task: on_waife_graph,
 };
@@ -0,0 +1,283 @@
+//! 入群欢迎
+//! ```text
+//! /welcome [on|off]
+//! /welcome set <模板>
+//! /welcome reset
+//! /welcome test
+//! ```
+//! 模板中可以使用 `{name}` `{mention}` `{username}` `{id}` `{chat}` `{count}`。
+//!
+//! 只有琳酱是管理员时才能收到成员入群的消息。打开 [crate::mods::captcha] 时，通过验证后才会欢迎。
+
+use serde::{Deserialize, Serialize};
+use teloxide_core::prelude::*;
+use teloxide_core::types::*;
+
+use crate::Consumption;
+use crate::linquebot::db::{DataType, DbData, WarnDbError};
+use crate::linquebot::msg_context::Context;
+use crate::linquebot::*;
+use crate::mods::{captcha, roster};
+use crate::utils::escape_html;
+use crate::utils::markup::mention_id;
+use crate::utils::split_args;
+use crate::utils::telegram::prelude::*;
+
+const DEFAULT_TEMPLATE: &str = "欢迎 {mention} 加入 {chat}！";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct WelcomeSetting {
+    enabled: bool,
+    template: Option<String>,
+}
+
+impl DbData for WelcomeSetting {
+    const KEY: &str = "welcome::WelcomeSetting";
+}
+
+pub static DATA_TYPES: &[DataType] = &[DataType::of::<WelcomeSetting>()];
+
+impl WelcomeSetting {
+    fn template(&self) -> &str {
+        self.template.as_deref().unwrap_or(DEFAULT_TEMPLATE)
+    }
+}
+
+/// 模板变量的值，都是 HTML
+struct Vars {
+    name: String,
+    mention: String,
+    username: String,
+    id: String,
+    chat: String,
+    count: String,
+}
+
+impl Vars {
+    fn new(user: &User, chat: &Chat, count: usize) -> Self {
+        let name = escape_html(&user.full_name());
+        Self {
+            mention: mention_id(user.id, user.full_name()).html(),
+            username: user
+                .username
+                .as_ref()
+                .map(|username| format!("@{username}"))
+                .unwrap_or_else(|| name.clone()),
+            id: user.id.to_string(),
+            chat: escape_html(chat.title().unwrap_or("本群")),
+            count: count.to_string(),
+            name,
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&str> {
+        Some(match key {
+            "name" => &self.name,
+            "mention" => &self.mention,
+            "username" => &self.username,
+            "id" => &self.id,
+            "chat" => &self.chat,
+            "count" => &self.count,
+            _ => return None,
+        })
+    }
+}
+
+/// 把模板渲染成 HTML，不认识的 `{...}` 原样保留
+fn render(template: &str, vars: &Vars) -> String {
+    let mut res = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        res.push_str(&escape_html(&rest[..start]));
+        rest = &rest[start..];
+        let value = rest
+            .find('}')
+            .and_then(|end| Some((vars.get(&rest[1..end])?, end)));
+        match value {
+            Some((value, end)) => {
+                res.push_str(value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                res.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    res.push_str(&escape_html(rest));
+    res
+}
+
+fn on_welcome(ctx: &mut Context, msg: &Message) -> Consumption {
+    if msg.chat.is_private() {
+        return ctx
+            .task()
+            .reply("只能在群里设置入群欢迎哦")
+            .send()
+            .warn_on_error("welcome")
+            .into();
+    }
+    let [cmd, rest] = split_args(ctx.cmd?.content);
+    let (cmd, template) = (cmd.to_string(), rest.trim().to_string());
+    let chat = msg.chat.clone();
+    let from = msg.from.as_ref()?.clone();
+    let ctx = ctx.task();
+    async move {
+        let mut setting = match ctx
+            .app
+            .db
+            .of::<WelcomeSetting>()
+            .chat(ctx.chat_id)
+            .get_or_insert(Default::default)
+            .await
+        {
+            Ok(setting) => setting,
+            Err(err) => return ctx.reply_db_error("welcome", err).await,
+        };
+        let text = match cmd.as_str() {
+            "" => {
+                let state = if setting.enabled { "打开" } else { "关闭" };
+                format!(
+                    "本群的入群欢迎已{state}，当前模板：\n{}",
+                    setting.template()
+                )
+            }
+            "on" => {
+                setting.enabled = true;
+                "本群的入群欢迎已打开".to_string()
+            }
+            "off" => {
+                setting.enabled = false;
+                "本群的入群欢迎已关闭".to_string()
+            }
+            "set" if !template.is_empty() => {
+                setting.template = Some(template);
+                setting.enabled = true;
+                "入群欢迎模板已更新".to_string()
+            }
+            "reset" => {
+                setting.template = None;
+                format!("入群欢迎模板已恢复为：\n{DEFAULT_TEMPLATE}")
+            }
+            "test" => {
+                let template = setting.template().to_string();
+                drop(setting);
+                let count = match roster::members(ctx.app, ctx.chat_id).await {
+                    Ok(members) => members.len(),
+                    Err(err) => return ctx.reply_db_error("welcome", err).await,
+                };
+                let html = render(&template, &Vars::new(&from, &chat, count));
+                ctx.reply_html(html).send().warn_on_error("welcome").await;
+                return;
+            }
+            _ => "用法：/welcome [on|off]、/welcome set <模板>、/welcome reset、/welcome test"
+                .to_string(),
+        };
+        drop(setting);
+        ctx.reply(text).send().warn_on_error("welcome").await;
+    }
+    .into()
+}
+
+/// 发送入群欢迎，没有打开时什么也不做
+pub async fn greet(app: &'static App, chat: &Chat, user: &User) {
+    let template = {
+        let Some(setting) = app
+            .db
+            .of::<WelcomeSetting>()
+            .chat(chat.id)
+            .get()
+            .await
+            .ok_or_warn("welcome")
+            .flatten()
+        else {
+            return;
+        };
+        if !setting.enabled {
+            return;
+        }
+        setting.template().to_string()
+    };
+    let Some(count) = roster::members(app, chat.id)
+        .await
+        .ok_or_warn("welcome")
+        .map(|members| members.len())
+    else {
+        return;
+    };
+    let request = app
+        .bot
+        .send_message(chat.id, render(&template, &Vars::new(user, chat, count)))
+        .parse_mode(ParseMode::Html);
+    app.outbox
+        .send(chat.id, &request)
+        .warn_on_error("welcome")
+        .await;
+}
+
+fn on_chat_member(app: &'static App, data: &ChatMemberUpdated) -> Consumption {
+    let joined = !data.old_chat_member.is_present() && data.new_chat_member.is_present();
+    if !joined || data.new_chat_member.user.is_bot {
+        return Consumption::just_next();
+    }
+    let user = data.new_chat_member.user.clone();
+    let chat = data.chat.clone();
+    Consumption::next_with(async move {
+        // 打开入群验证时，通过验证后再欢迎
+        if captcha::enabled(app, chat.id).await {
+            return;
+        }
+        greet(app, &chat, &user).await;
+    })
+}
+
+pub static MODULE: Module = Module {
+    kind: ModuleKind::Command(ModuleDescription {
+        name: "welcome",
+        description: "设置入群欢迎",
+        description_detailed: Some(concat!(
+            "/welcome on 或 /welcome off 打开或关闭入群欢迎\n",
+            "/welcome set <模板> 设置欢迎消息，可以使用 {name} {mention} {username} {id} {chat} {count}\n",
+            "/welcome reset 恢复默认模板\n",
+            "/welcome test 预览欢迎消息\n",
+            "琳酱需要是管理员才能知道有人入群"
+        )),
+        permission: Permission::ChatAdmin,
+    }),
+    task: on_welcome,
+};
+
+pub static ON_CHAT_MEMBER: MicroTask = MicroTask::OnChatMember(on_chat_member);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> Vars {
+        Vars {
+            name: "A&lt;B".to_string(),
+            mention: "<a>A</a>".to_string(),
+            username: "@a".to_string(),
+            id: "1".to_string(),
+            chat: "群".to_string(),
+            count: "3".to_string(),
+        }
+    }
+
+    #[test]
+    fn render_vars() {
+        assert_eq!(
+            render("欢迎 {mention}，你是第 {count} 位 {name}", &vars()),
+            "欢迎 <a>A</a>，你是第 3 位 A&lt;B"
+        );
+    }
+
+    #[test]
+    fn render_unknown() {
+        assert_eq!(render("{foo} {id} {", &vars()), "{foo} 1 {");
+        assert_eq!(
+            render("<b>{username}</b>", &vars()),
+            "&lt;b&gt;@a&lt;/b&gt;"
+        );
+    }
+}
@@ -1,34 +1,89 @@
-use crate::{App, ModuleKind};
-use log::{error, trace};
-use teloxide_core::types::Message;
+use crate::utils::telegram::prelude::WarnOnError;
+use crate::{
+    App, Module, ModuleKind, TaskResult, chat_modules::ChatModules, permission::Permission,
+};
+use log::{error, trace, warn};
+use teloxide_core::{prelude::*, types::Message};
 
-pub fn resolve(app: &'static App, message: Message) {
+pub async fn resolve(app: &'static App, message: Message) {
     if let Some(text) = message.text() {
         trace!(target: "main-loop", "get message: {text}");
     }
     if let Some(sticker) = message.sticker() {
         trace!(target: "main-loop", "get sticker: {sticker:?}");
     }
+    let chat_modules = ChatModules::load(app, message.chat.id)
+        .await
+        .unwrap_or_else(|err| {
+            warn!(target: "main-loop", "failed to load modules of {}: {err}", message.chat.id);
+            Default::default()
+        });
     let mut context = app.create_message_context(&message);
     for module in app.modules {
+        if context.edited && !app.handles_edit(module) {
+            continue;
+        }
         if let ModuleKind::Command(desc) = &module.kind
-            && !context.matches_command(desc) {
-                continue;
+            && !context.matches_command(desc)
+        {
+            continue;
+        }
+        if !chat_modules.is_enabled(module) {
+            // 被关闭的命令不再交给后面的模块处理
+            if let ModuleKind::Command(_) = &module.kind {
+                break;
             }
+            continue;
+        }
+        if let ModuleKind::Command(desc) = &module.kind
+            && desc.permission != Permission::Anyone
+        {
+            // 检查权限可能要请求 Telegram，放到单独的任务中，不阻塞后面的更新
+            tokio::spawn(run_privileged(
+                app,
+                module,
+                desc.permission,
+                message.clone(),
+            ));
+            break;
+        }
         let task_result = (module.task)(&mut context, &message);
         if let Some(task) = task_result.task {
-            tokio::spawn(async move {
-                let result = tokio::spawn(task);
-                let Err(err) = result.await else {
-                    return;
-                };
-                if err.is_panic() {
-                    error!("module {:?} panicked: {err}", module.name());
-                }
-            });
+            tokio::spawn(run_task(module, task));
         }
         if !task_result.next {
             break;
         }
     }
 }
+
+async fn run_task(module: &'static Module, task: TaskResult) {
+    let Err(err) = tokio::spawn(task).await else {
+        return;
+    };
+    if err.is_panic() {
+        error!("module {:?} panicked: {err}", module.name());
+    }
+}
+
+/// 有权限时执行命令，否则回复拒绝的原因
+async fn run_privileged(
+    app: &'static App,
+    module: &'static Module,
+    permission: Permission,
+    message: Message,
+) {
+    let mut context = app.create_message_context(&message);
+    if !app.permissions.check(&app.bot, &message, permission).await {
+        context
+            .task()
+            .reply_html(permission.refusal())
+            .send()
+            .warn_on_error("permission-refusal")
+            .await;
+        return;
+    }
+    if let Some(task) = (module.task)(&mut context, &message).task {
+        run_task(module, task).await;
+    }
+}
@@ -1,3 +1,4 @@
+use crate::linquebot::backlog::STALE_SECS;
 use crate::linquebot::*;
 use chrono::Utc;
 use log::{trace, warn};
@@ -34,10 +35,25 @@ fn update_name(upd: &UpdateKind) -> &'static str {
 
 pub static ALLOWED_UPDATES: &[AllowedUpdate] = &[
     AllowedUpdate::Message,
+    AllowedUpdate::EditedMessage,
     AllowedUpdate::CallbackQuery,
+    AllowedUpdate::InlineQuery,
     AllowedUpdate::MyChatMember,
+    AllowedUpdate::ChatMember,
 ];
 
+fn spawn_micro_task(task: TaskResult) {
+    tokio::spawn(async move {
+        let result = tokio::spawn(task);
+        let Err(err) = result.await else {
+            return;
+        };
+        if err.is_panic() {
+            log::error!("microtask panicked: {err}");
+        }
+    });
+}
+
 pub async fn resolve(app: &'static App, update: Update) {
     let now = Utc::now();
 
@@ -47,15 +63,7 @@ pub async fn resolve(app: &'static App, update: Update) {
                 if let MicroTask::$kind(task) = task {
                     let task_result = task(app, &$data);
                     if let Some(task) = task_result.task {
-                        tokio::spawn(async move {
-                            let result = tokio::spawn(task);
-                            let Err(err) = result.await else {
-                                return;
-                            };
-                            if err.is_panic() {
-                                log::error!("microtask panicked: {err}");
-                            }
-                        });
+                        spawn_micro_task(task);
                     }
                     if !task_result.next {
                         break;
@@ -66,22 +74,42 @@ pub async fn resolve(app: &'static App, update: Update) {
     }
     match update.kind {
         UpdateKind::Message(message) => {
-            if now.signed_duration_since(message.date).num_seconds() > 30 {
-                warn!(
-                    target: "main-loop",
-                    "skipped message {}s ago: {:?}",
-                    now.signed_duration_since(message.date).num_seconds(),
-                    message.text()
-                );
+            let ago = now.signed_duration_since(message.date).num_seconds();
+            if ago > STALE_SECS {
+                if !app.backlog.replay(app, &message) {
+                    warn!(
+                        target: "main-loop",
+                        "skipped message {ago}s ago: {:?}",
+                        message.text()
+                    );
+                    return;
+                }
+                trace!(target: "main-loop", "replay message {ago}s ago: {:?}", message.text());
+            }
+            super::message::resolve(app, message).await;
+        }
+        UpdateKind::EditedMessage(message) => {
+            let ago = message.edit_date().map_or(i64::MAX, |date| {
+                now.signed_duration_since(date).num_seconds()
+            });
+            if ago > STALE_SECS {
+                trace!(target: "main-loop", "skipped edited message {ago}s ago: {:?}", message.text());
                 return;
             }
-            super::message::resolve(app, message);
+            super::message::resolve(app, message).await;
         }
         UpdateKind::CallbackQuery(data) => {
             trace!("get callback query: {:?}", data.data);
-            handle_kind!(OnCallbackQuery, data)
+            if let Some(task) = callback::dispatch(app, &data).task {
+                spawn_micro_task(task);
+            }
         }
         UpdateKind::MyChatMember(data) => handle_kind!(OnMyChatMember, data),
+        UpdateKind::ChatMember(data) => handle_kind!(OnChatMember, data),
+        UpdateKind::InlineQuery(data) => {
+            trace!("get inline query: {:?}", data.query);
+            handle_kind!(OnInlineQuery, data)
+        }
         _ => {
             warn!(
                 "get unimplemented update kind: {:?}",
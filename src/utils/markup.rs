@@ -0,0 +1,457 @@
+//! 自动转义的消息格式
+//!
+//! 用 [Markup] 拼出消息，再渲染成 Telegram 的 HTML 或 MarkdownV2，所有文字和链接都会被正确转义：
+//! ```ignore
+//! let markup = mention(&user) + " 该" + bold(todo.thing) + "啦！";
+//! ctx.reply_html(markup.html());
+//! ```
+//!
+//! Telegram 不支持的嵌套（链接中的链接、引用中的引用、链接和格式中的代码等）会只保留内层的文字。
+
+use std::ops::Add;
+
+use teloxide_core::types::{User, UserId};
+
+use super::escape_html;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    Text(String),
+    Bold(Markup),
+    Spoiler(Markup),
+    Link(String, Markup),
+    /// 单行代码，换行会被替换成空格
+    Code(String),
+    /// 引用块，总是单独占据几行
+    Blockquote(Markup),
+}
+
+/// 一段带格式的文字
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Markup(Vec<Node>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Html,
+    Markdown,
+    Plain,
+}
+
+/// 渲染时已经在哪些格式里面
+#[derive(Debug, Clone, Copy, Default)]
+struct Scope {
+    bold: bool,
+    spoiler: bool,
+    link: bool,
+    quote: bool,
+}
+
+impl Scope {
+    fn formatted(&self) -> bool {
+        self.bold || self.spoiler || self.link
+    }
+}
+
+/// MarkdownV2 中需要转义的字符
+const MARKDOWN_SPECIAL: &str = "_*[]()~`>#+-=|{}.!\\";
+
+pub fn escape_markdown(text: &str) -> String {
+    escape_chars(text, MARKDOWN_SPECIAL)
+}
+
+fn escape_chars(text: &str, special: &str) -> String {
+    let mut res = String::with_capacity(text.len());
+    for ch in text.chars() {
+        if special.contains(ch) {
+            res.push('\\');
+        }
+        res.push(ch);
+    }
+    res
+}
+
+impl Markup {
+    #[cfg(test)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|node| match node {
+            Node::Text(text) | Node::Code(text) => text.is_empty(),
+            Node::Bold(inner)
+            | Node::Spoiler(inner)
+            | Node::Link(_, inner)
+            | Node::Blockquote(inner) => inner.is_empty(),
+        })
+    }
+
+    /// Telegram HTML，配合 `ParseMode::Html` 使用
+    pub fn html(&self) -> String {
+        self.render(Format::Html)
+    }
+
+    /// Telegram MarkdownV2，配合 `ParseMode::MarkdownV2` 使用
+    pub fn markdown(&self) -> String {
+        self.render(Format::Markdown)
+    }
+
+    /// 去掉格式后显示出来的文字
+    pub fn plain(&self) -> String {
+        self.render(Format::Plain)
+    }
+
+    fn render(&self, format: Format) -> String {
+        let mut writer = Writer {
+            format,
+            out: String::new(),
+            empty: true,
+            line_start: true,
+        };
+        self.render_to(&mut writer, Scope::default());
+        writer.out
+    }
+
+    fn render_to(&self, w: &mut Writer, scope: Scope) {
+        let mut after_quote = false;
+        for node in &self.0 {
+            if after_quote && !w.line_start {
+                w.text("\n", "\n");
+            }
+            after_quote = false;
+            match node {
+                Node::Text(text) => match w.format {
+                    Format::Html => w.text(text, &escape_html(text)),
+                    Format::Markdown if scope.quote => {
+                        w.text(text, &escape_markdown(text).replace('\n', "\n>"))
+                    }
+                    Format::Markdown => w.text(text, &escape_markdown(text)),
+                    Format::Plain => w.text(text, text),
+                },
+                Node::Code(code) => {
+                    let code = code.replace('\n', " ");
+                    match w.format {
+                        _ if code.is_empty() => {}
+                        Format::Html if !scope.formatted() => w.wrap(("<code>", "</code>"), |w| {
+                            w.text(&code, &escape_html(&code))
+                        }),
+                        Format::Markdown if !scope.formatted() => {
+                            w.wrap(("`", "`"), |w| w.text(&code, &escape_chars(&code, "`\\")))
+                        }
+                        Format::Html => w.text(&code, &escape_html(&code)),
+                        Format::Markdown => w.text(&code, &escape_markdown(&code)),
+                        Format::Plain => w.text(&code, &code),
+                    }
+                }
+                Node::Bold(inner) if !scope.bold && !inner.is_empty() => {
+                    let scope = Scope {
+                        bold: true,
+                        ..scope
+                    };
+                    let marks = w.marks(("<b>", "</b>"), ("*", "*"));
+                    w.wrap(marks, |w| inner.render_to(w, scope));
+                }
+                Node::Spoiler(inner) if !scope.spoiler && !inner.is_empty() => {
+                    let scope = Scope {
+                        spoiler: true,
+                        ..scope
+                    };
+                    let marks = w.marks(("<tg-spoiler>", "</tg-spoiler>"), ("||", "||"));
+                    w.wrap(marks, |w| inner.render_to(w, scope));
+                }
+                Node::Link(url, inner) if !scope.link && !inner.is_empty() => {
+                    let scope = Scope {
+                        link: true,
+                        ..scope
+                    };
+                    let open = format!("<a href=\"{}\">", escape_html(url));
+                    let close = format!("]({})", escape_chars(url, ")\\"));
+                    let marks = w.marks((&open, "</a>"), ("[", &close));
+                    w.wrap(marks, |w| inner.render_to(w, scope));
+                }
+                // 引用块只能在最外层，并且从新的一行开始
+                Node::Blockquote(inner)
+                    if !scope.quote && !scope.formatted() && !inner.is_empty() =>
+                {
+                    if !w.line_start {
+                        w.text("\n", "\n");
+                    }
+                    let scope = Scope {
+                        quote: true,
+                        ..scope
+                    };
+                    let marks = w.marks(("<blockquote>", "</blockquote>"), (">", ""));
+                    w.wrap(marks, |w| inner.render_to(w, scope));
+                    // 以换行结尾时，最后一行不属于引用
+                    if w.format == Format::Markdown && w.out.ends_with("\n>") {
+                        w.out.pop();
+                    }
+                    after_quote = true;
+                }
+                Node::Bold(inner)
+                | Node::Spoiler(inner)
+                | Node::Link(_, inner)
+                | Node::Blockquote(inner) => inner.render_to(w, scope),
+            }
+        }
+    }
+}
+
+/// 渲染的结果，以及显示出来的文字的状态
+struct Writer {
+    format: Format,
+    out: String,
+    /// 还没有显示任何文字
+    empty: bool,
+    /// 显示的文字为空或者以换行结尾
+    line_start: bool,
+}
+
+impl Writer {
+    /// 写入显示为 `visible` 的文字
+    fn text(&mut self, visible: &str, rendered: &str) {
+        if !visible.is_empty() {
+            self.empty = false;
+            self.line_start = visible.ends_with('\n');
+        }
+        self.out.push_str(rendered);
+    }
+
+    fn marks<'a>(
+        &self,
+        html: (&'a str, &'a str),
+        markdown: (&'a str, &'a str),
+    ) -> (&'a str, &'a str) {
+        match self.format {
+            Format::Html => html,
+            Format::Markdown => markdown,
+            Format::Plain => ("", ""),
+        }
+    }
+
+    fn wrap(&mut self, (open, close): (&str, &str), inner: impl FnOnce(&mut Self)) {
+        self.out.push_str(open);
+        inner(self);
+        // MarkdownV2 的引用块要从行首开始，所以把结尾的换行移到结束标记后面
+        let mut newlines = String::new();
+        while self.format == Format::Markdown {
+            let len = match () {
+                _ if self.out.ends_with('\n') => 1,
+                _ if self.out.ends_with("\n>") => 2,
+                _ => break,
+            };
+            newlines.insert_str(0, &self.out[self.out.len() - len..]);
+            self.out.truncate(self.out.len() - len);
+        }
+        self.out.push_str(close);
+        self.out.push_str(&newlines);
+    }
+}
+
+impl From<&str> for Markup {
+    fn from(text: &str) -> Self {
+        text.to_string().into()
+    }
+}
+
+impl From<String> for Markup {
+    fn from(text: String) -> Self {
+        Self(vec![Node::Text(text)])
+    }
+}
+
+impl From<&String> for Markup {
+    fn from(text: &String) -> Self {
+        text.clone().into()
+    }
+}
+
+impl<T: Into<Markup>> Add<T> for Markup {
+    type Output = Markup;
+
+    fn add(mut self, rhs: T) -> Markup {
+        self.0.extend(rhs.into().0);
+        self
+    }
+}
+
+impl<T: Into<Markup>> FromIterator<T> for Markup {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        Self(iter.into_iter().flat_map(|part| part.into().0).collect())
+    }
+}
+
+pub fn text(text: impl Into<String>) -> Markup {
+    Markup::from(text.into())
+}
+
+pub fn bold(inner: impl Into<Markup>) -> Markup {
+    Markup(vec![Node::Bold(inner.into())])
+}
+
+pub fn spoiler(inner: impl Into<Markup>) -> Markup {
+    Markup(vec![Node::Spoiler(inner.into())])
+}
+
+pub fn link(url: impl Into<String>, inner: impl Into<Markup>) -> Markup {
+    Markup(vec![Node::Link(url.into(), inner.into())])
+}
+
+pub fn code(code: impl Into<String>) -> Markup {
+    Markup(vec![Node::Code(code.into())])
+}
+
+pub fn blockquote(inner: impl Into<Markup>) -> Markup {
+    Markup(vec![Node::Blockquote(inner.into())])
+}
+
+/// 用 id 提及一个人，不需要对方有用户名
+pub fn mention_id(user_id: UserId, inner: impl Into<Markup>) -> Markup {
+    link(format!("tg://user?id={user_id}"), inner)
+}
+
+/// 以全名提及一个人
+pub fn mention(user: &User) -> Markup {
+    link(user.url().to_string(), user.full_name())
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::SmallRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::super::split::{Token, tokenize_html, tokenize_markdown};
+    use super::*;
+
+    #[test]
+    fn render() {
+        let markup = text("a<b>&c ") + bold(link("https://x.y/?a=1&b=\"2\")", "*1*")) + " ok.";
+        assert_eq!(
+            markup.html(),
+            "a&lt;b&gt;&amp;c <b><a href=\"https://x.y/?a=1&amp;b=&quot;2&quot;)\">*1*</a></b> ok."
+        );
+        assert_eq!(
+            markup.markdown(),
+            "a<b\\>&c *[\\*1\\*](https://x.y/?a=1&b=\"2\"\\))* ok\\."
+        );
+        assert_eq!(markup.plain(), "a<b>&c *1* ok.");
+
+        let quoted = text("说：") + blockquote("第一行\n第二行") + "完";
+        assert_eq!(
+            quoted.html(),
+            "说：\n<blockquote>第一行\n第二行</blockquote>\n完"
+        );
+        assert_eq!(quoted.markdown(), "说：\n>第一行\n>第二行\n完");
+        assert_eq!(bold(bold("a") + code("`x`")).markdown(), "*a\\`x\\`*");
+        assert!((Markup::new() + bold("") + spoiler(code(""))).is_empty());
+    }
+
+    const ALPHABET: &[&str] = &[
+        "a", "琳", " ", "\n", "<", ">", "&", "\"", "'", ";", "*", "_", "[", "]", "(", ")", "~",
+        "`", "#", "+", "-", "=", "|", "{", "}", ".", "!", "\\", "&amp;", "</b>", "😀",
+    ];
+
+    fn random_text(rng: &mut SmallRng) -> String {
+        let len = rng.random_range(0..8);
+        (0..len)
+            .map(|_| ALPHABET[rng.random_range(0..ALPHABET.len())])
+            .collect()
+    }
+
+    fn random_markup(rng: &mut SmallRng, depth: u32) -> Markup {
+        let len = rng.random_range(1..4);
+        (0..len)
+            .map(|_| {
+                let kind = if depth == 0 {
+                    0
+                } else {
+                    rng.random_range(0..7)
+                };
+                let inner = |rng: &mut SmallRng| random_markup(rng, depth - 1);
+                match kind {
+                    0 => text(random_text(rng)),
+                    1 => bold(inner(rng)),
+                    2 => spoiler(inner(rng)),
+                    3 => link(random_text(rng), inner(rng)),
+                    4 => code(random_text(rng)),
+                    5 => blockquote(inner(rng)),
+                    _ => mention_id(UserId(rng.random()), inner(rng)),
+                }
+            })
+            .collect()
+    }
+
+    fn unescape_html(entity: &str) -> &str {
+        match entity {
+            "&lt;" => "<",
+            "&gt;" => ">",
+            "&amp;" => "&",
+            "&quot;" => "\"",
+            _ => panic!("unknown entity {entity}"),
+        }
+    }
+
+    fn unescape_markdown(atom: &str) -> &str {
+        &atom[1..]
+    }
+
+    /// 解析渲染的结果，检查标记是闭合的，返回显示出来的文字
+    fn visible(tokens: Vec<Token>, allowed: &[&str], unescape: fn(&str) -> &str) -> String {
+        let mut stack = vec![];
+        let mut res = String::new();
+        for token in tokens {
+            match token {
+                Token::Text(text) => res.push_str(text),
+                Token::Atom(atom, 0) => assert_eq!(atom, ">"),
+                Token::Atom(atom, _) => res.push_str(unescape(atom)),
+                Token::Open(open, close) => {
+                    assert!(
+                        allowed.iter().any(|tag| open.starts_with(tag)),
+                        "unexpected {open}"
+                    );
+                    stack.push(close);
+                }
+                Token::Close(close) => {
+                    let open = stack.pop().expect("unbalanced close");
+                    assert!(
+                        close == open || open.starts_with("]("),
+                        "{open} closed by {close}"
+                    );
+                }
+            }
+        }
+        assert!(stack.is_empty(), "unclosed {stack:?}");
+        res
+    }
+
+    #[test]
+    fn user_input_cannot_escape() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        for _ in 0..2000 {
+            let markup = random_markup(&mut rng, 3);
+            let plain = markup.plain();
+
+            let html = markup.html();
+            let tags = [
+                "<b>",
+                "<tg-spoiler>",
+                "<a href=\"",
+                "<code>",
+                "<blockquote>",
+            ];
+            assert_eq!(
+                visible(tokenize_html(&html), &tags, unescape_html),
+                plain,
+                "{html}"
+            );
+
+            let markdown = markup.markdown();
+            let markers = ["*", "||", "[", "`"];
+            assert_eq!(
+                visible(tokenize_markdown(&markdown), &markers, unescape_markdown),
+                plain,
+                "{markdown}"
+            );
+        }
+    }
+}
@@ -1,5 +1,8 @@
 pub mod base64;
+pub mod markup;
 pub mod pattern;
+pub mod split;
+pub mod time;
 
 use std::{
     collections::{HashMap, HashSet},
@@ -43,21 +46,14 @@ pub fn escape_html(str: &str) -> String {
         match ch {
             '<' => ret.push_str("&lt;"),
             '>' => ret.push_str("&gt;"),
-            '&' => ret.push_str("&amp"),
+            '&' => ret.push_str("&amp;"),
+            '"' => ret.push_str("&quot;"),
             x => ret.push(x),
         }
     }
     ret
 }
 
-pub fn split_n<const N: usize>(src: &str) -> (Vec<&str>, Option<&str>) {
-    let mut it = src.split(|c: char| c.is_whitespace());
-    let pre = (1..N)
-        .map_while(|_| it.find(|s| !s.is_empty()))
-        .collect::<Vec<_>>();
-    (pre, it.remainder().map(|str| str.trim()))
-}
-
 pub fn split_args<const N: usize>(src: &str) -> [&str; N] {
     let mut res: [&str; N] = [""; N];
     let mut it = src.split(|c: char| c.is_whitespace());
@@ -114,22 +110,59 @@ pub mod telegram {
         }
         use reqwest::Response;
         use teloxide_core::{
-            types::{ChatId, Message, User},
             Bot,
+            payloads::{SendMessage, SendPhoto, SendSticker},
+            requests::HasPayload,
+            types::{ChatId, Message, ThreadId, User},
         };
 
+        /// 带有 `message_thread_id` 的请求
+        pub trait TopicPayload {
+            fn set_thread_id(&mut self, thread_id: Option<ThreadId>);
+        }
+
+        macro_rules! impl_topic_payload {
+            ($($payload:ty),*) => {$(
+                impl TopicPayload for $payload {
+                    fn set_thread_id(&mut self, thread_id: Option<ThreadId>) {
+                        self.message_thread_id = thread_id;
+                    }
+                }
+            )*};
+        }
+
+        impl_topic_payload!(SendMessage, SendPhoto, SendSticker);
+
+        pub trait InTopic {
+            /// 发送到论坛话题中，`None` 时发送到聊天本身
+            fn in_topic(self, thread_id: Option<ThreadId>) -> Self;
+        }
+
+        impl<R> InTopic for R
+        where
+            R: HasPayload,
+            R::Payload: TopicPayload,
+        {
+            fn in_topic(mut self, thread_id: Option<ThreadId>) -> Self {
+                self.payload_mut().set_thread_id(thread_id);
+                self
+            }
+        }
+
         pub trait UserExtension {
             fn html_link(&self) -> String;
         }
 
         impl UserExtension for User {
             fn html_link(&self) -> String {
-                format!("<a href=\"{}\">{}</a>", self.url(), self.full_name())
+                crate::utils::markup::mention(self).html()
             }
         }
 
         pub trait MessageExtension {
             fn is_reply_to_channel(&self) -> bool;
+            /// 消息所在的论坛话题
+            fn topic_id(&self) -> Option<ThreadId>;
         }
 
         impl MessageExtension for Message {
@@ -138,12 +171,18 @@ pub mod telegram {
                     .and_then(|msg| msg.sender_chat.as_ref())
                     .is_some_and(|chat| chat.is_channel())
             }
+
+            fn topic_id(&self) -> Option<ThreadId> {
+                // 普通超级群中的回复也有 thread_id，只有论坛话题中的才需要带上
+                self.thread_id.filter(|_| self.is_topic_message)
+            }
         }
 
         pub trait BotExtension {
             async fn send_sticker_by_file_id(
                 &self,
                 chat_id: ChatId,
+                thread_id: Option<ThreadId>,
                 file_id: &str,
             ) -> reqwest::Result<Response>;
         }
@@ -152,14 +191,19 @@ pub mod telegram {
             async fn send_sticker_by_file_id(
                 &self,
                 chat_id: ChatId,
+                thread_id: Option<ThreadId>,
                 file_id: &str,
             ) -> reqwest::Result<Response> {
+                let thread = thread_id
+                    .map(|thread| format!("&message_thread_id={thread}"))
+                    .unwrap_or_default();
                 reqwest::get(format!(
-                    "{}bot{}/sendSticker?chat_id={}&sticker={}",
+                    "{}bot{}/sendSticker?chat_id={}&sticker={}{}",
                     self.api_url(),
                     self.token(),
                     chat_id,
-                    file_id
+                    file_id,
+                    thread
                 ))
                 .await
             }
@@ -169,23 +213,6 @@ pub mod telegram {
 
 #[cfg(test)]
 mod tests {
-    #[test]
-    fn split_n_tests() {
-        use crate::utils::split_n;
-
-        assert_eq!(split_n::<3>(""), (vec![], None));
-        assert_eq!(split_n::<3>("11 22 33"), (vec!["11", "22"], Some("33")));
-        assert_eq!(split_n::<4>("11 22 33"), (vec!["11", "22", "33"], None));
-        assert_eq!(
-            split_n::<3>("11  22  33  44"),
-            (vec!["11", "22"], Some("33  44"))
-        );
-        assert_eq!(
-            split_n::<3>("  11  22  33  44  "),
-            (vec!["11", "22"], Some("33  44"))
-        );
-    }
-
     #[test]
     fn split_args_test() {
         use crate::utils::split_args;
@@ -198,4 +225,19 @@ mod tests {
             ["11", "22", "33  44"]
         );
     }
+
+    #[test]
+    fn topic_id_test() {
+        use crate::test_utils::fabricator::fab_text_message;
+        use crate::utils::telegram::prelude::MessageExtension;
+        use teloxide_core::types::{MessageId, ThreadId};
+
+        let mut msg = fab_text_message("hi");
+        assert_eq!(msg.topic_id(), None);
+        // 普通超级群中的回复
+        msg.thread_id = Some(ThreadId(MessageId(42)));
+        assert_eq!(msg.topic_id(), None);
+        msg.is_topic_message = true;
+        assert_eq!(msg.topic_id(), Some(ThreadId(MessageId(42))));
+    }
 }
@@ -0,0 +1,370 @@
+//! 把过长的消息切成几段
+//!
+//! Telegram 限制每条消息解析后最多 4096 个字符（按 UTF-16 计算）。
+//! 切分时优先在空行、换行、空白处切开，不会切开 HTML 实体、转义字符和标签；
+//! 跨越切分点的 HTML 标签和 MarkdownV2 标记会在前一段末尾闭合，并在后一段开头重新打开。
+
+use teloxide_core::types::ParseMode;
+
+/// 单条消息的最大长度
+pub const MESSAGE_LIMIT: usize = 4096;
+
+fn len16(text: &str) -> usize {
+    text.encode_utf16().count()
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum Token<'a> {
+    /// 可以在任意字符处切开的文字
+    Text(&'a str),
+    /// 不能切开的一段，以及它显示出来的长度
+    Atom(&'a str, usize),
+    /// 开始标记，以及对应的结束标记
+    Open(&'a str, String),
+    Close(&'a str),
+}
+
+pub(super) fn tokenize_html(text: &str) -> Vec<Token<'_>> {
+    let mut tokens = vec![];
+    let mut rest = text;
+    while !rest.is_empty() {
+        let (token, len) = if rest.starts_with('<')
+            && let Some(end) = rest.find('>')
+        {
+            let tag = &rest[..=end];
+            if tag.starts_with("</") {
+                (Token::Close(tag), tag.len())
+            } else {
+                let name = tag[1..tag.len() - 1]
+                    .split(|c: char| c.is_whitespace())
+                    .next()
+                    .unwrap_or_default();
+                (Token::Open(tag, format!("</{name}>")), tag.len())
+            }
+        } else if rest.starts_with('&')
+            && let Some(end) = rest.find(';')
+        {
+            (Token::Atom(&rest[..=end], 1), end + 1)
+        } else {
+            let first = rest.chars().next().map_or(1, char::len_utf8);
+            let end = rest[first..]
+                .find(['<', '&'])
+                .map_or(rest.len(), |end| end + first);
+            (Token::Text(&rest[..end]), end)
+        };
+        tokens.push(token);
+        rest = &rest[len..];
+    }
+    tokens
+}
+
+/// 找到没有转义的 `c`
+fn find_unescaped(text: &str, c: char) -> Option<usize> {
+    let mut escaped = false;
+    for (i, ch) in text.char_indices() {
+        match ch {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            _ if ch == c => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+pub(super) fn tokenize_markdown(text: &str) -> Vec<Token<'_>> {
+    let mut tokens = vec![];
+    // 已经打开的标记，以及链接的结束位置
+    let mut opened: Vec<(&str, Option<usize>)> = vec![];
+    let mut pos = 0;
+    let mut line_start = true;
+    while pos < text.len() {
+        let rest = &text[pos..];
+        let in_code = opened
+            .last()
+            .is_some_and(|(marker, _)| marker.starts_with('`'));
+        let (token, len) = if let Some((_, Some(end))) = opened.last()
+            && *end == pos
+        {
+            // 链接文字结束，`](url)` 作为结束标记
+            let close = find_unescaped(rest, ')').map_or(rest.len(), |end| end + 1);
+            opened.pop();
+            (Token::Close(&rest[..close]), close)
+        } else if let Some(escaped) = rest.strip_prefix('\\') {
+            let len = escaped.chars().next().map_or(1, |c| 1 + c.len_utf8());
+            (Token::Atom(&rest[..len], 1), len)
+        } else if in_code && !rest.starts_with('`') {
+            let end = rest.find(['\\', '`']).unwrap_or(rest.len());
+            (Token::Text(&rest[..end]), end)
+        } else if let Some(marker) = ["```", "`", "||", "__", "_", "*", "~"]
+            .into_iter()
+            .find(|marker| rest.starts_with(marker))
+        {
+            if opened.last().is_some_and(|(last, _)| *last == marker) {
+                opened.pop();
+                (Token::Close(marker), marker.len())
+            } else if marker == "```" {
+                // 代码块的语言写在第一行
+                let len = rest.find('\n').map_or(rest.len(), |end| end + 1);
+                opened.push((marker, None));
+                (Token::Open(&rest[..len], marker.to_string()), len)
+            } else {
+                opened.push((marker, None));
+                (Token::Open(marker, marker.to_string()), marker.len())
+            }
+        } else if rest.starts_with('[') || rest.starts_with("![") {
+            let open = if rest.starts_with('!') { 2 } else { 1 };
+            match find_unescaped(&rest[open..], ']') {
+                Some(end) => {
+                    let end = pos + open + end;
+                    let close =
+                        find_unescaped(&text[end..], ')').map_or(text.len(), |c| end + c + 1);
+                    opened.push(("[", Some(end)));
+                    (
+                        Token::Open(&rest[..open], text[end..close].to_string()),
+                        open,
+                    )
+                }
+                None => (Token::Text(&rest[..open]), open),
+            }
+        } else if line_start && rest.starts_with('>') {
+            (Token::Atom(&rest[..1], 0), 1)
+        } else if rest.starts_with('\n') {
+            (Token::Text(&rest[..1]), 1)
+        } else {
+            let end = rest
+                .char_indices()
+                .skip(1)
+                .find(|(_, c)| {
+                    matches!(
+                        c,
+                        '\\' | '`' | '|' | '_' | '*' | '~' | '[' | ']' | '!' | '\n'
+                    )
+                })
+                .map_or(rest.len(), |(i, c)| if c == '\n' { i + 1 } else { i });
+            (Token::Text(&rest[..end]), end)
+        };
+        line_start = rest[..len].ends_with('\n');
+        tokens.push(token);
+        pos += len;
+    }
+    tokens
+}
+
+/// 在 `text` 的前 `room` 个字符中找一个切分点，返回切分的字节位置
+fn find_break(text: &str, room: usize) -> Option<usize> {
+    let mut fit = 0;
+    let mut count = 0;
+    for (i, c) in text.char_indices() {
+        count += c.len_utf16();
+        if count > room {
+            break;
+        }
+        fit = i + c.len_utf8();
+    }
+    let head = &text[..fit];
+    let candidates = [
+        head.rfind("\n\n").map(|i| i + 2),
+        head.rfind('\n').map(|i| i + 1),
+        head.rfind(char::is_whitespace)
+            .map(|i| i + head[i..].chars().next().map_or(1, char::len_utf8)),
+    ];
+    candidates
+        .iter()
+        .flatten()
+        .find(|&&i| i >= fit / 2)
+        .or(candidates.iter().flatten().next())
+        .copied()
+        .filter(|&i| i > 0)
+}
+
+/// 一段一段地拼出消息
+struct Splitter {
+    limit: usize,
+    chunks: Vec<String>,
+    current: String,
+    /// 当前这段显示出来的长度
+    visible: usize,
+    /// 已经打开的标记
+    stack: Vec<(String, String)>,
+    /// 还没有内容的标记在 `current` 中的位置，它们在 `stack` 的末尾
+    empty: Vec<usize>,
+}
+
+impl Splitter {
+    fn flush(&mut self) {
+        if self.visible == 0 {
+            return;
+        }
+        // 没有内容的标记移到下一段
+        let start = self.empty.first().copied().unwrap_or(self.current.len());
+        let moved = self.current.split_off(start);
+        let kept = self.stack.len() - self.empty.len();
+        for (_, close) in self.stack[..kept].iter().rev() {
+            self.current.push_str(close);
+        }
+        // 下一段重新打开所有标记，在有内容之前它们都是空的
+        let mut next = String::new();
+        let mut empty = vec![];
+        for (open, _) in &self.stack[..kept] {
+            empty.push(next.len());
+            next.push_str(open);
+        }
+        empty.extend(self.empty.iter().map(|pos| pos - start + next.len()));
+        next.push_str(&moved);
+        self.empty = empty;
+        self.chunks.push(std::mem::replace(&mut self.current, next));
+        self.visible = 0;
+    }
+
+    fn push_visible(&mut self, text: &str, len: usize) {
+        self.current.push_str(text);
+        self.visible += len;
+        self.empty.clear();
+    }
+
+    fn push(&mut self, token: Token) {
+        match token {
+            Token::Open(open, close) => {
+                self.empty.push(self.current.len());
+                self.current.push_str(open);
+                self.stack.push((open.to_string(), close));
+            }
+            Token::Close(close) => {
+                // 丢掉没有内容的标记
+                match self.empty.pop() {
+                    Some(pos) => self.current.truncate(pos),
+                    None => self.current.push_str(close),
+                }
+                self.stack.pop();
+            }
+            Token::Atom(atom, len) => {
+                if self.visible + len > self.limit {
+                    self.flush();
+                }
+                self.push_visible(atom, len);
+            }
+            Token::Text(mut text) => loop {
+                let room = self.limit - self.visible;
+                let len = len16(text);
+                if len <= room {
+                    self.push_visible(text, len);
+                    return;
+                }
+                let cut = match find_break(text, room) {
+                    Some(cut) => cut,
+                    // 没有合适的切分点，先在这段文字之前切开
+                    None if self.visible > 0 => {
+                        self.flush();
+                        continue;
+                    }
+                    None => text
+                        .char_indices()
+                        .scan(0, |count, (i, c)| {
+                            *count += c.len_utf16();
+                            Some((*count, i + c.len_utf8()))
+                        })
+                        .take_while(|(count, _)| *count <= room)
+                        .last()
+                        .map_or(text.chars().next().map_or(0, char::len_utf8), |(_, i)| i),
+                };
+                self.push_visible(&text[..cut], len16(&text[..cut]));
+                self.flush();
+                text = &text[cut..];
+            },
+        }
+    }
+}
+
+/// 把消息切成显示长度不超过 `limit` 的几段，每段的标记都是闭合的
+pub fn split_message(text: &str, parse_mode: Option<ParseMode>, limit: usize) -> Vec<String> {
+    let tokens = match parse_mode {
+        Some(ParseMode::Html) => tokenize_html(text),
+        Some(ParseMode::MarkdownV2) => tokenize_markdown(text),
+        _ => vec![Token::Text(text)],
+    };
+    let mut splitter = Splitter {
+        limit: limit.max(1),
+        chunks: vec![],
+        current: String::new(),
+        visible: 0,
+        stack: vec![],
+        empty: vec![],
+    };
+    for token in tokens {
+        splitter.push(token);
+    }
+    if splitter.visible > 0 || splitter.chunks.is_empty() {
+        splitter.chunks.push(splitter.current);
+    }
+    splitter.chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_plain() {
+        assert_eq!(split_message("", None, 10), vec![""]);
+        assert_eq!(split_message("short", None, 10), vec!["short"]);
+        assert_eq!(
+            split_message("aaaa bbbb\ncccc dddd", None, 12),
+            vec!["aaaa bbbb\n", "cccc dddd"]
+        );
+        assert_eq!(
+            split_message("一二三四五六七八九十", None, 4),
+            vec!["一二三四", "五六七八", "九十"]
+        );
+        // 不会切开代理对
+        assert_eq!(split_message("😀😀😀", None, 3), vec!["😀", "😀", "😀"]);
+    }
+
+    #[test]
+    fn split_html() {
+        assert_eq!(
+            split_message("<b>aaaa bbbb</b> &amp;&amp;", Some(ParseMode::Html), 6),
+            vec!["<b>aaaa </b>", "<b>bbbb</b> &amp;", "&amp;"]
+        );
+        assert_eq!(
+            split_message(
+                r#"<a href="https://example.com">aa bb</a>"#,
+                Some(ParseMode::Html),
+                3
+            ),
+            vec![
+                r#"<a href="https://example.com">aa </a>"#,
+                r#"<a href="https://example.com">bb</a>"#
+            ]
+        );
+    }
+
+    #[test]
+    fn split_markdown() {
+        assert_eq!(
+            split_message(r"*aa bb* \*\*", Some(ParseMode::MarkdownV2), 4),
+            vec![r"*aa *", r"*bb* \*", r"\*"]
+        );
+        assert_eq!(
+            split_message(
+                "[aa bb](https://example.com) `cc dd`",
+                Some(ParseMode::MarkdownV2),
+                3
+            ),
+            vec![
+                "[aa ](https://example.com)",
+                "[bb](https://example.com) ",
+                "`cc `",
+                "`dd`"
+            ]
+        );
+        assert_eq!(
+            split_message(
+                "```rust\nlet a;\nlet b;\n```",
+                Some(ParseMode::MarkdownV2),
+                8
+            ),
+            vec!["```rust\nlet a;\n```", "```rust\nlet b;\n```"]
+        );
+    }
+}
@@ -0,0 +1,707 @@
+//! 时间表达式解析
+//!
+//! [parse_when] 从字符串开头解析出一个时间，返回剩下的部分。支持：
+//! - 时长：`30`（分钟）、`1h30m`、`2d`、`1.5h`、`3小时`、`半小时`、`一个半小时后`
+//! - 时刻：`14:30`、`下午三点`、`晚上8点半`。已经过去的时刻会顺延到明天
+//! - 日期：`2026-12-24 20:00`、`12月24日`、`明天下午三点`、`后天`、`下周一`、`周五 18:00`
+//! - 重复：`每天 8:00`、`每周五`、`每星期一 早上九点`
+//!
+//! 只有日期没有时刻时，默认为早上 9 点。
+
+use chrono::{DateTime, Datelike, Days, FixedOffset, NaiveDate, NaiveTime, TimeZone};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// 没有指定时刻时使用的时间
+const DEFAULT_HOUR: u32 = 9;
+
+/// 重复的时间
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Recurrence {
+    Daily {
+        hour: u32,
+        minute: u32,
+    },
+    /// `weekday` 从周一开始，为 0 到 6
+    Weekly {
+        weekday: u32,
+        hour: u32,
+        minute: u32,
+    },
+}
+
+impl Recurrence {
+    /// `after` 之后的下一次时间
+    pub fn next_after(&self, after: DateTime<FixedOffset>) -> DateTime<FixedOffset> {
+        let (hour, minute) = match *self {
+            Recurrence::Daily { hour, minute } | Recurrence::Weekly { hour, minute, .. } => {
+                (hour, minute)
+            }
+        };
+        let mut date = after.date_naive();
+        loop {
+            let matches = match *self {
+                Recurrence::Daily { .. } => true,
+                Recurrence::Weekly { weekday, .. } => {
+                    date.weekday().num_days_from_monday() == weekday
+                }
+            };
+            if matches
+                && let Some(time) = at_time(after.timezone(), date, hour, minute)
+                && time > after
+            {
+                return time;
+            }
+            date = date.succ_opt().expect("date overflow");
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match *self {
+            Recurrence::Daily { hour, minute } => format!("每天 {hour:02}:{minute:02}"),
+            Recurrence::Weekly {
+                weekday,
+                hour,
+                minute,
+            } => format!(
+                "每周{} {hour:02}:{minute:02}",
+                WEEKDAY_NAMES[weekday as usize % 7]
+            ),
+        }
+    }
+}
+
+/// 解析出的时间
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum When {
+    At(DateTime<FixedOffset>),
+    Every(Recurrence),
+}
+
+impl When {
+    /// 第一次触发的时间
+    pub fn first_after(&self, now: DateTime<FixedOffset>) -> DateTime<FixedOffset> {
+        match self {
+            When::At(time) => *time,
+            When::Every(recurrence) => recurrence.next_after(now),
+        }
+    }
+}
+
+const WEEKDAY_NAMES: [&str; 7] = ["一", "二", "三", "四", "五", "六", "日"];
+
+fn at_time(
+    tz: FixedOffset,
+    date: NaiveDate,
+    hour: u32,
+    minute: u32,
+) -> Option<DateTime<FixedOffset>> {
+    let time = NaiveTime::from_hms_opt(hour, minute, 0)?;
+    tz.from_local_datetime(&date.and_time(time)).single()
+}
+
+/// 去掉开头的 `prefixes` 之一，前面的优先
+fn eat<'a>(src: &'a str, prefixes: &[&str]) -> Option<&'a str> {
+    prefixes.iter().find_map(|prefix| src.strip_prefix(prefix))
+}
+
+fn chinese_digit(ch: char) -> Option<u32> {
+    Some(match ch {
+        '零' | '〇' => 0,
+        '一' => 1,
+        '二' | '两' => 2,
+        '三' => 3,
+        '四' => 4,
+        '五' => 5,
+        '六' => 6,
+        '七' => 7,
+        '八' => 8,
+        '九' => 9,
+        _ => return None,
+    })
+}
+
+/// 解析开头的整数，支持阿拉伯数字和 `二十五`、`十二`、`两` 这样的中文数字
+fn integer(src: &str) -> Option<(u32, &str)> {
+    let end = src.find(|c: char| !c.is_ascii_digit()).unwrap_or(src.len());
+    if end > 0 {
+        return Some((src[..end].parse().ok()?, &src[end..]));
+    }
+    let end = src
+        .char_indices()
+        .find(|(_, c)| chinese_digit(*c).is_none() && *c != '十')
+        .map_or(src.len(), |(i, _)| i);
+    let (num, rest) = src.split_at(end);
+    if num.is_empty() {
+        return None;
+    }
+    let value = match num.split_once('十') {
+        Some((tens, ones)) => {
+            let tens = match tens.chars().collect::<Vec<_>>()[..] {
+                [] => 1,
+                [ch] => chinese_digit(ch)?,
+                _ => return None,
+            };
+            let ones = match ones.chars().collect::<Vec<_>>()[..] {
+                [] => 0,
+                [ch] => chinese_digit(ch)?,
+                _ => return None,
+            };
+            tens * 10 + ones
+        }
+        None => num.chars().try_fold(0u32, |acc, ch| {
+            acc.checked_mul(10)?.checked_add(chinese_digit(ch)?)
+        })?,
+    };
+    Some((value, rest))
+}
+
+/// 解析开头的数字，可以是小数
+fn decimal(src: &str) -> Option<(f64, &str)> {
+    let end = src
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(src.len());
+    if end > 0 {
+        return Some((src[..end].parse().ok()?, &src[end..]));
+    }
+    let (num, rest) = integer(src)?;
+    Some((num as f64, rest))
+}
+
+/// 时长单位，长的前缀放在前面
+const UNITS: &[(&[&str], f64)] = &[
+    (&["天", "days", "day", "d"], 24.0 * 60.0 * 60.0),
+    (
+        &["小时", "钟头", "hours", "hour", "hrs", "hr", "h"],
+        60.0 * 60.0,
+    ),
+    (
+        &["分钟", "分", "minutes", "minute", "mins", "min", "m"],
+        60.0,
+    ),
+    (
+        &["秒钟", "秒", "seconds", "second", "secs", "sec", "s"],
+        1.0,
+    ),
+];
+
+/// 匹配开头的时长单位。英文单位后面不能紧跟字母，`5 have lunch` 不是 5 小时
+fn unit(src: &str) -> Option<(f64, &str)> {
+    UNITS.iter().find_map(|(names, unit)| {
+        names.iter().find_map(|name| {
+            let rest = src.strip_prefix(name)?;
+            let in_word = name.is_ascii() && rest.starts_with(|c: char| c.is_ascii_alphabetic());
+            (!in_word).then_some((*unit, rest))
+        })
+    })
+}
+
+/// 解析 `1h30m`、`3小时`、`一个半小时` 这样带单位的时长
+fn duration_with_units(src: &str) -> Option<(Duration, &str)> {
+    let mut rest = src;
+    let mut secs = 0.0;
+    let mut parsed = false;
+    loop {
+        let (mut num, after_num) = match eat(rest, &["半"]) {
+            Some(after) => (0.5, after),
+            None => match decimal(rest) {
+                Some(res) => res,
+                None => break,
+            },
+        };
+        let mut after_num = eat(after_num, &["个"]).unwrap_or(after_num);
+        if num >= 1.0
+            && let Some(after_half) = eat(after_num, &["半"])
+        {
+            num += 0.5;
+            after_num = after_half;
+        }
+        let Some((unit, after_unit)) = unit(after_num.trim_start()) else {
+            break;
+        };
+        secs += num * unit;
+        rest = after_unit;
+        parsed = true;
+    }
+    if !parsed {
+        return None;
+    }
+    let rest = eat(rest, &["以后", "之后", "后"]).unwrap_or(rest);
+    Some((Duration::try_from_secs_f64(secs).ok()?, rest))
+}
+
+/// 解析时长，不带单位的数字视为分钟
+pub fn duration(src: &str) -> Option<(Duration, &str)> {
+    let src = src.trim_start();
+    if let Some(res) = duration_with_units(src) {
+        return Some(res);
+    }
+    let (minutes, rest) = decimal(src)?;
+    if !(rest.is_empty() || rest.starts_with(char::is_whitespace)) {
+        return None;
+    }
+    Some((Duration::try_from_secs_f64(minutes * 60.0).ok()?, rest))
+}
+
+/// 解析一整个时长字符串，例如 `2h`、`1h30m`、`10`
+pub fn parse_duration(src: &str) -> Option<Duration> {
+    match duration(src)? {
+        (duration, rest) if rest.trim().is_empty() => Some(duration),
+        _ => None,
+    }
+}
+
+/// 把时长写成 `1天2小时`、`30分钟` 的形式，忽略不足一分钟的部分
+pub fn describe_duration(duration: Duration) -> String {
+    let minutes = duration.as_secs() / 60;
+    let parts = [
+        (minutes / (60 * 24), "天"),
+        (minutes / 60 % 24, "小时"),
+        (minutes % 60, "分钟"),
+    ];
+    let res = parts
+        .iter()
+        .filter(|(num, _)| *num > 0)
+        .map(|(num, unit)| format!("{num}{unit}"))
+        .collect::<String>();
+    if res.is_empty() {
+        format!("{}秒", duration.as_secs())
+    } else {
+        res
+    }
+}
+
+/// 一天中的时段
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Period {
+    /// 凌晨、半夜
+    Midnight,
+    /// 早上、上午
+    Morning,
+    Noon,
+    /// 下午、晚上
+    Afternoon,
+}
+
+fn period(src: &str) -> Option<(Period, &str)> {
+    const PERIODS: &[(&[&str], Period)] = &[
+        (&["凌晨", "半夜", "午夜"], Period::Midnight),
+        (&["早上", "早晨", "清晨", "上午", "早"], Period::Morning),
+        (&["中午"], Period::Noon),
+        (&["下午", "傍晚", "晚上", "夜里", "晚"], Period::Afternoon),
+    ];
+    PERIODS
+        .iter()
+        .find_map(|(names, period)| Some((*period, eat(src, names)?)))
+}
+
+/// 解析 `14:30`、`下午三点`、`8点半` 这样的时刻
+fn time_of_day(src: &str, period_hint: Option<Period>) -> Option<((u32, u32), &str)> {
+    let src = src.trim_start();
+    let (period, src) = match period(src) {
+        Some((period, rest)) => (Some(period), rest),
+        None => (period_hint, src),
+    };
+    let (hour, rest) = integer(src)?;
+    let (minute, rest) = if let Some(rest) = eat(rest, &[":", "："]) {
+        let end = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        if end != 2 {
+            return None;
+        }
+        (rest[..end].parse().ok()?, &rest[end..])
+    } else {
+        let rest = eat(rest, &["点钟", "点", "时"])?;
+        if let Some(rest) = eat(rest, &["半"]) {
+            (30, rest)
+        } else if let Some(rest) = eat(rest, &["一刻"]) {
+            (15, rest)
+        } else if let Some(rest) = eat(rest, &["三刻"]) {
+            (45, rest)
+        } else if let Some((minute, after)) = integer(rest) {
+            (minute, eat(after, &["分"]).unwrap_or(after))
+        } else {
+            (0, rest)
+        }
+    };
+    let hour = match (period, hour) {
+        (Some(Period::Midnight), 12) => 0,
+        (Some(Period::Noon), 1..=5) => hour + 12,
+        (Some(Period::Afternoon), 1..=11) => hour + 12,
+        (_, 24) if minute == 0 => 0,
+        _ => hour,
+    };
+    if hour > 23 || minute > 59 {
+        return None;
+    }
+    Some(((hour, minute), rest))
+}
+
+fn weekday(src: &str) -> Option<(u32, &str)> {
+    let mut chars = src.chars();
+    let day = match chars.next()? {
+        '日' | '天' | '七' => 6,
+        ch => chinese_digit(ch).filter(|day| (1..=6).contains(day))? - 1,
+    };
+    Some((day, chars.as_str()))
+}
+
+/// 解析开头的日期，可能同时带有时段，例如 `今晚`
+fn date(src: &str, today: NaiveDate) -> Option<(NaiveDate, Option<Period>, &str)> {
+    const DAYS: &[(&str, u64, Option<Period>)] = &[
+        ("今天", 0, None),
+        ("今晚", 0, Some(Period::Afternoon)),
+        ("明天", 1, None),
+        ("明晚", 1, Some(Period::Afternoon)),
+        ("大后天", 3, None),
+        ("后天", 2, None),
+    ];
+    if let Some((days, period, rest)) = DAYS
+        .iter()
+        .find_map(|(name, days, period)| Some((*days, *period, src.strip_prefix(name)?)))
+    {
+        return Some((today.checked_add_days(Days::new(days))?, period, rest));
+    }
+
+    if let Some(rest) = eat(src, &["下周", "下星期", "下礼拜"]) {
+        let (day, rest) = weekday(rest)?;
+        let monday =
+            today.checked_sub_days(Days::new(today.weekday().num_days_from_monday() as u64))?;
+        return Some((
+            monday.checked_add_days(Days::new(7 + day as u64))?,
+            None,
+            rest,
+        ));
+    }
+    if let Some(rest) = eat(src, &["这周", "本周", "周", "星期", "礼拜"]) {
+        let (day, rest) = weekday(rest)?;
+        let offset = (day + 7 - today.weekday().num_days_from_monday()) % 7;
+        return Some((
+            today.checked_add_days(Days::new(offset as u64))?,
+            None,
+            rest,
+        ));
+    }
+
+    // 2026-12-24, 2026/12/24, 2026年12月24日
+    let (first, rest) = integer(src)?;
+    let (year, month, rest) = if let Some(rest) = eat(rest, &["-", "/", "年"]) {
+        let (month, rest) = integer(rest)?;
+        let rest = eat(rest, &["-", "/", "月"])?;
+        (first as i32, month, rest)
+    } else {
+        let rest = eat(rest, &["月"])?;
+        (today.year(), first, rest)
+    };
+    let (day, rest) = integer(rest)?;
+    let rest = eat(rest, &["日", "号"]).unwrap_or(rest);
+    let date = NaiveDate::from_ymd_opt(year, month, day)?;
+    Some((date, None, rest))
+}
+
+fn recurrence(src: &str) -> Option<(Recurrence, &str)> {
+    if let Some(rest) = eat(src, &["每天", "每日"]) {
+        let ((hour, minute), rest) = time_of_day(rest, None).unwrap_or(((DEFAULT_HOUR, 0), rest));
+        return Some((Recurrence::Daily { hour, minute }, rest));
+    }
+    let rest = eat(src, &["每个", "每"])?;
+    let rest = eat(rest, &["周", "星期", "礼拜"])?;
+    let (weekday, rest) = weekday(rest)?;
+    let ((hour, minute), rest) = time_of_day(rest, None).unwrap_or(((DEFAULT_HOUR, 0), rest));
+    Some((
+        Recurrence::Weekly {
+            weekday,
+            hour,
+            minute,
+        },
+        rest,
+    ))
+}
+
+/// 从 `src` 开头解析一个时间，返回时间和剩下的字符串
+pub fn parse_when(src: &str, now: DateTime<FixedOffset>) -> Option<(When, &str)> {
+    let src = src.trim_start();
+    let tz = now.timezone();
+    let today = now.date_naive();
+
+    if let Some((recurrence, rest)) = recurrence(src) {
+        return Some((When::Every(recurrence), rest.trim_start()));
+    }
+
+    if let Some((date, period, rest)) = date(src, today) {
+        let ((hour, minute), rest) = time_of_day(rest, period).unwrap_or(((DEFAULT_HOUR, 0), rest));
+        return Some((
+            When::At(at_time(tz, date, hour, minute)?),
+            rest.trim_start(),
+        ));
+    }
+
+    if let Some(((hour, minute), rest)) = time_of_day(src, None) {
+        let mut time = at_time(tz, today, hour, minute)?;
+        if time <= now {
+            time = at_time(tz, today.succ_opt()?, hour, minute)?;
+        }
+        return Some((When::At(time), rest.trim_start()));
+    }
+
+    let (duration, rest) = duration(src)?;
+    let time = now.checked_add_signed(chrono::Duration::from_std(duration).ok()?)?;
+    Some((When::At(time), rest.trim_start()))
+}
+
+/// 解析 `+8`、`-5`、`+5:30`、`UTC+8` 形式的时区
+pub fn parse_offset(src: &str) -> Option<FixedOffset> {
+    let src = src.trim();
+    let src = eat(src, &["UTC", "utc", "GMT", "gmt"]).unwrap_or(src);
+    let (sign, rest) = match src.chars().next()? {
+        '+' => (1, &src[1..]),
+        '-' => (-1, &src[1..]),
+        _ => (1, src),
+    };
+    let (hours, minutes) = rest.split_once(':').unwrap_or((rest, "0"));
+    let hours = hours.parse::<i32>().ok()?;
+    let minutes = minutes.parse::<i32>().ok()?;
+    if !(0..=14).contains(&hours) || !(0..60).contains(&minutes) {
+        return None;
+    }
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+}
+
+/// 没有设置时区时使用 UTC+8
+pub fn default_offset() -> FixedOffset {
+    FixedOffset::east_opt(8 * 3600).expect("valid offset")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2026-10-14 10:00 +08:00，周三
+    fn now() -> DateTime<FixedOffset> {
+        default_offset()
+            .with_ymd_and_hms(2026, 10, 14, 10, 0, 0)
+            .unwrap()
+    }
+
+    fn at(month: u32, day: u32, hour: u32, minute: u32) -> When {
+        When::At(
+            default_offset()
+                .with_ymd_and_hms(2026, month, day, hour, minute, 0)
+                .unwrap(),
+        )
+    }
+
+    fn parse(src: &str) -> Option<(When, &str)> {
+        parse_when(src, now())
+    }
+
+    #[test]
+    fn parse_durations() {
+        assert_eq!(parse_duration("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("10"), Some(Duration::from_secs(600)));
+        assert_eq!(parse_duration("1.5h"), Some(Duration::from_secs(5400)));
+        assert_eq!(parse_duration(" 2d "), Some(Duration::from_secs(172800)));
+        assert_eq!(parse_duration("1h30m"), Some(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("1d2h3m4s"), Some(Duration::from_secs(93784)));
+        assert_eq!(parse_duration("3 小时"), Some(Duration::from_secs(10800)));
+        assert_eq!(parse_duration("半小时"), Some(Duration::from_secs(1800)));
+        assert_eq!(parse_duration("两个小时"), Some(Duration::from_secs(7200)));
+        assert_eq!(
+            parse_duration("一个半小时"),
+            Some(Duration::from_secs(5400))
+        );
+        assert_eq!(
+            parse_duration("1小时30分钟"),
+            Some(Duration::from_secs(5400))
+        );
+        assert_eq!(
+            parse_duration("二十分钟后"),
+            Some(Duration::from_secs(1200))
+        );
+        assert_eq!(parse_duration("h"), None);
+        assert_eq!(parse_duration("2w"), None);
+        assert_eq!(parse_duration("2h 吃饭"), None);
+        assert_eq!(parse_duration("10 seconds"), Some(Duration::from_secs(10)));
+        assert_eq!(parse_duration("5 hours"), Some(Duration::from_secs(18000)));
+        assert_eq!(parse_duration("10 spamming"), None);
+        assert_eq!(
+            duration("10 spamming"),
+            Some((Duration::from_secs(600), " spamming"))
+        );
+    }
+
+    #[test]
+    fn describe_durations() {
+        assert_eq!(describe_duration(Duration::from_secs(30)), "30秒");
+        assert_eq!(describe_duration(Duration::from_secs(5400)), "1小时30分钟");
+        assert_eq!(
+            describe_duration(Duration::from_secs(93784)),
+            "1天2小时3分钟"
+        );
+        assert_eq!(describe_duration(Duration::from_secs(86400)), "1天");
+    }
+
+    #[test]
+    fn parse_duration_prefix() {
+        assert_eq!(parse("5 吃饭"), Some((at(10, 14, 10, 5), "吃饭")));
+        assert_eq!(parse("1h30m 吃饭"), Some((at(10, 14, 11, 30), "吃饭")));
+        assert_eq!(parse("2d 交作业"), Some((at(10, 16, 10, 0), "交作业")));
+        assert_eq!(parse("十分钟后喝水"), Some((at(10, 14, 10, 10), "喝水")));
+        assert_eq!(
+            parse("5 have lunch"),
+            Some((at(10, 14, 10, 5), "have lunch"))
+        );
+        assert_eq!(
+            parse("30 drink water"),
+            Some((at(10, 14, 10, 30), "drink water"))
+        );
+        assert_eq!(
+            parse("2 hours have lunch"),
+            Some((at(10, 14, 12, 0), "have lunch"))
+        );
+        assert_eq!(parse("5吃饭"), None);
+        assert_eq!(parse("吃饭"), None);
+    }
+
+    #[test]
+    fn parse_time_of_day() {
+        assert_eq!(parse("14:30 开会"), Some((at(10, 14, 14, 30), "开会")));
+        assert_eq!(parse("14：30 开会"), Some((at(10, 14, 14, 30), "开会")));
+        assert_eq!(parse("下午三点开会"), Some((at(10, 14, 15, 0), "开会")));
+        assert_eq!(parse("晚上8点半 看番"), Some((at(10, 14, 20, 30), "看番")));
+        assert_eq!(parse("3点一刻 摸鱼"), Some((at(10, 15, 3, 15), "摸鱼")));
+        assert_eq!(parse("中午12点 吃饭"), Some((at(10, 14, 12, 0), "吃饭")));
+        assert_eq!(parse("中午一点 午休"), Some((at(10, 14, 13, 0), "午休")));
+        assert_eq!(parse("十点二十分 喝水"), Some((at(10, 14, 10, 20), "喝水")));
+        // 已经过去的时刻顺延到明天
+        assert_eq!(parse("9:00 上班"), Some((at(10, 15, 9, 0), "上班")));
+        assert_eq!(parse("10:00 上班"), Some((at(10, 15, 10, 0), "上班")));
+        assert_eq!(parse("凌晨12点 睡觉"), Some((at(10, 15, 0, 0), "睡觉")));
+        assert_eq!(parse("25:00 睡觉"), None);
+        assert_eq!(parse("14:3 睡觉"), None);
+    }
+
+    #[test]
+    fn parse_dates() {
+        assert_eq!(
+            parse("2026-12-24 20:00 平安夜"),
+            Some((at(12, 24, 20, 0), "平安夜"))
+        );
+        assert_eq!(parse("2026/12/24 过节"), Some((at(12, 24, 9, 0), "过节")));
+        assert_eq!(
+            parse("2026年12月24日晚上八点 过节"),
+            Some((at(12, 24, 20, 0), "过节"))
+        );
+        assert_eq!(parse("12月24号 过节"), Some((at(12, 24, 9, 0), "过节")));
+        assert_eq!(
+            parse("明天下午三点 开会"),
+            Some((at(10, 15, 15, 0), "开会"))
+        );
+        assert_eq!(parse("明天下午三点开会"), Some((at(10, 15, 15, 0), "开会")));
+        assert_eq!(parse("明天 开会"), Some((at(10, 15, 9, 0), "开会")));
+        assert_eq!(parse("后天 14:00 开会"), Some((at(10, 16, 14, 0), "开会")));
+        assert_eq!(parse("大后天 开会"), Some((at(10, 17, 9, 0), "开会")));
+        assert_eq!(parse("今晚八点 看番"), Some((at(10, 14, 20, 0), "看番")));
+        assert_eq!(parse("明晚9点 看番"), Some((at(10, 15, 21, 0), "看番")));
+        assert_eq!(parse("2026-02-30 开会"), None);
+    }
+
+    #[test]
+    fn parse_weekdays() {
+        assert_eq!(parse("下周一 开会"), Some((at(10, 19, 9, 0), "开会")));
+        assert_eq!(
+            parse("下星期三下午两点 开会"),
+            Some((at(10, 21, 14, 0), "开会"))
+        );
+        assert_eq!(parse("下周日 休息"), Some((at(10, 25, 9, 0), "休息")));
+        assert_eq!(parse("周五 18:00 下班"), Some((at(10, 16, 18, 0), "下班")));
+        assert_eq!(parse("星期天 休息"), Some((at(10, 18, 9, 0), "休息")));
+        assert_eq!(
+            parse("周三 下午三点 开会"),
+            Some((at(10, 14, 15, 0), "开会"))
+        );
+        assert_eq!(parse("周报 写完"), None);
+    }
+
+    #[test]
+    fn parse_recurrences() {
+        assert_eq!(
+            parse("每天 8:00 吃药"),
+            Some((
+                When::Every(Recurrence::Daily { hour: 8, minute: 0 }),
+                "吃药"
+            ))
+        );
+        assert_eq!(
+            parse("每天晚上十点 睡觉"),
+            Some((
+                When::Every(Recurrence::Daily {
+                    hour: 22,
+                    minute: 0
+                }),
+                "睡觉"
+            ))
+        );
+        assert_eq!(
+            parse("每周五 周报"),
+            Some((
+                When::Every(Recurrence::Weekly {
+                    weekday: 4,
+                    hour: 9,
+                    minute: 0
+                }),
+                "周报"
+            ))
+        );
+        assert_eq!(
+            parse("每星期一 早上九点半 例会"),
+            Some((
+                When::Every(Recurrence::Weekly {
+                    weekday: 0,
+                    hour: 9,
+                    minute: 30
+                }),
+                "例会"
+            ))
+        );
+        assert_eq!(parse("每月 交房租"), None);
+    }
+
+    #[test]
+    fn recurrence_next() {
+        let daily = Recurrence::Daily { hour: 8, minute: 0 };
+        assert_eq!(When::At(daily.next_after(now())), at(10, 15, 8, 0));
+        let daily = Recurrence::Daily {
+            hour: 10,
+            minute: 0,
+        };
+        assert_eq!(When::At(daily.next_after(now())), at(10, 15, 10, 0));
+        let friday = Recurrence::Weekly {
+            weekday: 4,
+            hour: 9,
+            minute: 0,
+        };
+        assert_eq!(When::At(friday.next_after(now())), at(10, 16, 9, 0));
+        let wednesday = Recurrence::Weekly {
+            weekday: 2,
+            hour: 9,
+            minute: 0,
+        };
+        assert_eq!(When::At(wednesday.next_after(now())), at(10, 21, 9, 0));
+        assert_eq!(friday.describe(), "每周五 09:00");
+    }
+
+    #[test]
+    fn parse_offsets() {
+        assert_eq!(parse_offset("+8"), FixedOffset::east_opt(8 * 3600));
+        assert_eq!(parse_offset("UTC-5"), FixedOffset::west_opt(5 * 3600));
+        assert_eq!(
+            parse_offset("+5:30"),
+            FixedOffset::east_opt(5 * 3600 + 1800)
+        );
+        assert_eq!(parse_offset("9"), FixedOffset::east_opt(9 * 3600));
+        assert_eq!(parse_offset("+15"), None);
+        assert_eq!(parse_offset("abc"), None);
+    }
+}